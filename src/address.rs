@@ -0,0 +1,110 @@
+//where coins are sent: a payload, in practice an ed25519 public key, followed by the first
+//CHECKSUM_LEN bytes of its sha-256, all written as hex. a mistyped address fails the checksum
+//instead of paying an account nobody holds the key to
+use sha2::{Digest, Sha256};
+
+const CHECKSUM_LEN: usize = 4;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Address {
+    payload: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AddrError {
+    InvalidHex,
+    //nothing left once the checksum is taken off
+    TooShort,
+    BadChecksum,
+}
+
+impl std::fmt::Display for AddrError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AddrError::InvalidHex => write!(f, "address is not hex"),
+            AddrError::TooShort => write!(f, "address is too short"),
+            AddrError::BadChecksum => write!(f, "address checksum doesn't match, it is probably mistyped"),
+        }
+    }
+}
+
+fn checksum(payload: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let digest = Sha256::digest(payload);
+    let mut sum = [0u8; CHECKSUM_LEN];
+    sum.copy_from_slice(&digest[..CHECKSUM_LEN]);
+    sum
+}
+
+impl Address {
+    pub fn from_payload(payload: Vec<u8>) -> Address {
+        Address { payload }
+    }
+
+    pub fn parse(s: &str) -> Result<Address, AddrError> {
+        let bytes = hex::decode(s).map_err(|_| AddrError::InvalidHex)?;
+        if bytes.len() <= CHECKSUM_LEN {
+            return Err(AddrError::TooShort);
+        }
+
+        let (payload, sum) = bytes.split_at(bytes.len() - CHECKSUM_LEN);
+        if sum != checksum(payload) {
+            return Err(AddrError::BadChecksum);
+        }
+        Ok(Address::from_payload(payload.to_vec()))
+    }
+
+    //the payload without the checksum, what balances and `Transaction::from` are keyed by
+    pub fn payload_hex(&self) -> String {
+        hex::encode(&self.payload)
+    }
+}
+
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}", self.payload_hex(), hex::encode(checksum(&self.payload)))
+    }
+}
+
+//written in the checksummed form, so a transaction with a mistyped recipient doesn't deserialize
+impl serde::Serialize for Address {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Address {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Address::parse(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn an_address_round_trips_and_any_one_typo_is_caught() {
+        let address = Address::from_payload(vec![0xab; 32]);
+        let written = address.to_string();
+        assert_eq!(written.len(), 2 * (32 + CHECKSUM_LEN));
+        assert_eq!(Address::parse(&written), Ok(address.clone()));
+        assert_eq!(serde_json::from_str::<Address>(&serde_json::to_string(&address).unwrap()).unwrap(), address);
+
+        for i in 0..written.len() {
+            let mut typo = written.clone().into_bytes();
+            typo[i] = if typo[i] == b'0' { b'1' } else { b'0' };
+            let typo = String::from_utf8(typo).unwrap();
+            assert_eq!(Address::parse(&typo), Err(AddrError::BadChecksum), "{}", typo);
+            assert!(serde_json::from_value::<Address>(serde_json::Value::String(typo)).is_err());
+        }
+    }
+
+    #[test]
+    fn an_address_has_to_be_hex_with_a_payload() {
+        assert_eq!(Address::parse("xyz0"), Err(AddrError::InvalidHex));
+        assert_eq!(Address::parse("abc"), Err(AddrError::InvalidHex));
+        assert_eq!(Address::parse(""), Err(AddrError::TooShort));
+        assert_eq!(Address::parse(&hex::encode(checksum(&[]))), Err(AddrError::TooShort));
+    }
+}
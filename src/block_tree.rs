@@ -0,0 +1,82 @@
+//every valid block a node has seen, losing forks included, linked to its parent. the blockchain's
+//`chain` is the path from genesis to best_tip, other tips are competing branches kept for analysis
+use std::collections::{HashMap, HashSet};
+
+use crate::{Block, Blockchain};
+
+struct Entry {
+    block: Block,
+    //of the whole branch from genesis up to and including this block
+    work: u128,
+}
+
+#[derive(Default)]
+pub struct BlockTree {
+    entries: HashMap<String, Entry>,
+    //blocks no other block builds on yet
+    tips: HashSet<String>,
+}
+
+impl BlockTree {
+    //false if the block is already known or its parent isn't. a block at index 0 starts the tree
+    pub fn insert(&mut self, block: Block) -> bool {
+        if self.entries.contains_key(&block.header.hash) {
+            return false;
+        }
+
+        let parent_work = if block.header.index == 0 {
+            0
+        } else {
+            match self.entries.get(&block.header.previous_hash) {
+                Some(parent) => parent.work,
+                None => return false,
+            }
+        };
+
+        self.tips.remove(&block.header.previous_hash);
+        self.tips.insert(block.header.hash.clone());
+        let work = parent_work.saturating_add(Blockchain::block_work(&block.header.hash));
+        self.entries.insert(block.header.hash.clone(), Entry { block, work });
+        true
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.entries.contains_key(hash)
+    }
+
+    pub fn tips(&self) -> Vec<&Block> {
+        self.tips.iter().map(|hash| &self.entries[hash].block).collect()
+    }
+
+    //cumulative work of the branch ending at `hash`, 0 if the block isn't known
+    pub fn work(&self, hash: &str) -> u128 {
+        self.entries.get(hash).map_or(0, |entry| entry.work)
+    }
+
+    //the tip whose branch wins Blockchain::compare_chains, the same order a proof-of-work chain
+    //picks between competing chains
+    pub fn best_tip(&self) -> Option<&Block> {
+        fn rank(entry: &Entry) -> (u128, usize, &str) {
+            (entry.work, entry.block.header.index as usize + 1, &entry.block.header.hash)
+        }
+        self.tips.iter()
+            .map(|hash| &self.entries[hash])
+            .max_by(|a, b| Blockchain::compare_chains(rank(a), rank(b)))
+            .map(|entry| &entry.block)
+    }
+
+    //the blocks from the root up to and including `hash`, empty if it isn't known
+    pub fn path_to(&self, hash: &str) -> Vec<Block> {
+        let mut path = Vec::new();
+        let mut next = self.entries.get(hash);
+        while let Some(entry) = next {
+            path.push(entry.block.clone());
+            next = match entry.block.header.index {
+                0 => None,
+                _ => self.entries.get(&entry.block.header.previous_hash),
+            };
+        }
+        path.reverse();
+        path
+    }
+}
@@ -0,0 +1,123 @@
+//where a blockchain keeps its blocks, genesis first. validation reads them as one slice, so a
+//store backed by a file or a database still holds them in memory and writes through. writing
+//can't fail here, such a store has to deal with its own write errors: the chain in memory is what
+//the node goes by
+use crate::Block;
+
+pub trait ChainStore: Send {
+    //the block goes on top of the tip, it has already been checked
+    fn append(&mut self, block: Block);
+
+    //drops the blocks from height `len` on and hands them back, oldest first
+    fn truncate(&mut self, len: usize) -> Vec<Block>;
+
+    fn blocks(&self) -> &[Block];
+
+    fn get(&self, index: u64) -> Option<&Block> {
+        usize::try_from(index).ok().and_then(|i| self.blocks().get(i))
+    }
+
+    fn len(&self) -> usize {
+        self.blocks().len()
+    }
+
+    //a store always holds the genesis block at least
+    fn tip(&self) -> &Block {
+        self.blocks().last().expect("chain always holds at least the genesis block")
+    }
+
+    //a copy of the whole chain, e.g. to check it without holding the blockchain's lock
+    fn load_all(&self) -> Vec<Block> {
+        self.blocks().to_vec()
+    }
+}
+
+//saved with the rest of the blockchain as a plain list of blocks, whatever the store
+impl serde::Serialize for dyn ChainStore {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.blocks().serialize(serializer)
+    }
+}
+
+//the default, blocks live only as long as the process unless the blockchain is saved
+pub struct MemoryStore {
+    blocks: Vec<Block>,
+}
+
+impl MemoryStore {
+    pub fn new(blocks: Vec<Block>) -> MemoryStore {
+        MemoryStore { blocks }
+    }
+}
+
+impl ChainStore for MemoryStore {
+    fn append(&mut self, block: Block) {
+        self.blocks.push(block);
+    }
+
+    fn truncate(&mut self, len: usize) -> Vec<Block> {
+        self.blocks.split_off(len.min(self.blocks.len()))
+    }
+
+    fn blocks(&self) -> &[Block] {
+        &self.blocks
+    }
+}
+
+//writes every change through to a file of JSON lines, one block each. only there so tests can
+//run the blockchain on a store that isn't just a Vec, a write error fails the test. the file is
+//removed along with the store
+#[cfg(test)]
+pub struct FileStore {
+    path: std::path::PathBuf,
+    blocks: Vec<Block>,
+}
+
+#[cfg(test)]
+impl FileStore {
+    //picks up the blocks already in the file, or starts it with `genesis`
+    pub fn open(path: std::path::PathBuf, genesis: Block) -> FileStore {
+        let blocks = match std::fs::read_to_string(&path) {
+            Ok(lines) => lines.lines().map(|line| serde_json::from_str(line).expect("store file holds blocks")).collect(),
+            Err(_) => vec![genesis],
+        };
+        let store = FileStore { path, blocks };
+        store.rewrite();
+        store
+    }
+
+    fn rewrite(&self) {
+        let lines: String = self.blocks.iter()
+            .map(|block| serde_json::to_string(block).expect("block serializes") + "\n")
+            .collect();
+        std::fs::write(&self.path, lines).expect("store file is writable");
+    }
+}
+
+#[cfg(test)]
+impl Drop for FileStore {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+impl ChainStore for FileStore {
+    fn append(&mut self, block: Block) {
+        use std::io::Write;
+
+        let mut file = std::fs::OpenOptions::new().append(true).open(&self.path).expect("store file is writable");
+        writeln!(file, "{}", serde_json::to_string(&block).expect("block serializes")).expect("store file is writable");
+        self.blocks.push(block);
+    }
+
+    fn truncate(&mut self, len: usize) -> Vec<Block> {
+        let dropped = self.blocks.split_off(len.min(self.blocks.len()));
+        self.rewrite();
+        dropped
+    }
+
+    fn blocks(&self) -> &[Block] {
+        &self.blocks
+    }
+}
@@ -0,0 +1,62 @@
+//where block timestamps and the future-drift check get the time from
+use std::sync::{Arc, Mutex};
+
+pub trait Clock: Send + Sync {
+    fn now_millis(&self) -> u128;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    //milliseconds since the unix epoch, 0 if the clock is set before it. such a timestamp never
+    //follows its parent, so the block is rejected instead of the node panicking
+    fn now_millis(&self) -> u128 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis())
+            .unwrap_or(0)
+    }
+}
+
+pub fn system() -> Arc<dyn Clock> {
+    Arc::new(SystemClock)
+}
+
+//only moves when advanced, reading it never does, so timestamps depend neither on when a
+//run happens nor on how often anything looked at the time. miners keep block timestamps apart
+//themselves, each one at least 1 ms past its parent
+pub struct MockClock {
+    millis: Mutex<u128>,
+}
+
+impl MockClock {
+    pub fn new(start_millis: u128) -> MockClock {
+        MockClock { millis: Mutex::new(start_millis) }
+    }
+
+    pub fn advance(&self, millis: u128) {
+        let mut now = self.millis.lock().expect("clock lock poisoned");
+        *now = now.saturating_add(millis);
+    }
+}
+
+impl Clock for MockClock {
+    fn now_millis(&self) -> u128 {
+        *self.millis.lock().expect("clock lock poisoned")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mock_clock_only_moves_when_told() {
+        let clock = MockClock::new(1_000);
+        assert_eq!(clock.now_millis(), 1_000);
+        assert_eq!(clock.now_millis(), 1_000);
+
+        clock.advance(250);
+        assert_eq!(clock.now_millis(), 1_250);
+    }
+}
@@ -0,0 +1,769 @@
+//how a Message is turned into bytes on a peer socket. every node in a network has to use the
+//same codec, a frame in the other format doesn't decode
+use std::{cell::Cell, fmt, io, str::FromStr};
+use serde::{de, ser, Deserialize, Serialize};
+
+use crate::Message;
+use crate::net::PROTOCOL_VERSION;
+
+//what every frame holds, the message and the protocol version of the node that sent it
+#[derive(Serialize, Deserialize)]
+struct Envelope<M> {
+    version: u16,
+    payload: M,
+}
+
+pub enum Frame {
+    Message(Message),
+    //a message type added after this build, from a newer peer. `variant` is its name, or its
+    //index with bincode
+    Unknown { version: u16, variant: String },
+}
+
+pub trait Codec: Send + Sync {
+    fn encode(&self, msg: &Message) -> io::Result<Vec<u8>>;
+    //a frame that fails to decode is only Frame::Unknown when its variant is one Message doesn't
+    //have, anything else malformed is an error
+    fn decode(&self, bytes: &[u8]) -> io::Result<Frame>;
+}
+
+pub struct Json;
+
+impl Codec for Json {
+    fn encode(&self, msg: &Message) -> io::Result<Vec<u8>> {
+        Ok(serde_json::to_vec(&Envelope { version: PROTOCOL_VERSION, payload: msg })?)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> io::Result<Frame> {
+        let e = match serde_json::from_slice::<Envelope<Message>>(bytes) {
+            Ok(envelope) => return Ok(Frame::Message(envelope.payload)),
+            Err(e) => e,
+        };
+
+        //a unit variant is written as its name, any other as an object keyed by its name
+        let Ok(Envelope { version, payload }) = serde_json::from_slice::<Envelope<serde_json::Value>>(bytes) else { return Err(e.into()); };
+        let variant = match payload {
+            serde_json::Value::String(name) => Some(name),
+            serde_json::Value::Object(fields) if fields.len() == 1 => fields.into_iter().next().map(|(name, _)| name),
+            _ => None,
+        };
+        match variant {
+            Some(variant) if !message_variants().contains(&variant.as_str()) => Ok(Frame::Unknown { version, variant }),
+            _ => Err(e.into()),
+        }
+    }
+}
+
+//bincode's layout: fixed-width little-endian integers, u64 lengths and u32 variant indices.
+//no field names or tags, so the reader has to know the type it is reading
+pub struct Bincode;
+
+impl Codec for Bincode {
+    fn encode(&self, msg: &Message) -> io::Result<Vec<u8>> {
+        let mut writer = Writer { out: Vec::new() };
+        Envelope { version: PROTOCOL_VERSION, payload: msg }.serialize(&mut writer).map_err(Error::into_io)?;
+        Ok(writer.out)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> io::Result<Frame> {
+        let mut reader = Reader { input: bytes };
+        match Envelope::<Message>::deserialize(&mut reader) {
+            Ok(_) if !reader.input.is_empty() => Err(io::Error::new(io::ErrorKind::InvalidData, "trailing bytes after message")),
+            Ok(envelope) => Ok(Frame::Message(envelope.payload)),
+            Err(e) => {
+                //the version's two bytes, then the variant index
+                let mut header = Reader { input: bytes };
+                let (Ok(version), Ok(index)) = (header.take_array().map(u16::from_le_bytes), header.read_u32()) else { return Err(e.into_io()); };
+                if usize::try_from(index).is_ok_and(|index| index >= message_variants().len()) {
+                    Ok(Frame::Unknown { version, variant: format!("#{}", index) })
+                } else {
+                    Err(e.into_io())
+                }
+            }
+        }
+    }
+}
+
+//the variant names serde derived for Message, read off by asking it to deserialize itself from a
+//deserializer that only records what it is asked for
+fn message_variants() -> &'static [&'static str] {
+    struct Probe<'a>(&'a Cell<&'static [&'static str]>);
+
+    impl<'de> de::Deserializer<'de> for Probe<'_> {
+        type Error = Error;
+
+        fn deserialize_any<V: de::Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Error> {
+            Err(Error(String::from("only an enum can be probed")))
+        }
+
+        fn deserialize_enum<V: de::Visitor<'de>>(self, _name: &'static str, variants: &'static [&'static str], _visitor: V) -> Result<V::Value, Error> {
+            self.0.set(variants);
+            Err(Error(String::from("probed")))
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf option
+            unit unit_struct newtype_struct seq tuple tuple_struct map struct identifier ignored_any
+        }
+    }
+
+    let variants = Cell::new(&[][..]);
+    let _ = Message::deserialize(Probe(&variants));
+    variants.get()
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum CodecKind {
+    #[default]
+    Json,
+    Bincode,
+}
+
+impl CodecKind {
+    pub fn codec(self) -> &'static dyn Codec {
+        match self {
+            CodecKind::Json => &Json,
+            CodecKind::Bincode => &Bincode,
+        }
+    }
+}
+
+impl FromStr for CodecKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(CodecKind::Json),
+            "bincode" => Ok(CodecKind::Bincode),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Error(String);
+
+impl Error {
+    fn into_io(self) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, self.0)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error(msg.to_string())
+    }
+}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error(msg.to_string())
+    }
+}
+
+struct Writer {
+    out: Vec<u8>,
+}
+
+impl Writer {
+    fn write_len(&mut self, len: usize) {
+        self.out.extend_from_slice(&(len as u64).to_le_bytes());
+    }
+
+    fn write_variant(&mut self, index: u32) {
+        self.out.extend_from_slice(&index.to_le_bytes());
+    }
+}
+
+impl ser::Serializer for &mut Writer {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Self;
+    type SerializeMap = Self;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Self;
+
+    fn serialize_bool(self, v: bool) -> Result<(), Error> {
+        self.out.push(v as u8);
+        Ok(())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), Error> {
+        self.out.extend_from_slice(&v.to_le_bytes());
+        Ok(())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), Error> {
+        self.out.extend_from_slice(&v.to_le_bytes());
+        Ok(())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), Error> {
+        self.out.extend_from_slice(&v.to_le_bytes());
+        Ok(())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), Error> {
+        self.out.extend_from_slice(&v.to_le_bytes());
+        Ok(())
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<(), Error> {
+        self.out.extend_from_slice(&v.to_le_bytes());
+        Ok(())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), Error> {
+        self.out.push(v);
+        Ok(())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), Error> {
+        self.out.extend_from_slice(&v.to_le_bytes());
+        Ok(())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), Error> {
+        self.out.extend_from_slice(&v.to_le_bytes());
+        Ok(())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), Error> {
+        self.out.extend_from_slice(&v.to_le_bytes());
+        Ok(())
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<(), Error> {
+        self.out.extend_from_slice(&v.to_le_bytes());
+        Ok(())
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), Error> {
+        self.out.extend_from_slice(&v.to_le_bytes());
+        Ok(())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), Error> {
+        self.out.extend_from_slice(&v.to_le_bytes());
+        Ok(())
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), Error> {
+        self.serialize_u32(v as u32)
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), Error> {
+        self.serialize_bytes(v.as_bytes())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), Error> {
+        self.write_len(v.len());
+        self.out.extend_from_slice(v);
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<(), Error> {
+        self.out.push(0);
+        Ok(())
+    }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<(), Error> {
+        self.out.push(1);
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn serialize_unit_variant(self, _name: &'static str, index: u32, _variant: &'static str) -> Result<(), Error> {
+        self.write_variant(index);
+        Ok(())
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(self, _name: &'static str, value: &T) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(self, _name: &'static str, index: u32, _variant: &'static str, value: &T) -> Result<(), Error> {
+        self.write_variant(index);
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self, Error> {
+        let len = len.ok_or_else(|| Error(String::from("sequence length must be known up front")))?;
+        self.write_len(len);
+        Ok(self)
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self, Error> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize) -> Result<Self, Error> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_variant(self, _name: &'static str, index: u32, _variant: &'static str, _len: usize) -> Result<Self, Error> {
+        self.write_variant(index);
+        Ok(self)
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<Self, Error> {
+        let len = len.ok_or_else(|| Error(String::from("map length must be known up front")))?;
+        self.write_len(len);
+        Ok(self)
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self, Error> {
+        Ok(self)
+    }
+
+    fn serialize_struct_variant(self, _name: &'static str, index: u32, _variant: &'static str, _len: usize) -> Result<Self, Error> {
+        self.write_variant(index);
+        Ok(self)
+    }
+}
+
+impl ser::SerializeSeq for &mut Writer {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeTuple for &mut Writer {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeTupleStruct for &mut Writer {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeTupleVariant for &mut Writer {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeMap for &mut Writer {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: ?Sized + Serialize>(&mut self, key: &T) -> Result<(), Error> {
+        key.serialize(&mut **self)
+    }
+
+    fn serialize_value<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeStruct for &mut Writer {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, _key: &'static str, value: &T) -> Result<(), Error> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeStructVariant for &mut Writer {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, _key: &'static str, value: &T) -> Result<(), Error> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+struct Reader<'de> {
+    input: &'de [u8],
+}
+
+impl<'de> Reader<'de> {
+    fn take(&mut self, n: usize) -> Result<&'de [u8], Error> {
+        if self.input.len() < n {
+            return Err(Error(String::from("unexpected end of input")));
+        }
+        let (head, rest) = self.input.split_at(n);
+        self.input = rest;
+        Ok(head)
+    }
+
+    fn take_array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        Ok(self.take(N)?.try_into().expect("took exactly N bytes"))
+    }
+
+    fn read_len(&mut self) -> Result<usize, Error> {
+        let len = u64::from_le_bytes(self.take_array()?);
+        usize::try_from(len).map_err(|_| Error(String::from("length does not fit in memory")))
+    }
+
+    fn read_u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.take_array()?))
+    }
+}
+
+impl<'de> de::Deserializer<'de> for &mut Reader<'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: de::Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Error> {
+        Err(Error(String::from("bincode needs to know the type it is reading")))
+    }
+
+    fn deserialize_bool<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.take(1)?[0] {
+            0 => visitor.visit_bool(false),
+            1 => visitor.visit_bool(true),
+            b => Err(Error(format!("invalid bool byte {}", b))),
+        }
+    }
+
+    fn deserialize_i8<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_i8(i8::from_le_bytes(self.take_array()?))
+    }
+
+    fn deserialize_i16<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_i16(i16::from_le_bytes(self.take_array()?))
+    }
+
+    fn deserialize_i32<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_i32(i32::from_le_bytes(self.take_array()?))
+    }
+
+    fn deserialize_i64<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_i64(i64::from_le_bytes(self.take_array()?))
+    }
+
+    fn deserialize_i128<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_i128(i128::from_le_bytes(self.take_array()?))
+    }
+
+    fn deserialize_u8<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_u8(self.take(1)?[0])
+    }
+
+    fn deserialize_u16<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_u16(u16::from_le_bytes(self.take_array()?))
+    }
+
+    fn deserialize_u32<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_u32(self.read_u32()?)
+    }
+
+    fn deserialize_u64<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_u64(u64::from_le_bytes(self.take_array()?))
+    }
+
+    fn deserialize_u128<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_u128(u128::from_le_bytes(self.take_array()?))
+    }
+
+    fn deserialize_f32<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_f32(f32::from_le_bytes(self.take_array()?))
+    }
+
+    fn deserialize_f64<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_f64(f64::from_le_bytes(self.take_array()?))
+    }
+
+    fn deserialize_char<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let code = self.read_u32()?;
+        let c = char::from_u32(code).ok_or_else(|| Error(format!("invalid char {:#x}", code)))?;
+        visitor.visit_char(c)
+    }
+
+    fn deserialize_str<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let len = self.read_len()?;
+        let s = std::str::from_utf8(self.take(len)?).map_err(|e| Error(e.to_string()))?;
+        visitor.visit_borrowed_str(s)
+    }
+
+    fn deserialize_string<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_bytes<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let len = self.read_len()?;
+        visitor.visit_borrowed_bytes(self.take(len)?)
+    }
+
+    fn deserialize_byte_buf<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_option<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.take(1)?[0] {
+            0 => visitor.visit_none(),
+            1 => visitor.visit_some(self),
+            b => Err(Error(format!("invalid option tag {}", b))),
+        }
+    }
+
+    fn deserialize_unit<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: de::Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: de::Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let len = self.read_len()?;
+        visitor.visit_seq(Elements { reader: self, remaining: len })
+    }
+
+    fn deserialize_tuple<V: de::Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_seq(Elements { reader: self, remaining: len })
+    }
+
+    fn deserialize_tuple_struct<V: de::Visitor<'de>>(self, _name: &'static str, len: usize, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_seq(Elements { reader: self, remaining: len })
+    }
+
+    fn deserialize_map<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let len = self.read_len()?;
+        visitor.visit_map(Elements { reader: self, remaining: len })
+    }
+
+    fn deserialize_struct<V: de::Visitor<'de>>(self, _name: &'static str, fields: &'static [&'static str], visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_seq(Elements { reader: self, remaining: fields.len() })
+    }
+
+    fn deserialize_enum<V: de::Visitor<'de>>(self, _name: &'static str, _variants: &'static [&'static str], visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_enum(self)
+    }
+
+    fn deserialize_identifier<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_u32(visitor)
+    }
+
+    fn deserialize_ignored_any<V: de::Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Error> {
+        Err(Error(String::from("bincode can't skip values it doesn't know the type of")))
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+//sequences, tuples, structs and maps are all just their elements back to back
+struct Elements<'a, 'de> {
+    reader: &'a mut Reader<'de>,
+    remaining: usize,
+}
+
+impl<'de> de::SeqAccess<'de> for Elements<'_, 'de> {
+    type Error = Error;
+
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, Error> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+        seed.deserialize(&mut *self.reader).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.remaining)
+    }
+}
+
+impl<'de> de::MapAccess<'de> for Elements<'_, 'de> {
+    type Error = Error;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, Error> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+        seed.deserialize(&mut *self.reader).map(Some)
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        seed.deserialize(&mut *self.reader)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.remaining)
+    }
+}
+
+impl<'de> de::EnumAccess<'de> for &mut Reader<'de> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V: de::DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self), Error> {
+        let index = self.read_u32()?;
+        let variant = seed.deserialize(de::IntoDeserializer::<Error>::into_deserializer(index))?;
+        Ok((variant, self))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for &mut Reader<'de> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: de::DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: de::Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_seq(Elements { reader: self, remaining: len })
+    }
+
+    fn struct_variant<V: de::Visitor<'de>>(self, fields: &'static [&'static str], visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_seq(Elements { reader: self, remaining: fields.len() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+    use crate::{node_address, GenesisConfig};
+
+    //one of every Message variant, Blocks carrying a long chain
+    fn every_message() -> Vec<Message> {
+        let mut bchain = test_chain(GenesisConfig::default());
+        for _ in 0..50 {
+            let block = next_block(&bchain, &node_address(0), Vec::new());
+            bchain.add_block(block).unwrap();
+        }
+        let chain = bchain.chain.load_all();
+        let tx = transfer(0, &node_address(1), 5, 1, 0);
+        let header = chain[1].header.clone();
+
+        vec![
+            Message::Mine,
+            Message::Pause,
+            Message::Resume,
+            Message::Resync,
+            Message::Tx { tx: tx.clone(), hops_left: 3 },
+            Message::NewBlock { from: 1, block: chain[1].clone() },
+            Message::RequestChain { requester: 2, locator: bchain.locator() },
+            Message::ChainHeaders { peer: 1, requester: 2, from: 1, headers: bchain.headers_since(1) },
+            Message::GetBlocks { peer: 1, requester: 2, from: 1, count: 64 },
+            Message::Blocks { peer: 1, requester: 2, blocks: chain },
+            Message::Hello { node_id: 3, chain_id: 9, version: 1 },
+            Message::BadFrame { peer: 4 },
+            Message::GetTxProof { requester: 2, tx_hash: tx.hash() },
+            Message::TxProof { peer: 1, requester: 2, tx_hash: tx.hash(), block_header: Box::new(header), merkle_proof: vec![("ab".repeat(32), true)], height: 1 },
+        ]
+    }
+
+    #[test]
+    fn every_message_round_trips_through_both_codecs() {
+        let messages = every_message();
+        assert_eq!(messages.len(), message_variants().len());
+
+        for kind in [CodecKind::Json, CodecKind::Bincode] {
+            for msg in &messages {
+                let bytes = kind.codec().encode(msg).unwrap();
+                let Frame::Message(decoded) = kind.codec().decode(&bytes).unwrap() else { panic!("{:?} decoded to an unknown frame", kind); };
+                assert_eq!(serde_json::to_value(&decoded).unwrap(), serde_json::to_value(msg).unwrap(), "{:?}", kind);
+                assert_eq!(kind.codec().encode(&decoded).unwrap(), bytes, "{:?}", kind);
+            }
+        }
+    }
+
+    #[test]
+    fn bincode_frames_are_smaller_and_unreadable_as_json() {
+        let messages = every_message();
+        let blocks = messages.iter().find(|msg| matches!(msg, Message::Blocks { .. })).unwrap();
+        let json = Json.encode(blocks).unwrap();
+        let bincode = Bincode.encode(blocks).unwrap();
+        assert!(bincode.len() < json.len());
+        assert!(Json.decode(&bincode).is_err());
+        //read as bincode, json's opening bytes make a variant index no build has
+        assert!(matches!(Bincode.decode(&json), Ok(Frame::Unknown { .. })));
+    }
+
+    #[test]
+    fn a_message_type_from_a_newer_build_decodes_as_unknown() {
+        let json = serde_json::json!({ "version": 9, "payload": { "Gossip": { "peers": [1, 2] } } }).to_string();
+        assert!(matches!(Json.decode(json.as_bytes()), Ok(Frame::Unknown { version: 9, variant }) if variant == "Gossip"));
+        let json = serde_json::json!({ "version": 9, "payload": "Ping" }).to_string();
+        assert!(matches!(Json.decode(json.as_bytes()), Ok(Frame::Unknown { version: 9, variant }) if variant == "Ping"));
+
+        let mut bincode = 9u16.to_le_bytes().to_vec();
+        bincode.extend((message_variants().len() as u32).to_le_bytes());
+        bincode.extend([1, 2, 3]);
+        let unknown = format!("#{}", message_variants().len());
+        assert!(matches!(Bincode.decode(&bincode), Ok(Frame::Unknown { version: 9, variant }) if variant == unknown));
+
+        //a type we do know that doesn't decode is malformed, not newer
+        let json = serde_json::json!({ "version": 9, "payload": { "NewBlock": { "from": "one" } } }).to_string();
+        assert!(Json.decode(json.as_bytes()).is_err());
+        let mut bincode = 9u16.to_le_bytes().to_vec();
+        bincode.extend(0u32.to_le_bytes());
+        bincode.extend([1]);
+        assert!(Bincode.decode(&bincode).is_err());
+    }
+}
@@ -0,0 +1,470 @@
+//ed25519 signatures (RFC 8032), ported from TweetNaCl.
+//field elements are 16 limbs of 16 bits, points are extended (X, Y, Z, T) coordinates
+use sha2::{Digest, Sha512};
+
+type Gf = [i64; 16];
+type Point = [Gf; 4];
+
+const GF0: Gf = [0; 16];
+const GF1: Gf = [1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+const D: Gf = [
+    0x78a3, 0x1359, 0x4dca, 0x75eb, 0xd8ab, 0x4141, 0x0a4d, 0x0070,
+    0xe898, 0x7779, 0x4079, 0x8cc7, 0xfe73, 0x2b6f, 0x6cee, 0x5203,
+];
+const D2: Gf = [
+    0xf159, 0x26b2, 0x9b94, 0xebd6, 0xb156, 0x8283, 0x149a, 0x00e0,
+    0xd130, 0xeef3, 0x80f2, 0x198e, 0xfce7, 0x56df, 0xd9dc, 0x2406,
+];
+const X: Gf = [
+    0xd51a, 0x8f25, 0x2d60, 0xc956, 0xa7b2, 0x9525, 0xc760, 0x692c,
+    0xdc5c, 0xfdd6, 0xe231, 0xc0a4, 0x53fe, 0xcd6e, 0x36d3, 0x2169,
+];
+const Y: Gf = [
+    0x6658, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666,
+    0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666,
+];
+const I: Gf = [
+    0xa0b0, 0x4a0e, 0x1b27, 0xc4ee, 0xe478, 0xad2f, 0x1806, 0x2f43,
+    0xd7a7, 0x3dfb, 0x0099, 0x2b4d, 0xdf0b, 0x4fc1, 0x2480, 0x2b83,
+];
+//group order, little endian
+const L: [i64; 32] = [
+    0xed, 0xd3, 0xf5, 0x5c, 0x1a, 0x63, 0x12, 0x58, 0xd6, 0x9c, 0xf7, 0xa2, 0xde, 0xf9, 0xde, 0x14,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x10,
+];
+
+fn car25519(o: &mut Gf) {
+    for i in 0..16 {
+        o[i] += 1 << 16;
+        let c = o[i] >> 16;
+        if i < 15 {
+            o[i + 1] += c - 1;
+        } else {
+            o[0] += 38 * (c - 1);
+        }
+        o[i] -= c << 16;
+    }
+}
+
+fn sel25519(p: &mut Gf, q: &mut Gf, b: i64) {
+    let c = !(b - 1);
+    for i in 0..16 {
+        let t = c & (p[i] ^ q[i]);
+        p[i] ^= t;
+        q[i] ^= t;
+    }
+}
+
+fn pack25519(n: &Gf) -> [u8; 32] {
+    let mut t = *n;
+    car25519(&mut t);
+    car25519(&mut t);
+    car25519(&mut t);
+
+    for _ in 0..2 {
+        let mut m = GF0;
+        m[0] = t[0] - 0xffed;
+        for i in 1..15 {
+            m[i] = t[i] - 0xffff - ((m[i - 1] >> 16) & 1);
+            m[i - 1] &= 0xffff;
+        }
+        m[15] = t[15] - 0x7fff - ((m[14] >> 16) & 1);
+        let b = (m[15] >> 16) & 1;
+        m[14] &= 0xffff;
+        sel25519(&mut t, &mut m, 1 - b);
+    }
+
+    let mut o = [0u8; 32];
+    for i in 0..16 {
+        o[2 * i] = (t[i] & 0xff) as u8;
+        o[2 * i + 1] = (t[i] >> 8) as u8;
+    }
+    o
+}
+
+fn neq25519(a: &Gf, b: &Gf) -> bool {
+    pack25519(a) != pack25519(b)
+}
+
+fn par25519(a: &Gf) -> u8 {
+    pack25519(a)[0] & 1
+}
+
+fn unpack25519(n: &[u8; 32]) -> Gf {
+    let mut o = GF0;
+    for i in 0..16 {
+        o[i] = n[2 * i] as i64 + ((n[2 * i + 1] as i64) << 8);
+    }
+    o[15] &= 0x7fff;
+    o
+}
+
+fn add_gf(a: &Gf, b: &Gf) -> Gf {
+    let mut o = GF0;
+    for i in 0..16 {
+        o[i] = a[i] + b[i];
+    }
+    o
+}
+
+fn sub_gf(a: &Gf, b: &Gf) -> Gf {
+    let mut o = GF0;
+    for i in 0..16 {
+        o[i] = a[i] - b[i];
+    }
+    o
+}
+
+fn mul_gf(a: &Gf, b: &Gf) -> Gf {
+    let mut t = [0i64; 31];
+    for i in 0..16 {
+        for j in 0..16 {
+            t[i + j] += a[i] * b[j];
+        }
+    }
+    for i in 0..15 {
+        t[i] += 38 * t[i + 16];
+    }
+
+    let mut o = GF0;
+    o.copy_from_slice(&t[..16]);
+    car25519(&mut o);
+    car25519(&mut o);
+    o
+}
+
+fn square_gf(a: &Gf) -> Gf {
+    mul_gf(a, a)
+}
+
+fn inv25519(i: &Gf) -> Gf {
+    let mut c = *i;
+    for a in (0..=253).rev() {
+        c = square_gf(&c);
+        if a != 2 && a != 4 {
+            c = mul_gf(&c, i);
+        }
+    }
+    c
+}
+
+fn pow2523(i: &Gf) -> Gf {
+    let mut c = *i;
+    for a in (0..=250).rev() {
+        c = square_gf(&c);
+        if a != 1 {
+            c = mul_gf(&c, i);
+        }
+    }
+    c
+}
+
+fn add_point(p: &mut Point, q: &Point) {
+    let a = mul_gf(&sub_gf(&p[1], &p[0]), &sub_gf(&q[1], &q[0]));
+    let b = mul_gf(&add_gf(&p[0], &p[1]), &add_gf(&q[0], &q[1]));
+    let c = mul_gf(&mul_gf(&p[3], &q[3]), &D2);
+    let d = mul_gf(&p[2], &q[2]);
+    let d = add_gf(&d, &d);
+    let e = sub_gf(&b, &a);
+    let f = sub_gf(&d, &c);
+    let g = add_gf(&d, &c);
+    let h = add_gf(&b, &a);
+
+    p[0] = mul_gf(&e, &f);
+    p[1] = mul_gf(&h, &g);
+    p[2] = mul_gf(&g, &f);
+    p[3] = mul_gf(&e, &h);
+}
+
+fn cswap(p: &mut Point, q: &mut Point, b: u8) {
+    for i in 0..4 {
+        sel25519(&mut p[i], &mut q[i], b as i64);
+    }
+}
+
+fn pack(p: &Point) -> [u8; 32] {
+    let zi = inv25519(&p[2]);
+    let tx = mul_gf(&p[0], &zi);
+    let ty = mul_gf(&p[1], &zi);
+    let mut r = pack25519(&ty);
+    r[31] ^= par25519(&tx) << 7;
+    r
+}
+
+fn scalarmult(q: &Point, s: &[u8; 32]) -> Point {
+    let mut p: Point = [GF0, GF1, GF1, GF0];
+    let mut q = *q;
+
+    for i in (0..256).rev() {
+        let b = (s[i / 8] >> (i & 7)) & 1;
+        cswap(&mut p, &mut q, b);
+        let p_copy = p;
+        add_point(&mut q, &p_copy);
+        add_point(&mut p, &p_copy);
+        cswap(&mut p, &mut q, b);
+    }
+    p
+}
+
+fn scalarbase(s: &[u8; 32]) -> Point {
+    let q: Point = [X, Y, GF1, mul_gf(&X, &Y)];
+    scalarmult(&q, s)
+}
+
+fn mod_l(x: &mut [i64; 64]) -> [u8; 32] {
+    for i in (32..64).rev() {
+        let mut carry = 0;
+        let mut j = i - 32;
+        while j < i - 12 {
+            x[j] += carry - 16 * x[i] * L[j - (i - 32)];
+            carry = (x[j] + 128) >> 8;
+            x[j] -= carry << 8;
+            j += 1;
+        }
+        x[j] += carry;
+        x[i] = 0;
+    }
+
+    let mut carry = 0;
+    for j in 0..32 {
+        x[j] += carry - (x[31] >> 4) * L[j];
+        carry = x[j] >> 8;
+        x[j] &= 255;
+    }
+    for j in 0..32 {
+        x[j] -= carry * L[j];
+    }
+
+    let mut r = [0u8; 32];
+    for i in 0..32 {
+        x[i + 1] += x[i] >> 8;
+        r[i] = (x[i] & 255) as u8;
+    }
+    r
+}
+
+//whether the little-endian scalar is below L, as RFC 8032 requires of a signature's S. S and S + L
+//land on the same point, so without it anyone could turn a signature into a second valid one
+fn is_below_l(s: &[u8; 32]) -> bool {
+    for i in (0..32).rev() {
+        if (s[i] as i64) != L[i] {
+            return (s[i] as i64) < L[i];
+        }
+    }
+    false
+}
+
+fn reduce(h: &[u8]) -> [u8; 32] {
+    let mut x = [0i64; 64];
+    for (i, b) in h.iter().enumerate() {
+        x[i] = *b as i64;
+    }
+    mod_l(&mut x)
+}
+
+//decodes a public key into -A, None if it isn't a point on the curve
+fn unpackneg(p: &[u8; 32]) -> Option<Point> {
+    let mut r: Point = [GF0, GF0, GF1, GF0];
+    r[1] = unpack25519(p);
+
+    let num = square_gf(&r[1]);
+    let den = mul_gf(&num, &D);
+    let num = sub_gf(&num, &r[2]);
+    let den = add_gf(&r[2], &den);
+
+    let den2 = square_gf(&den);
+    let den4 = square_gf(&den2);
+    let den6 = mul_gf(&den4, &den2);
+    let mut t = mul_gf(&den6, &num);
+    t = mul_gf(&t, &den);
+
+    t = pow2523(&t);
+    t = mul_gf(&t, &num);
+    t = mul_gf(&t, &den);
+    t = mul_gf(&t, &den);
+    r[0] = mul_gf(&t, &den);
+
+    let chk = mul_gf(&square_gf(&r[0]), &den);
+    if neq25519(&chk, &num) {
+        r[0] = mul_gf(&r[0], &I);
+    }
+
+    let chk = mul_gf(&square_gf(&r[0]), &den);
+    if neq25519(&chk, &num) {
+        return None;
+    }
+
+    if par25519(&r[0]) == (p[31] >> 7) {
+        r[0] = sub_gf(&GF0, &r[0]);
+    }
+
+    r[3] = mul_gf(&r[0], &r[1]);
+    Some(r)
+}
+
+fn expand_secret(secret: &[u8; 32]) -> [u8; 64] {
+    let mut d = [0u8; 64];
+    d.copy_from_slice(&Sha512::digest(secret));
+    d[0] &= 248;
+    d[31] &= 127;
+    d[31] |= 64;
+    d
+}
+
+#[derive(Clone)]
+pub struct Keypair {
+    pub secret: [u8; 32],
+    pub public: [u8; 32],
+}
+
+impl Keypair {
+    pub fn generate() -> Keypair {
+        Keypair::from_secret(rand::random())
+    }
+
+    pub fn from_secret(secret: [u8; 32]) -> Keypair {
+        let d = expand_secret(&secret);
+        let mut scalar = [0u8; 32];
+        scalar.copy_from_slice(&d[..32]);
+
+        Keypair { secret, public: pack(&scalarbase(&scalar)) }
+    }
+
+    pub fn sign(&self, msg: &[u8]) -> [u8; 64] {
+        let d = expand_secret(&self.secret);
+
+        let mut hasher = Sha512::new();
+        hasher.update(&d[32..]);
+        hasher.update(msg);
+        let r = reduce(&hasher.finalize());
+
+        let big_r = pack(&scalarbase(&r));
+
+        let mut hasher = Sha512::new();
+        hasher.update(big_r);
+        hasher.update(self.public);
+        hasher.update(msg);
+        let h = reduce(&hasher.finalize());
+
+        //s = r + h * a mod L
+        let mut x = [0i64; 64];
+        for i in 0..32 {
+            x[i] = r[i] as i64;
+        }
+        for i in 0..32 {
+            for j in 0..32 {
+                x[i + j] += h[i] as i64 * d[j] as i64;
+            }
+        }
+        let s = mod_l(&mut x);
+
+        let mut sig = [0u8; 64];
+        sig[..32].copy_from_slice(&big_r);
+        sig[32..].copy_from_slice(&s);
+        sig
+    }
+}
+
+pub fn verify(public: &[u8], msg: &[u8], sig: &[u8]) -> bool {
+    let (Ok(public), Ok(sig)) = (<[u8; 32]>::try_from(public), <[u8; 64]>::try_from(sig)) else {
+        return false;
+    };
+
+    let mut s = [0u8; 32];
+    s.copy_from_slice(&sig[32..]);
+    if !is_below_l(&s) {
+        return false;
+    }
+    let Some(neg_a) = unpackneg(&public) else { return false; };
+
+    let mut hasher = Sha512::new();
+    hasher.update(&sig[..32]);
+    hasher.update(public);
+    hasher.update(msg);
+    let h = reduce(&hasher.finalize());
+
+    //s·B - h·A has to land back on R
+    let mut p = scalarmult(&neg_a, &h);
+    add_point(&mut p, &scalarbase(&s));
+
+    pack(&p) == sig[..32]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //(secret, public, message, signature) from RFC 8032 section 7.1
+    const VECTORS: [(&str, &str, &str, &str); 3] = [
+        (
+            "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60",
+            "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+            "",
+            "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+        ),
+        (
+            "4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb",
+            "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c",
+            "72",
+            "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+        ),
+        (
+            "c5aa8df43f9f837bedb7442f31dcb7b166d38535076f094b85ce3a2e0b4458f7",
+            "fc51cd8e6218a1a38da47ed00230f0580816ed13ba3303ac5deb911548908025",
+            "af82",
+            "6291d657deec24024827e69c3abe01a30ce548a284743a445e3680d7db5ac3ac18ff9b538d16f290ae67f760984dc6594a7c15e9716ed28dc027beceea1ec40a",
+        ),
+    ];
+
+    #[test]
+    fn matches_the_rfc_8032_vectors() {
+        for (secret, public, msg, sig) in VECTORS {
+            let keypair = Keypair::from_secret(hex::decode(secret).unwrap().try_into().unwrap());
+            let msg = hex::decode(msg).unwrap();
+            assert_eq!(hex::encode(keypair.public), public);
+            assert_eq!(hex::encode(keypair.sign(&msg)), sig);
+            assert!(verify(&keypair.public, &msg, &hex::decode(sig).unwrap()));
+        }
+    }
+
+    #[test]
+    fn rejects_a_changed_message_signature_or_key() {
+        let keypair = Keypair::from_secret([7; 32]);
+        let sig = keypair.sign(b"pay bob 5");
+        assert!(verify(&keypair.public, b"pay bob 5", &sig));
+        assert!(!verify(&keypair.public, b"pay bob 6", &sig));
+
+        let mut flipped = sig;
+        flipped[40] ^= 1;
+        assert!(!verify(&keypair.public, b"pay bob 5", &flipped));
+        assert!(!verify(&Keypair::from_secret([8; 32]).public, b"pay bob 5", &sig));
+        assert!(!verify(&keypair.public[..31], b"pay bob 5", &sig));
+    }
+
+    #[test]
+    fn rejects_a_signature_whose_s_is_not_below_the_group_order() {
+        let keypair = Keypair::from_secret([7; 32]);
+        let sig = keypair.sign(b"pay bob 5");
+
+        //(R, S + L) hits the same point as (R, S)
+        let mut malleated = sig;
+        let mut carry = 0;
+        for i in 0..32 {
+            let sum = sig[32 + i] as i64 + L[i] + carry;
+            malleated[32 + i] = (sum & 255) as u8;
+            carry = sum >> 8;
+        }
+        assert_eq!(carry, 0);
+        assert!(!verify(&keypair.public, b"pay bob 5", &malleated));
+
+        let mut l = [0u8; 32];
+        for i in 0..32 {
+            l[i] = L[i] as u8;
+        }
+        let mut just_below = l;
+        just_below[0] -= 1;
+        assert!(!is_below_l(&l));
+        assert!(is_below_l(&just_below));
+        assert!(!is_below_l(&[0xff; 32]));
+    }
+}
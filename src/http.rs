@@ -0,0 +1,272 @@
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Mutex};
+
+use crate::{verify_merkle_proof, Address, Blockchain, Message};
+use crate::metrics::Metrics;
+use crate::{rpc, ws};
+
+//requests are GETs or a single rpc call, anything larger than this is rejected
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn json<T: serde::Serialize>(value: &T) -> Response {
+        Response { status: 200, content_type: "application/json", body: serde_json::to_string(value).expect("response serializes") }
+    }
+
+    fn text(body: String) -> Response {
+        Response { status: 200, content_type: "text/plain; version=0.0.4", body }
+    }
+
+    fn error(status: u16, message: &str) -> Response {
+        Response { status, content_type: "application/json", body: serde_json::json!({ "error": message }).to_string() }
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        202 => "Accepted",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
+
+//read-only view of a node's chain and metrics, served until the listener is dropped. POST /rpc
+//can also submit transactions and POST /resync makes the node sync from its peers, both are
+//handed to the node on `inbound`. GET /health reports 503 once the chain hasn't moved for
+//`health_window_ms`
+pub async fn serve(listener: TcpListener, blockchain: Arc<Mutex<Blockchain>>, metrics: Arc<Metrics>, wallet_address: Address, inbound: mpsc::Sender<Message>, health_window_ms: u128) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                warn!("http accept failed: {}", e);
+                continue;
+            }
+        };
+
+        let blockchain = blockchain.clone();
+        let metrics = metrics.clone();
+        let wallet_address = wallet_address.clone();
+        let inbound = inbound.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, blockchain, metrics, &wallet_address, &inbound, health_window_ms).await {
+                warn!(peer = addr; "http connection failed: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle_connection(mut stream: TcpStream, blockchain: Arc<Mutex<Blockchain>>, metrics: Arc<Metrics>, wallet_address: &Address, inbound: &mpsc::Sender<Message>, health_window_ms: u128) -> std::io::Result<()> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 1024];
+
+    //only the request line and headers matter, read until the blank line
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if buf.len() > MAX_REQUEST_LEN {
+            break;
+        }
+    }
+
+    let response = match parse_request_line(&buf) {
+        Some((method, path)) if method == "GET" && path == "/ws" => {
+            let Some(key) = header(&buf, "sec-websocket-key") else {
+                return write_response(&mut stream, Response::error(400, "expected a websocket upgrade")).await;
+            };
+
+            let (tip, blocks) = {
+                let bchain = blockchain.lock().await;
+                (bchain.last_block().clone(), bchain.new_blocks.subscribe())
+            };
+            let head = format!(
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                ws::accept_key(&key),
+            );
+            stream.write_all(head.as_bytes()).await?;
+            return ws::serve_blocks(stream, tip, blocks).await;
+        }
+        Some((method, path)) if method == "POST" && path == "/rpc" => {
+            let Some(body) = read_body(&mut stream, &mut buf).await? else {
+                return write_response(&mut stream, Response::error(400, "expected a body with a Content-Length")).await;
+            };
+            match rpc::handle(&body, &blockchain, inbound).await {
+                Some(reply) => Response::json(&reply),
+                None => Response { status: 204, content_type: "application/json", body: String::new() },
+            }
+        }
+        //202, the node only asks its peers here, whether it catches up shows in GET /health
+        Some((method, path)) if method == "POST" && path == "/resync" => match inbound.send(Message::Resync).await {
+            Ok(()) => Response { status: 202, ..Response::json(&serde_json::json!({ "resyncing": true })) },
+            Err(_) => Response::error(503, "node has stopped"),
+        },
+        Some((method, path)) => {
+            let bchain = blockchain.lock().await;
+            route(&method, &path, &bchain, &metrics, wallet_address, health_window_ms)
+        }
+        None => Response::error(400, "malformed request"),
+    };
+
+    write_response(&mut stream, response).await
+}
+
+async fn write_response(stream: &mut TcpStream, response: Response) -> std::io::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason(response.status),
+        response.content_type,
+        response.body.len(),
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(response.body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+//the request body, from what already came in with the headers plus the rest of Content-Length.
+//None without a usable length or when the request would exceed MAX_REQUEST_LEN
+async fn read_body(stream: &mut TcpStream, buf: &mut Vec<u8>) -> std::io::Result<Option<Vec<u8>>> {
+    let Some(head_len) = buf.windows(4).position(|w| w == b"\r\n\r\n").map(|i| i + 4) else { return Ok(None); };
+    let Some(len) = header(buf, "content-length").and_then(|len| len.parse::<usize>().ok()) else { return Ok(None); };
+    if head_len + len > MAX_REQUEST_LEN {
+        return Ok(None);
+    }
+
+    let mut chunk = [0u8; 1024];
+    while buf.len() < head_len + len {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    Ok(Some(buf[head_len..head_len + len].to_vec()))
+}
+
+fn parse_request_line(buf: &[u8]) -> Option<(String, String)> {
+    let text = std::str::from_utf8(buf).ok()?;
+    let line = text.lines().next()?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?.to_string();
+    let path = parts.next()?.to_string();
+    Some((method, path))
+}
+
+//the value of the first header called `name`, matched case-insensitively
+fn header(buf: &[u8], name: &str) -> Option<String> {
+    let text = std::str::from_utf8(buf).ok()?;
+    text.lines()
+        .skip(1)
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim().to_string())
+}
+
+fn route(method: &str, path: &str, bchain: &Blockchain, metrics: &Metrics, wallet_address: &Address, health_window_ms: u128) -> Response {
+    if method != "GET" {
+        return Response::error(405, "only GET is supported, apart from POST /rpc and POST /resync");
+    }
+
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+    match segments.as_slice() {
+        ["chain"] => Response::json(&bchain.chain.blocks()),
+        //the chain's tip and those of competing branches, heaviest first
+        ["tips"] => {
+            let best = bchain.tree.best_tip().map(|block| &block.header.hash);
+            let mut tips = bchain.tree.tips();
+            tips.sort_by_key(|block| std::cmp::Reverse(bchain.tree.work(&block.header.hash)));
+            //work is a u128, sent as a string so no json reader loses precision
+            Response::json(&tips.into_iter()
+                .map(|block| serde_json::json!({
+                    "index": block.header.index,
+                    "hash": block.header.hash,
+                    "work": bchain.tree.work(&block.header.hash).to_string(),
+                    "best": best == Some(&block.header.hash),
+                }))
+                .collect::<Vec<_>>())
+        }
+        ["chain", "dot"] => Response { status: 200, content_type: "text/vnd.graphviz", body: bchain.to_dot() },
+        ["metrics"] => Response::text(metrics.render(bchain)),
+        ["stats"] => Response::json(&bchain.stats()),
+        ["health"] => {
+            let health = bchain.health(health_window_ms);
+            let status = if health.healthy { 200 } else { 503 };
+            Response { status, ..Response::json(&health) }
+        }
+        //the node's own earnings
+        ["wallet"] => Response::json(&serde_json::json!({
+            "address": wallet_address.to_string(),
+            "balance": bchain.balance_of(wallet_address),
+        })),
+        ["block", "hash", hash] => match bchain.block_by_hash(hash) {
+            Some(block) => Response::json(block),
+            None => Response::error(404, "block not found"),
+        },
+        ["block", index, "proof", tx_index] => {
+            let block = index.parse::<u64>().ok().and_then(|i| bchain.block_by_index(i));
+            let tx_index = tx_index.parse::<usize>().ok();
+            match block.zip(tx_index).and_then(|(block, i)| Some((block, i, block.merkle_proof(i)?))) {
+                Some((block, i, proof)) => {
+                    let tx_hash = block.body.transactions[i].hash();
+                    debug_assert!(verify_merkle_proof(&tx_hash, &proof, &block.header.merkle_root));
+                    Response::json(&serde_json::json!({
+                        "tx_hash": tx_hash,
+                        "merkle_root": block.header.merkle_root,
+                        "proof": proof,
+                    }))
+                }
+                None => Response::error(404, "transaction not found"),
+            }
+        }
+        ["tx", hash] => match bchain.find_transaction(hash) {
+            Some((height, tx)) => Response::json(&serde_json::json!({ "height": height, "tx": tx })),
+            None => Response::error(404, "transaction not in chain"),
+        },
+        ["block", index] => match index.parse::<u64>().ok().and_then(|i| bchain.block_by_index(i)) {
+            Some(block) => Response::json(block),
+            None => Response::error(404, "block not found"),
+        },
+        //keyed by account, the payload of an address, and listed as checksummed addresses
+        ["balances", height] => match height.parse::<u64>().ok().and_then(|h| bchain.balances_at_height(h)) {
+            Some(balances) => Response::json(&balances.into_iter()
+                .map(|(account, balance)| (Address::from_payload(hex::decode(&account).unwrap_or_default()).to_string(), balance))
+                .collect::<std::collections::HashMap<_, _>>()),
+            None => Response::error(404, "height not in chain"),
+        },
+        ["balance", address, "confirmed", confirmations] => match (Address::parse(address), confirmations.parse::<u64>()) {
+            (Ok(address), Ok(confirmations)) => Response::json(&serde_json::json!({
+                "address": address.to_string(),
+                "confirmations": confirmations,
+                "balance": bchain.confirmed_balance_of(&address, confirmations),
+            })),
+            (Err(e), _) => Response::error(400, &e.to_string()),
+            (_, Err(_)) => Response::error(400, "confirmations must be a non-negative integer"),
+        },
+        ["balance", address] => match Address::parse(address) {
+            Ok(address) => Response::json(&serde_json::json!({
+                "address": address.to_string(),
+                "balance": bchain.balance_of(&address),
+            })),
+            Err(e) => Response::error(400, &e.to_string()),
+        },
+        _ => Response::error(404, "not found"),
+    }
+}
@@ -0,0 +1,150 @@
+//leveled logging with key=value fields and a per-node span.
+//RUST_LOG takes comma separated `level` or `target=level` directives, e.g. RUST_LOG=smblockchain=debug
+use std::fmt;
+use std::future::Future;
+use std::sync::OnceLock;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    fn parse(s: &str) -> Option<Level> {
+        match s.to_ascii_lowercase().as_str() {
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            "trace" => Some(Level::Trace),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        };
+        f.pad(name)
+    }
+}
+
+struct Filter {
+    default: Level,
+    //(target prefix, level), the longest matching prefix wins
+    directives: Vec<(String, Level)>,
+}
+
+impl Filter {
+    fn parse(spec: &str) -> Filter {
+        let mut filter = Filter { default: Level::Info, directives: Vec::new() };
+
+        for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some((target, level)) => {
+                    if let Some(level) = Level::parse(level) {
+                        filter.directives.push((target.to_string(), level));
+                    }
+                }
+                None => {
+                    if let Some(level) = Level::parse(directive) {
+                        filter.default = level;
+                    }
+                }
+            }
+        }
+
+        filter
+    }
+
+    fn max_level(&self, target: &str) -> Level {
+        self.directives.iter()
+            .filter(|(prefix, _)| target.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, level)| *level)
+            .unwrap_or(self.default)
+    }
+}
+
+static FILTER: OnceLock<Filter> = OnceLock::new();
+
+fn filter() -> &'static Filter {
+    FILTER.get_or_init(|| Filter::parse(&std::env::var("RUST_LOG").unwrap_or_default()))
+}
+
+pub fn init() {
+    filter();
+}
+
+pub fn enabled(level: Level, target: &str) -> bool {
+    level <= filter().max_level(target)
+}
+
+tokio::task_local! {
+    static NODE_ID: usize;
+}
+
+//every event logged inside `fut` is tagged with the node it belongs to
+pub fn in_node_span<F: Future>(node_id: usize, fut: F) -> impl Future<Output = F::Output> {
+    NODE_ID.scope(node_id, fut)
+}
+
+//same as in_node_span, for work on a blocking thread
+pub fn in_node_span_sync<R>(node_id: usize, f: impl FnOnce() -> R) -> R {
+    NODE_ID.sync_scope(node_id, f)
+}
+
+pub fn emit(level: Level, target: &str, fields: &[(&str, &dyn fmt::Display)], args: fmt::Arguments<'_>) {
+    let mut line = format!("{:>5} ", level);
+
+    if let Ok(node_id) = NODE_ID.try_with(|id| *id) {
+        line.push_str(&format!("node{{node_id={}}}: ", node_id));
+    }
+
+    line.push_str(&format!("{}: {}", target, args));
+    for (key, value) in fields {
+        line.push_str(&format!(" {}={}", key, value));
+    }
+
+    println!("{}", line);
+}
+
+macro_rules! log_event {
+    ($level:expr, $($key:ident = $value:expr),+ ; $($arg:tt)+) => {
+        if $crate::logging::enabled($level, module_path!()) {
+            $crate::logging::emit(
+                $level,
+                module_path!(),
+                &[$((stringify!($key), &$value as &dyn std::fmt::Display)),+],
+                format_args!($($arg)+),
+            );
+        }
+    };
+    ($level:expr, $($arg:tt)+) => {
+        if $crate::logging::enabled($level, module_path!()) {
+            $crate::logging::emit($level, module_path!(), &[], format_args!($($arg)+));
+        }
+    };
+}
+
+macro_rules! warn {
+    ($($arg:tt)+) => { log_event!($crate::logging::Level::Warn, $($arg)+) };
+}
+
+macro_rules! info {
+    ($($arg:tt)+) => { log_event!($crate::logging::Level::Info, $($arg)+) };
+}
+
+macro_rules! debug {
+    ($($arg:tt)+) => { log_event!($crate::logging::Level::Debug, $($arg)+) };
+}
@@ -1,319 +1,3478 @@
-use sha2::{Digest, Sha256};
+use rand::seq::SliceRandom;
+use rand::{rngs::StdRng, SeedableRng};
+use sha2::{Digest, Sha256, Sha512};
 use serde::{Serialize, Deserialize};
-use tokio::sync::mpsc;
-use std::{sync::Arc};
-use tokio::sync::Mutex;
+use tokio::sync::{broadcast, mpsc};
+use std::{fs, io, path::{Path, PathBuf}, sync::Arc};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::{Mutex, Notify, Semaphore};
+
+#[macro_use]
+mod logging;
+
+mod address;
+mod block_tree;
+mod chain_store;
+mod ed25519;
+mod http;
+mod metrics;
+mod rpc;
+mod target;
+
+use target::Target;
+use address::Address;
+use block_tree::BlockTree;
+use chain_store::{ChainStore, MemoryStore};
+mod net;
+mod codec;
+mod ws;
+mod clock;
+use clock::Clock;
+#[cfg(test)]
+mod test_support;
+#[cfg(test)]
+mod tests;
+
+
+//chain files are compressed at this level, zstd's default
+const ZSTD_LEVEL: i32 = 3;
+//first four bytes of every zstd frame
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+//the json in a chain file, decompressed first when it is zstd. the format is told apart by the
+//magic bytes, not the file name
+fn read_chain_file(path: &Path) -> io::Result<Vec<u8>> {
+    let bytes = fs::read(path)?;
+    if bytes.starts_with(&ZSTD_MAGIC) {
+        zstd::decode_all(bytes.as_slice())
+    } else {
+        Ok(bytes)
+    }
+}
+
+//sender of newly issued coins, the only `from` allowed to go below zero
+const COINBASE: &str = "COINBASE";
+
+//most bits one retarget moves the target by either way, 2 being bitcoin's factor of 4
+const MAX_RETARGET_BITS: u32 = 2;
+
+//leading zero bits a retarget keeps the target between unless told otherwise. 1 never lets work
+//drop to nothing, 40 is already far past what the simulation can mine
+fn default_min_difficulty() -> u32 {
+    1
+}
+
+fn default_max_difficulty() -> u32 {
+    40
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Transaction {
+    //hex of the sender's ed25519 public key, or COINBASE
+    from: String,
+    //each recipient and what it is paid. one address may appear more than once
+    outputs: Vec<(Address, u64)>,
+    //paid to whoever mines the transaction, on top of the outputs
+    #[serde(default)]
+    fee: u64,
+    nonce: u64,
+    #[serde(default)]
+    signature: Vec<u8>,
+    #[serde(default)]
+    public_key: Vec<u8>,
+}
+
+impl Transaction {
+    //what is signed and who signed it, not the signature. a spend re-signed, or with its signature
+    //re-encoded, keeps its id and can't be relayed and mined again under a second one
+    fn hash(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.signing_bytes());
+        hasher.update(&self.public_key);
+        hex::encode(hasher.finalize())
+    }
+
+    //everything the signature covers, every output included
+    fn signing_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(&(&self.from, &self.outputs, self.fee, self.nonce)).expect("transaction serializes")
+    }
+
+    //what the outputs add up to, None if that overflows
+    fn amount(&self) -> Option<u64> {
+        self.outputs.iter().try_fold(0u64, |total, (_, amount)| total.checked_add(*amount))
+    }
+
+    //what the sender is debited, None if it doesn't fit a balance
+    fn cost(&self) -> Option<i64> {
+        i64::try_from(self.amount()?.checked_add(self.fee)?).ok()
+    }
+
+    fn sign(&mut self, keypair: &ed25519::Keypair) {
+        self.from = hex::encode(keypair.public);
+        self.public_key = keypair.public.to_vec();
+        self.signature = keypair.sign(&self.signing_bytes()).to_vec();
+    }
+
+    fn verify(&self) -> bool {
+        hex::encode(&self.public_key) == self.from
+            && ed25519::verify(&self.public_key, &self.signing_bytes(), &self.signature)
+    }
+}
+
+//simulation wallets are derived from the node id so they survive restarts with --state-dir
+fn node_keypair(id: usize) -> ed25519::Keypair {
+    ed25519::Keypair::from_secret(Sha256::digest(format!("node{}", id)).into())
+}
+
+//a saved chain always loads into memory, whatever store it was kept in
+fn non_empty_chain<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Box<dyn ChainStore>, D::Error> {
+    let chain = Vec::<Block>::deserialize(deserializer)?;
+    if chain.is_empty() {
+        return Err(serde::de::Error::custom("chain must hold at least the genesis block"));
+    }
+    Ok(Box::new(MemoryStore::new(chain)))
+}
+
+fn node_address(id: usize) -> Address {
+    Address::from_payload(node_keypair(id).public.to_vec())
+}
+
+fn merkle_parent(left: &str, right: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(hex::decode(left).unwrap_or_default());
+    hasher.update(hex::decode(right).unwrap_or_default());
+    hex::encode(hasher.finalize())
+}
+
+//binary tree of transaction hashes, an odd level duplicates its last node
+fn merkle_root(transactions: &[Transaction]) -> String {
+    if transactions.is_empty() {
+        return "0".repeat(64);
+    }
+
+    let mut level: Vec<String> = transactions.iter().map(Transaction::hash).collect();
+
+    while level.len() > 1 {
+        if level.len() % 2 == 1 {
+            level.push(level[level.len() - 1].clone());
+        }
+        level = level.chunks(2).map(|pair| merkle_parent(&pair[0], &pair[1])).collect();
+    }
+
+    level.remove(0)
+}
+
+//folds a transaction hash up a merkle_proof path, true when it lands on `root`
+fn verify_merkle_proof(tx_hash: &str, proof: &[(String, bool)], root: &str) -> bool {
+    let computed = proof.iter().fold(tx_hash.to_string(), |node, (sibling, sibling_is_left)| {
+        if *sibling_is_left {
+            merkle_parent(sibling, &node)
+        } else {
+            merkle_parent(&node, sibling)
+        }
+    });
+    computed == root
+}
+
+//each part is prefixed with its big-endian u64 length, so moving bytes from one field
+//into its neighbour always changes the digest
+trait Hasher: Sync {
+    fn hash(&self, parts: &[&[u8]]) -> String;
+}
+
+struct Sha256Hasher;
+
+impl Hasher for Sha256Hasher {
+    fn hash(&self, parts: &[&[u8]]) -> String {
+        let mut hasher = Sha256::new();
+        for part in parts {
+            hasher.update((part.len() as u64).to_be_bytes());
+            hasher.update(part);
+        }
+        hex::encode(hasher.finalize())
+    }
+}
+
+struct Sha512Hasher;
+
+impl Hasher for Sha512Hasher {
+    fn hash(&self, parts: &[&[u8]]) -> String {
+        let mut hasher = Sha512::new();
+        for part in parts {
+            hasher.update((part.len() as u64).to_be_bytes());
+            hasher.update(part);
+        }
+        hex::encode(hasher.finalize())
+    }
+}
+
+//sha-256 of the sha-256 digest, the way bitcoin hashes block headers
+struct DoubleSha256Hasher;
+
+impl Hasher for DoubleSha256Hasher {
+    fn hash(&self, parts: &[&[u8]]) -> String {
+        let mut hasher = Sha256::new();
+        for part in parts {
+            hasher.update((part.len() as u64).to_be_bytes());
+            hasher.update(part);
+        }
+        hex::encode(Sha256::digest(hasher.finalize()))
+    }
+}
+
+//recorded on the chain so it is always validated with the hasher it was built with
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+enum HashAlgorithm {
+    #[default]
+    Sha256,
+    DoubleSha256,
+    Sha512,
+}
+
+impl HashAlgorithm {
+    fn hasher(self) -> &'static dyn Hasher {
+        match self {
+            HashAlgorithm::Sha256 => &Sha256Hasher,
+            HashAlgorithm::DoubleSha256 => &DoubleSha256Hasher,
+            HashAlgorithm::Sha512 => &Sha512Hasher,
+        }
+    }
+}
+
+//what sets one network apart from another. chain_id goes into every block hash, so blocks from
+//a network with a different id never validate here
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct GenesisConfig {
+    data: String,
+    timestamp: u128,
+    chain_id: u64,
+    //credited by the genesis block, so these addresses can spend before anything is mined
+    #[serde(default)]
+    allocations: Vec<(Address, u64)>,
+}
+
+impl Default for GenesisConfig {
+    fn default() -> Self {
+        GenesisConfig {
+            data: String::from("Hi There"),
+            timestamp: 0,
+            chain_id: 0,
+            allocations: Vec::new(),
+        }
+    }
+}
+
+//everything the block hash covers, and the hash. enough to check linkage and proof of work
+//without the transactions, which only enter through merkle_root
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct BlockHeader {
+    index: u64,
+    timestamp: u128,
+    merkle_root: String,
+    previous_hash: String,
+    hash: String,
+    nonce: u64,
+    //proof-of-stake only, empty for mined blocks
+    #[serde(default)]
+    validator: String,
+    //the target the block was mined against, covered by the hash. None on forged blocks, the
+    //genesis block and blocks mined before the field existed
+    #[serde(default)]
+    target: Option<Target>,
+}
+
+impl BlockHeader {
+    //the hash this header should have on the network with `chain_id`
+    fn recompute_hash(&self, hasher: &dyn Hasher, chain_id: u64) -> String {
+        Block::compute_hash(hasher, chain_id, self.index, self.timestamp, &self.merkle_root, &self.previous_hash, self.nonce, &self.validator, self.target)
+    }
+
+    //what a light client can check of a TxProof without the block body: the header is the one
+    //whose hash, `known_hash`, it already has at that height, it hashes to itself and meets the
+    //target it records, and the proof leads from the tx to its merkle_root. a header matching
+    //nothing the client has proves nothing, anyone can make one up with no work in it
+    fn proves_inclusion(&self, hasher: &dyn Hasher, chain_id: u64, known_hash: &str, tx_hash: &str, proof: &[(String, bool)]) -> bool {
+        self.hash == known_hash
+            && self.recompute_hash(hasher, chain_id) == self.hash
+            && self.target.is_none_or(|target| target.is_met_by(&self.hash))
+            && verify_merkle_proof(tx_hash, proof, &self.merkle_root)
+    }
+}
+
+impl AsRef<BlockHeader> for BlockHeader {
+    fn as_ref(&self) -> &BlockHeader {
+        self
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+struct BlockBody {
+    transactions: Vec<Transaction>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(from = "FlatBlock")]
+struct Block {
+    header: BlockHeader,
+    body: BlockBody,
+}
+
+impl AsRef<BlockHeader> for Block {
+    fn as_ref(&self) -> &BlockHeader {
+        &self.header
+    }
+}
+
+//blocks keep the layout they had before the header was split out. #[serde(flatten)] would give
+//the same JSON but only works with self-describing formats, which the bincode codec isn't
+#[derive(Serialize, Deserialize)]
+struct FlatBlock<T = Vec<Transaction>, S = String> {
+    index: u64,
+    timestamp: u128,
+    transactions: T,
+    merkle_root: S,
+    previous_hash: S,
+    hash: S,
+    nonce: u64,
+    #[serde(default)]
+    validator: S,
+    #[serde(default)]
+    target: Option<Target>,
+}
+
+impl From<FlatBlock> for Block {
+    fn from(flat: FlatBlock) -> Self {
+        Block {
+            header: BlockHeader {
+                index: flat.index,
+                timestamp: flat.timestamp,
+                merkle_root: flat.merkle_root,
+                previous_hash: flat.previous_hash,
+                hash: flat.hash,
+                nonce: flat.nonce,
+                validator: flat.validator,
+                target: flat.target,
+            },
+            body: BlockBody { transactions: flat.transactions },
+        }
+    }
+}
+
+//one line for logs and the validate command, e.g. `#7 000495ab, 3 txs, ~12 leading zero bits (...)`
+impl std::fmt::Display for Block {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let hash = &self.header.hash;
+        write!(f, "#{} {}, {} txs, ", self.header.index, hash.get(..8).unwrap_or(hash), self.body.transactions.len())?;
+        match self.header.target {
+            Some(target) => write!(f, "{}", target),
+            None => write!(f, "no recorded target"),
+        }
+    }
+}
+
+impl Serialize for Block {
+    fn serialize<Ser: serde::Serializer>(&self, serializer: Ser) -> Result<Ser::Ok, Ser::Error> {
+        let header = &self.header;
+        FlatBlock {
+            index: header.index,
+            timestamp: header.timestamp,
+            transactions: &self.body.transactions,
+            merkle_root: &header.merkle_root,
+            previous_hash: &header.previous_hash,
+            hash: &header.hash,
+            nonce: header.nonce,
+            validator: &header.validator,
+            target: header.target,
+        }.serialize(serializer)
+    }
+}
+
+impl Block {
+
+    #[allow(clippy::too_many_arguments)]
+    fn compute_hash(hasher: &dyn Hasher, chain_id: u64, index: u64, timestamp: u128, merkle_root: &str, previous_hash: &str, nonce: u64, validator: &str, target: Option<Target>) -> String {
+        //numbers go in as fixed-width big-endian bytes, the same for every value and platform
+        let chain_id = chain_id.to_be_bytes();
+        let index = index.to_be_bytes();
+        let timestamp = timestamp.to_be_bytes();
+        let nonce = nonce.to_be_bytes();
+        let target = target.map(Target::to_hex);
+        let mut parts: Vec<&[u8]> = vec![
+            &chain_id,
+            &index,
+            &timestamp,
+            merkle_root.as_bytes(),
+            previous_hash.as_bytes(),
+            &nonce,
+            validator.as_bytes(),
+        ];
+        //blocks without a target hash exactly as they did before it was recorded
+        if let Some(target) = &target {
+            parts.push(target.as_bytes());
+        }
+        hasher.hash(&parts)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn from_parts(index: u64, timestamp: u128, transactions: Vec<Transaction>, merkle_root: String, previous_hash: String, hash: String, nonce: u64, validator: String, target: Option<Target>) -> Self {
+        Block {
+            header: BlockHeader { index, timestamp, merkle_root, previous_hash, hash, nonce, validator, target },
+            body: BlockBody { transactions },
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn new_block(hasher: &dyn Hasher, chain_id: u64, index: u64, timestamp: u128, transactions: Vec<Transaction>, previous_hash: String, nonce: u64) -> Self {
+        let merkle_root = merkle_root(&transactions);
+        let hash = Block::compute_hash(hasher, chain_id, index, timestamp, &merkle_root, &previous_hash, nonce, "", None);
+
+        Block::from_parts(index, timestamp, transactions, merkle_root, previous_hash, hash, nonce, String::new(), None)
+    }
+
+    //proof-of-stake block creation, no nonce grinding
+    #[allow(clippy::too_many_arguments)]
+    fn forge_block(hasher: &dyn Hasher, chain_id: u64, index: u64, timestamp: u128, transactions: Vec<Transaction>, previous_hash: String, validator: String) -> Self {
+        let merkle_root = merkle_root(&transactions);
+        let hash = Block::compute_hash(hasher, chain_id, index, timestamp, &merkle_root, &previous_hash, 0, &validator, None);
+
+        Block::from_parts(index, timestamp, transactions, merkle_root, previous_hash, hash, 0, validator, None)
+    }
+
+    fn genesis_block(hasher: &dyn Hasher, genesis: &GenesisConfig) -> Self {
+        let index = 0;
+        let timestamp = genesis.timestamp;
+        //coinbase-like credits, numbered so two allocations to one address still differ
+        let transactions = genesis.allocations.iter()
+            .enumerate()
+            .map(|(i, (to, amount))| Transaction {
+                from: String::from(COINBASE),
+                outputs: vec![(to.clone(), *amount)],
+                fee: 0,
+                nonce: i as u64,
+                signature: Vec::new(),
+                public_key: Vec::new(),
+            })
+            .collect();
+        //the genesis block has no parent, this slot commits to the network's genesis data instead
+        let previous_hash = hex::encode(Sha256::digest(genesis.data.as_bytes()));
+        let nonce = 0;
+
+        Block::new_block(hasher, genesis.chain_id, index, timestamp, transactions, previous_hash, nonce)
+    }
+
+
+    #[allow(clippy::too_many_arguments)]
+    fn mine_block(hasher: &dyn Hasher, chain_id: u64, index: u64, timestamp: u128, transactions: Vec<Transaction>, previous_hash: String, target: Target, cancel: &AtomicBool, yield_every: u64, progress: Option<&MiningProgress>) -> Result<Self, MineError> {
+
+        let merkle_root = merkle_root(&transactions);
+        let (nonce, hash) = Block::grind_nonces(hasher, chain_id, index, timestamp, &merkle_root, &previous_hash, target, 0, 1, &[cancel], yield_every, progress)?;
+
+        debug!(block_index = index, nonce = nonce, hash = hash; "block minted");
+        Ok(Block::from_parts(index, timestamp, transactions, merkle_root, previous_hash, hash, nonce, String::new(), Some(target)))
+    }
+
+    //worker k tries nonces k, k + workers, k + 2 * workers, ... and the first one to find a hash
+    //under the target stops the rest
+    #[allow(clippy::too_many_arguments)]
+    fn mine_block_parallel(hasher: &dyn Hasher, chain_id: u64, index: u64, timestamp: u128, transactions: Vec<Transaction>, previous_hash: String, target: Target, cancel: &AtomicBool, workers: usize, yield_every: u64, progress: Option<&MiningProgress>) -> Result<Self, MineError> {
+
+        let merkle_root = merkle_root(&transactions);
+        let workers = workers.max(1) as u64;
+        let found = AtomicBool::new(false);
+
+        let solution = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..workers)
+                .map(|k| {
+                    let (merkle_root, previous_hash, found) = (&merkle_root, &previous_hash, &found);
+                    scope.spawn(move || {
+                        let solution = Block::grind_nonces(hasher, chain_id, index, timestamp, merkle_root, previous_hash, target, k, workers, &[cancel, found], yield_every, progress)?;
+                        //two workers can land a hash in the same window, only the first to claim it wins
+                        match found.compare_exchange(false, true, Ordering::Relaxed, Ordering::Relaxed) {
+                            Ok(_) => Ok(solution),
+                            Err(_) => Err(MineError::Cancelled),
+                        }
+                    })
+                })
+                .collect();
+
+            handles.into_iter()
+                .map(|handle| handle.join().expect("mining worker panicked"))
+                .fold(None, |winner, result| winner.or(result.ok()))
+        });
+
+        match solution {
+            Some((nonce, hash)) => {
+                debug!(block_index = index, nonce = nonce, hash = hash, workers = workers; "block minted");
+                Ok(Block::from_parts(index, timestamp, transactions, merkle_root, previous_hash, hash, nonce, String::new(), Some(target)))
+            }
+            None if cancel.load(Ordering::Relaxed) => Err(MineError::Cancelled),
+            None => Err(MineError::NonceExhausted),
+        }
+    }
+
+    //tries nonces start, start + step, ... until one meets the target. gives up with
+    //Cancelled once any flag in `stop` is raised, or NonceExhausted when the nonce would overflow.
+    //every `yield_every` nonces, when not 0, the thread yields and the flags are looked at
+    #[allow(clippy::too_many_arguments)]
+    fn grind_nonces(hasher: &dyn Hasher, chain_id: u64, index: u64, timestamp: u128, merkle_root: &str, previous_hash: &str, target: Target, start: u64, step: u64, stop: &[&AtomicBool], yield_every: u64, progress: Option<&MiningProgress>) -> Result<(u64, String), MineError> {
+
+        let mut nonce = start;
+        let mut attempts: u64 = 0;
+        //lowest hash so far, only kept for `progress`. hex of equal length compares like the number
+        let mut best = String::new();
+
+        loop {
+            //lets other threads on this core run, a miner never blocks otherwise
+            let yielding = yield_every > 0 && attempts.is_multiple_of(yield_every);
+            if yielding {
+                std::thread::yield_now();
+            }
+            //only look at the flags every few thousand nonces so the check stays off the hot path
+            if (yielding || attempts.is_multiple_of(CANCEL_CHECK_INTERVAL)) && stop.iter().any(|flag| flag.load(Ordering::Relaxed)) {
+                return Err(MineError::Cancelled);
+            }
+            attempts += 1;
+
+            let hash = Block::compute_hash(hasher, chain_id, index, timestamp, merkle_root, previous_hash, nonce, "", Some(target));
+
+            if target.is_met_by(&hash) {
+                return Ok((nonce, hash));
+            }
+
+            if let Some(progress) = progress {
+                if best.is_empty() || hash < best {
+                    best.clone_from(&hash);
+                }
+                if progress.every > 0 && attempts.is_multiple_of(progress.every) {
+                    (progress.report)(attempts, hash_leading_zero_bits(&best));
+                }
+            }
+
+            //every nonce tried for this timestamp, caller has to retry with a fresh one
+            nonce = match nonce.checked_add(step) {
+                Some(n) => n,
+                None => return Err(MineError::NonceExhausted),
+            };
+        }
+    }
+
+    fn verify_merkle_root(&self) -> bool {
+        merkle_root(&self.body.transactions) == self.header.merkle_root
+    }
+
+    //sibling hashes from the transaction's leaf up to the root, each flagged true when the
+    //sibling sits on the left. built the same way as merkle_root, odd levels included
+    fn merkle_proof(&self, tx_index: usize) -> Option<Vec<(String, bool)>> {
+        if tx_index >= self.body.transactions.len() {
+            return None;
+        }
+
+        let mut level: Vec<String> = self.body.transactions.iter().map(Transaction::hash).collect();
+        let mut position = tx_index;
+        let mut proof = Vec::new();
+
+        while level.len() > 1 {
+            if level.len() % 2 == 1 {
+                level.push(level[level.len() - 1].clone());
+            }
+
+            let sibling = position ^ 1;
+            proof.push((level[sibling].clone(), sibling < position));
+
+            level = level.chunks(2).map(|pair| merkle_parent(&pair[0], &pair[1])).collect();
+            position /= 2;
+        }
+
+        Some(proof)
+    }
+
+    //coinbase transactions are unsigned, everything else must carry a valid signature
+    fn verify_signatures(&self) -> bool {
+        self.body.transactions.iter().all(|tx| tx.from == COINBASE || tx.verify())
+    }
+
+    //encoded size of the transaction list, the header fields are fixed size and don't count
+    fn payload_size(transactions: &[Transaction]) -> usize {
+        serde_json::to_vec(transactions).expect("transactions serialize").len()
+    }
+
+}
+
+const CANCEL_CHECK_INTERVAL: u64 = 4096;
+const MAX_ORPHANS: usize = 100;
+//recent blocks estimate_fee looks at, and the fewest fees they have to hold before it goes by them
+const FEE_ESTIMATE_BLOCKS: usize = 20;
+const FEE_ESTIMATE_MIN_SAMPLES: usize = 10;
+
+#[derive(Debug)]
+enum MineError {
+    NonceExhausted,
+    Cancelled,
+}
+
+//how a proof-of-work search is going, for whoever waits on it. every `every` nonces a thread
+//tries, `report` gets how many that thread has tried and the most leading zero bits any of its
+//hashes had so far
+struct MiningProgress<'a> {
+    every: u64,
+    report: &'a (dyn Fn(u64, u32) + Sync),
+}
+
+//leading zero bits of the number a hash reads as, 0 if it isn't one
+fn hash_leading_zero_bits(hash: &str) -> u32 {
+    let mut bytes = [0u8; 32];
+    match hash.get(..64).map(|prefix| hex::decode_to_slice(prefix, &mut bytes)) {
+        Some(Ok(())) => target::leading_zero_bits(&bytes),
+        _ => 0,
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum AddBlockError {
+    //buffered until its parent arrives
+    Orphan,
+    //valid, but on a branch with less work than the chain, see add_side_block
+    SideBranch,
+    IndexMismatch { expected: u64, got: u64 },
+    PreviousHashMismatch,
+    TooLarge { size: usize, max: usize },
+    TooManyTransactions { count: usize, max: usize },
+    TimestampNotAfterParent,
+    TimestampTooFarAhead,
+    HashMismatch,
+    MerkleRootMismatch,
+    InvalidSignature,
+    InvalidCoinbase,
+    InvalidNonce,
+    Overspend { tx_index: usize },
+    ImmatureReward { tx_index: usize },
+    DifficultyNotMet,
+    TargetMismatch,
+    UnexpectedValidator,
+    CheckpointMismatch,
+}
+
+impl AddBlockError {
+    //the block may belong to a fork we don't have, a peer's chain can settle it.
+    //anything else means the block itself is bad
+    fn needs_chain(&self) -> bool {
+        matches!(self, AddBlockError::Orphan | AddBlockError::IndexMismatch { .. } | AddBlockError::PreviousHashMismatch)
+    }
+}
+
+impl std::fmt::Display for AddBlockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AddBlockError::Orphan => write!(f, "parent not known yet"),
+            AddBlockError::SideBranch => write!(f, "on a branch with less work than the chain"),
+            AddBlockError::IndexMismatch { expected, got } => write!(f, "index mismatch, expected {} got {}", expected, got),
+            AddBlockError::PreviousHashMismatch => write!(f, "previous hash mismatch"),
+            AddBlockError::TooLarge { size, max } => write!(f, "payload is {} bytes, limit is {}", size, max),
+            AddBlockError::TooManyTransactions { count, max } => write!(f, "block holds {} transactions, limit is {}", count, max),
+            AddBlockError::TimestampNotAfterParent => write!(f, "timestamp not after parent"),
+            AddBlockError::TimestampTooFarAhead => write!(f, "timestamp too far ahead of local clock"),
+            AddBlockError::HashMismatch => write!(f, "hash mismatch"),
+            AddBlockError::MerkleRootMismatch => write!(f, "merkle root mismatch"),
+            AddBlockError::InvalidSignature => write!(f, "invalid transaction signature"),
+            AddBlockError::InvalidCoinbase => write!(f, "block must start with a single coinbase paying the reward"),
+            AddBlockError::InvalidNonce => write!(f, "transaction nonce out of sequence"),
+            AddBlockError::Overspend { tx_index } => write!(f, "transaction {} overspends sender balance", tx_index),
+            AddBlockError::ImmatureReward { tx_index } => write!(f, "transaction {} spends a block reward that hasn't matured", tx_index),
+            AddBlockError::DifficultyNotMet => write!(f, "hash not below the target"),
+            AddBlockError::TargetMismatch => write!(f, "recorded target differs from the chain's target at this height"),
+            AddBlockError::UnexpectedValidator => write!(f, "unexpected validator"),
+            AddBlockError::CheckpointMismatch => write!(f, "hash differs from the checkpoint at this height"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum MempoolError {
+    Coinbase,
+    InvalidSignature,
+    //already pending or mined
+    Known,
+    InvalidNonce,
+    Overspend,
+    ImmatureReward,
+}
+
+impl std::fmt::Display for MempoolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MempoolError::Coinbase => write!(f, "coinbase transactions can only be created by miners"),
+            MempoolError::InvalidSignature => write!(f, "invalid transaction signature"),
+            MempoolError::Known => write!(f, "transaction already known"),
+            MempoolError::InvalidNonce => write!(f, "transaction nonce out of sequence"),
+            MempoolError::Overspend => write!(f, "transaction overspends sender balance"),
+            MempoolError::ImmatureReward => write!(f, "transaction spends a block reward that hasn't matured"),
+        }
+    }
+}
+
+//why a whole chain failed validation
+#[derive(Debug, PartialEq)]
+enum ChainError {
+    Empty,
+    //block 0 isn't this network's genesis block
+    GenesisMismatch,
+    //block 1 names some other block than the genesis block as its parent
+    GenesisLinkMismatch,
+    Block { index: usize, error: AddBlockError },
+}
+
+impl std::fmt::Display for ChainError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChainError::Empty => write!(f, "chain is empty"),
+            ChainError::GenesisMismatch => write!(f, "block 0 is not this network's genesis block"),
+            ChainError::GenesisLinkMismatch => write!(f, "block 1 does not link to the genesis block"),
+            ChainError::Block { index, error } => write!(f, "block {}: {}", index, error),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum ReorgResult {
+    //blocks dropped from our old branch and blocks applied from the new one
+    Reorganized { removed: usize, added: usize },
+    //the incoming chain doesn't outweigh ours or fails validation
+    Rejected,
+    //not even the genesis block matches
+    NoCommonAncestor,
+    //switching would roll back more blocks than max_reorg_depth allows
+    TooDeep { depth: usize },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+enum ConsensusMode {
+    ProofOfWork { difficulty: usize },
+    //(address, stake) pairs
+    ProofOfStake { validators: Vec<(String, u64)> },
+}
+
+//picks a validator with probability proportional to stake, seeded by the parent hash so every node agrees
+fn select_validator<'a>(validators: &'a [(String, u64)], seed: &str) -> Option<&'a str> {
+    let total: u64 = validators.iter().map(|(_, stake)| *stake).sum();
+    if total == 0 {
+        return None;
+    }
+
+    let digest = Sha256::digest(seed.as_bytes());
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    let mut pick = u64::from_be_bytes(bytes) % total;
+
+    for (address, stake) in validators {
+        if pick < *stake {
+            return Some(address);
+        }
+        pick -= stake;
+    }
+
+    None
+}
+
+#[derive(Serialize)]
+struct Health {
+    healthy: bool,
+    height: u64,
+    //since the chain last moved
+    idle_ms: Option<u128>,
+    window_ms: u128,
+}
+
+#[derive(Serialize)]
+struct ChainStats {
+    height: u64,
+    //in blocks, coinbases included
+    transactions: usize,
+    //new coins the coinbases and genesis allocations created, the fees coinbases pass on to
+    //miners not counted
+    coinbase_issued: u64,
+    //between mined blocks, None until there are two of them
+    avg_block_interval_ms: Option<u128>,
+    //leading zero bits the next block's hash needs
+    difficulty_bits: u32,
+    mempool_size: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Blockchain {
+    //never empty: constructors start from genesis, deserializing rejects an empty list and
+    //reorganize only swaps in chains that validate
+    #[serde(deserialize_with = "non_empty_chain")]
+    chain: Box<dyn ChainStore>,
+    #[serde(default)]
+    genesis: GenesisConfig,
+    consensus: ConsensusMode,
+    #[serde(default)]
+    hash_algorithm: HashAlgorithm,
+    adjustment_interval: usize,
+    target_block_time_ms: u128,
+    //bounds on a retargeted target in leading zero bits, the easiest and the hardest it may get
+    #[serde(default = "default_min_difficulty")]
+    min_difficulty: u32,
+    #[serde(default = "default_max_difficulty")]
+    max_difficulty: u32,
+    max_future_drift_ms: u128,
+    #[serde(default)]
+    mempool: Vec<Transaction>,
+    //when each mempool transaction was queued, by hash. ones loaded from disk start counting at
+    //the first sweep
+    #[serde(skip)]
+    mempool_since: HashMap<String, u128>,
+    //keyed by previous_hash
+    #[serde(skip)]
+    orphan_pool: HashMap<String, Block>,
+    //block hash to position in `chain`, rebuilt whenever the chain is swapped out
+    #[serde(skip)]
+    hash_index: HashMap<String, usize>,
+    //transaction hash to the height of its block and its position there, rebuilt with hash_index
+    #[serde(skip)]
+    tx_index: HashMap<String, (u64, usize)>,
+    //total_work of `chain`, kept up to date as blocks are added
+    #[serde(skip)]
+    work: u128,
+    //height to the hash the block there must have, set by the operator. a chain that disagrees
+    //is never adopted however much work it carries
+    #[serde(skip)]
+    checkpoints: HashMap<u64, String>,
+    //most blocks a reorganization may roll back, set by the operator. a chain forking deeper is
+    //refused however much work it carries. None allows any depth
+    #[serde(skip)]
+    max_reorg_depth: Option<usize>,
+    //what estimate_fee suggests while recent blocks hold too few fees to go by, set by the operator
+    #[serde(skip)]
+    default_fee: u64,
+    //every block that joins the chain, for websocket clients. sending with nobody subscribed is fine
+    #[serde(skip, default = "block_feed")]
+    new_blocks: broadcast::Sender<Block>,
+    //read for new block timestamps and to judge how far ahead a block may be
+    #[serde(skip, default = "clock::system")]
+    clock: Arc<dyn Clock>,
+    //clock reading when the chain last grew or switched branch, see health
+    #[serde(skip)]
+    last_progress_ms: Option<u128>,
+    //`chain` plus every valid block on a competing branch. rebuilt from `chain` on load, so
+    //losing branches aren't saved
+    #[serde(skip)]
+    tree: BlockTree,
+    //a block holding more transactions than this, coinbase included, or a payload larger than
+    //max_block_bytes is invalid. miners stop filling a block at either limit
+    #[serde(alias = "max_block_txs")]
+    max_txs_per_block: usize,
+    max_block_bytes: usize,
+    //blocks a coinbase has to be buried under before its reward can be spent, 0 spends it in the
+    //block that mines it
+    #[serde(default)]
+    coinbase_maturity: u64,
+    block_reward: u64,
+    halving_interval: u64,
+}
+
+impl Blockchain {
+    
+    fn new(difficulty: usize, genesis: GenesisConfig) -> Self {
+        Blockchain::with_consensus(ConsensusMode::ProofOfWork { difficulty }, genesis)
+    }
+
+    fn with_consensus(consensus: ConsensusMode, genesis: GenesisConfig) -> Self {
+        Blockchain::with_hasher(consensus, HashAlgorithm::default(), genesis)
+    }
+
+    fn with_hasher(consensus: ConsensusMode, hash_algorithm: HashAlgorithm, genesis: GenesisConfig) -> Self {
+        let mut blockchain = Blockchain {
+            chain: Box::new(MemoryStore::new(vec![Block::genesis_block(hash_algorithm.hasher(), &genesis)])),
+            genesis,
+            consensus,
+            hash_algorithm,
+            adjustment_interval: 10,
+            target_block_time_ms: 1000,
+            min_difficulty: default_min_difficulty(),
+            max_difficulty: default_max_difficulty(),
+            max_future_drift_ms: 60_000,
+            mempool: Vec::new(),
+            mempool_since: HashMap::new(),
+            orphan_pool: HashMap::new(),
+            hash_index: HashMap::new(),
+            tx_index: HashMap::new(),
+            work: 0,
+            checkpoints: HashMap::new(),
+            max_reorg_depth: None,
+            default_fee: 1,
+            new_blocks: block_feed(),
+            clock: clock::system(),
+            last_progress_ms: None,
+            tree: BlockTree::default(),
+            max_txs_per_block: 100,
+            max_block_bytes: 1024 * 1024,
+            coinbase_maturity: 0,
+            block_reward: 50,
+            halving_interval: 100,
+        };
+        blockchain.reindex();
+        blockchain
+    }
+
+    //starting target, proof-of-stake chains have no work requirement
+    fn base_target(&self) -> Target {
+        match self.consensus {
+            ConsensusMode::ProofOfWork { difficulty } => Target::from_leading_zeros(difficulty),
+            ConsensusMode::ProofOfStake { .. } => Target::MAX,
+        }
+    }
+
+    //who has to forge the block on top of `previous_hash`, None under proof-of-work
+    fn expected_validator(&self, previous_hash: &str) -> Option<String> {
+        match &self.consensus {
+            ConsensusMode::ProofOfWork { .. } => None,
+            ConsensusMode::ProofOfStake { validators } => {
+                select_validator(validators, previous_hash).map(String::from)
+            }
+        }
+    }
+
+    fn meets_consensus(&self, header: &BlockHeader, target: Target) -> bool {
+        match &self.consensus {
+            ConsensusMode::ProofOfWork { .. } => {
+                header.validator.is_empty() && target.is_met_by(&header.hash)
+            }
+            ConsensusMode::ProofOfStake { validators } => {
+                select_validator(validators, &header.previous_hash) == Some(header.validator.as_str())
+            }
+        }
+    }
+
+    fn hasher(&self) -> &'static dyn Hasher {
+        self.hash_algorithm.hasher()
+    }
+
+    fn last_block(&self) -> &Block {
+        self.chain.tip()
+    }
+
+    fn block_by_hash(&self, hash: &str) -> Option<&Block> {
+        self.hash_index.get(hash).map(|&i| &self.chain.blocks()[i])
+    }
+
+    //the block holding the transaction and where in it, as tx_index has it. checked against the
+    //block itself, an index out of step with the chain fails debug builds instead of answering
+    //for some other transaction
+    fn locate_transaction(&self, tx_hash: &str) -> Option<(&Block, usize)> {
+        let &(height, position) = self.tx_index.get(tx_hash)?;
+        let block = self.block_by_index(height)?;
+        let found = block.body.transactions.get(position).is_some_and(|tx| tx.hash() == tx_hash);
+        debug_assert!(found, "tx_index is out of step with the chain at height {}", height);
+        found.then_some((block, position))
+    }
+
+    //the height of the block holding the transaction and the transaction itself
+    fn find_transaction(&self, tx_hash: &str) -> Option<(u64, &Transaction)> {
+        let (block, position) = self.locate_transaction(tx_hash)?;
+        Some((block.header.index, &block.body.transactions[position]))
+    }
+
+    //the header of the block holding the transaction and its merkle branch, None if the
+    //transaction isn't in the chain
+    fn tx_proof(&self, tx_hash: &str) -> Option<(BlockHeader, Vec<(String, bool)>)> {
+        let (block, position) = self.locate_transaction(tx_hash)?;
+        Some((block.header.clone(), block.merkle_proof(position)?))
+    }
+
+    fn index_transactions(tx_index: &mut HashMap<String, (u64, usize)>, block: &Block) {
+        for (position, tx) in block.body.transactions.iter().enumerate() {
+            tx_index.insert(tx.hash(), (block.header.index, position));
+        }
+    }
+
+    fn block_by_index(&self, index: u64) -> Option<&Block> {
+        self.chain.get(index)
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Block> {
+        self.chain.blocks().iter()
+    }
+
+    //every block from height `index` up to the tip, empty once `index` is past the tip
+    fn blocks_since(&self, index: u64) -> &[Block] {
+        usize::try_from(index).ok().and_then(|i| self.chain.blocks().get(i..)).unwrap_or(&[])
+    }
+
+    fn headers_since(&self, index: u64) -> Vec<BlockHeader> {
+        self.blocks_since(index).iter()
+            .map(|block| block.header.clone())
+            .collect()
+    }
+
+    //hashes of the tip, the 9 blocks below it, then blocks twice as far back each time, down
+    //to genesis. a peer finds the fork point in it without being sent the whole chain
+    fn locator(&self) -> Vec<String> {
+        let mut hashes = Vec::new();
+        let mut height = self.chain.len() - 1;
+        let mut step = 1;
+        loop {
+            hashes.push(self.chain.blocks()[height].header.hash.clone());
+            if height == 0 {
+                return hashes;
+            }
+            if hashes.len() >= 10 {
+                step *= 2;
+            }
+            height = height.saturating_sub(step);
+        }
+    }
+
+    //height of the highest block of ours that appears in a peer's locator
+    fn find_fork(&self, locator: &[String]) -> Option<u64> {
+        locator.iter()
+            .find_map(|hash| self.block_by_hash(hash))
+            .map(|block| block.header.index)
+    }
+
+    //how many leading blocks a peer's chain shares with ours, the fork point is right after them
+    fn common_prefix_len(&self, headers: &[BlockHeader]) -> usize {
+        self.iter()
+            .zip(headers)
+            .take_while(|(ours, theirs)| ours.header.hash == theirs.hash)
+            .count()
+    }
+
+    //graphviz view of the chain and buffered orphans, each block pointing at its parent.
+    //orphans whose parent we don't hold point at a dashed placeholder
+    fn to_dot(&self) -> String {
+        let mut orphans: Vec<&Block> = self.orphan_pool.values().collect();
+        orphans.sort_by_key(|block| block.header.index);
+
+        let known: HashSet<&str> = self.iter().chain(orphans.iter().copied()).map(|block| block.header.hash.as_str()).collect();
+        let mut out = String::from("digraph chain {\n    rankdir=RL;\n    node [shape=box];\n");
+
+        for block in self.iter() {
+            out.push_str(&format!("    \"{}\" [label=\"#{}\\n{}\"];\n", block.header.hash, block.header.index, block.header.hash.get(..8).unwrap_or(&block.header.hash)));
+        }
+        for block in &orphans {
+            out.push_str(&format!("    \"{}\" [label=\"#{}\\n{}\", style=dashed];\n", block.header.hash, block.header.index, block.header.hash.get(..8).unwrap_or(&block.header.hash)));
+        }
+
+        //genesis has no parent block, its previous_hash only commits to the genesis data
+        for block in self.iter().skip(1).chain(orphans.iter().copied()) {
+            if !known.contains(block.header.previous_hash.as_str()) {
+                out.push_str(&format!("    \"{}\" [label=\"?\", style=dashed];\n", block.header.previous_hash));
+            }
+            out.push_str(&format!("    \"{}\" -> \"{}\";\n", block.header.hash, block.header.previous_hash));
+        }
+
+        out.push_str("}\n");
+        out
+    }
+
+    //recomputes everything derived from `chain`, after it has been swapped out wholesale
+    fn reindex(&mut self) {
+        self.hash_index = self.chain.blocks().iter()
+            .enumerate()
+            .map(|(i, block)| (block.header.hash.clone(), i))
+            .collect();
+        self.tx_index.clear();
+        for block in self.chain.blocks() {
+            Blockchain::index_transactions(&mut self.tx_index, block);
+        }
+        self.work = Blockchain::total_work(self.chain.blocks());
+        for block in self.chain.blocks() {
+            self.tree.insert(block.clone());
+        }
+    }
+
+    //a block with n leading zero bits took about 2^n hashes to find
+    fn block_work(hash: &str) -> u128 {
+        let mut zero_bits = 0;
+        for c in hash.chars() {
+            match c.to_digit(16) {
+                Some(0) => zero_bits += 4,
+                Some(nibble) => {
+                    zero_bits += nibble.leading_zeros() - 28;
+                    break;
+                }
+                None => break,
+            }
+        }
+        1u128 << zero_bits.min(127)
+    }
+
+    fn total_work(chain: &[Block]) -> u128 {
+        chain.iter().fold(0u128, |work, block| work.saturating_add(Blockchain::block_work(&block.header.hash)))
+    }
+
+    //ranks two chains, each given as (work, length, tip hash): the most work wins, then the
+    //longest, then the lexicographically smaller tip hash. only two identical chains compare
+    //equal, so every node presented the same pair makes the same choice. Greater means `a` wins
+    fn compare_chains(a: (u128, usize, &str), b: (u128, usize, &str)) -> std::cmp::Ordering {
+        (a.0, a.1).cmp(&(b.0, b.1)).then_with(|| b.2.cmp(a.2))
+    }
+
+    //whether a chain with this much work, length and tip should replace ours, see compare_chains.
+    //proof-of-stake blocks carry no work so length decides
+    fn outweighs(&self, work: u128, len: usize, tip_hash: &str) -> bool {
+        let (ours, theirs) = match self.consensus {
+            ConsensusMode::ProofOfWork { .. } => (self.work, work),
+            ConsensusMode::ProofOfStake { .. } => (0, 0),
+        };
+
+        Blockchain::compare_chains((theirs, len, tip_hash), (ours, self.chain.len(), &self.last_block().header.hash)).is_gt()
+    }
+
+    fn conflicts_with_checkpoint(&self, header: &BlockHeader) -> bool {
+        self.checkpoints.get(&header.index).is_some_and(|hash| *hash != header.hash)
+    }
+
+    //blocks that arrive ahead of their parent wait here until the gap is filled
+    fn add_block(&mut self, block: Block) -> Result<(), AddBlockError> {
+        if block.header.previous_hash != self.last_block().header.hash && self.tree.contains(&block.header.previous_hash) {
+            return self.add_side_block(block);
+        }
+
+        if block.header.index > self.last_block().header.index + 1 {
+            self.add_orphan(block);
+            return Err(AddBlockError::Orphan);
+        }
+
+        //a rival for the next height whose parent hasn't arrived, it joins its branch once it does
+        if block.header.index == self.last_block().header.index + 1 && block.header.previous_hash != self.last_block().header.hash {
+            self.add_orphan(block);
+            return Err(AddBlockError::PreviousHashMismatch);
+        }
+
+        self.append_block(block)?;
+        self.connect_orphans();
+        Ok(())
+    }
+
+    //a block building on a branch other than our tip, such as a rival to our block at the same
+    //height. it's validated along its own branch and kept in the tree, and the chain switches over
+    //once that branch outweighs ours. Err(SideBranch) when the chain stays as it is
+    fn add_side_block(&mut self, block: Block) -> Result<(), AddBlockError> {
+        if block.header.timestamp > self.clock.now_millis() + self.max_future_drift_ms {
+            return Err(AddBlockError::TimestampTooFarAhead);
+        }
+
+        let mut branch = self.tree.path_to(&block.header.previous_hash);
+        branch.push(block.clone());
+        if let Err(e) = self.validate_chain(&branch, &self.genesis) {
+            //the branch starts at our own genesis block, only one of its blocks can fail
+            return Err(match e {
+                ChainError::Block { error, .. } => error,
+                ChainError::Empty | ChainError::GenesisMismatch | ChainError::GenesisLinkMismatch => AddBlockError::PreviousHashMismatch,
+            });
+        }
+
+        let tip_before = self.last_block().header.hash.clone();
+        let hash = block.header.hash.clone();
+        self.tree.insert(block);
+        if self.outweighs(self.tree.work(&hash), branch.len(), &hash) {
+            match self.reorganize(branch) {
+                ReorgResult::Reorganized { removed, added } => {
+                    info!(removed = removed, added = added; "switched to a heavier side branch");
+                }
+                ReorgResult::TooDeep { depth } => {
+                    warn!(depth = depth, hash = hash; "not switching to a heavier side branch, it forks deeper than the maximum reorg depth");
+                }
+                ReorgResult::Rejected | ReorgResult::NoCommonAncestor => {}
+            }
+        } else {
+            debug!(block_index = branch.len() - 1, hash = hash; "block added to a side branch");
+            //a block that got here first may extend the branch, and tip it over
+            if let Some(child) = self.orphan_pool.remove(&hash) {
+                let child_index = child.header.index;
+                if let Err(e) = self.add_block(child)
+                    && e != AddBlockError::SideBranch {
+                    debug!(block_index = child_index; "dropping orphan block: {}", e);
+                }
+            }
+        }
+
+        if self.last_block().header.hash == tip_before {
+            Err(AddBlockError::SideBranch)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn add_orphan(&mut self, block: Block) {
+        if self.orphan_pool.len() >= MAX_ORPHANS {
+            warn!(block_index = block.header.index, hash = block.header.hash; "orphan pool full, dropping block");
+            return;
+        }
+
+        debug!(block_index = block.header.index, hash = block.header.hash; "buffered orphan block");
+        self.orphan_pool.insert(block.header.previous_hash.clone(), block);
+    }
+
+    //applies every buffered orphan that now links onto the tip, in order
+    fn connect_orphans(&mut self) {
+        let tip_index = self.last_block().header.index;
+        self.orphan_pool.retain(|_, orphan| orphan.header.index > tip_index);
+
+        loop {
+            let tip_hash = self.last_block().header.hash.clone();
+            let Some(orphan) = self.orphan_pool.remove(&tip_hash) else { break; };
+            let orphan_index = orphan.header.index;
+            if let Err(e) = self.append_block(orphan) {
+                debug!(block_index = orphan_index; "dropping orphan block: {}", e);
+                break;
+            }
+        }
+    }
+
+    fn append_block(&mut self, block: Block) -> Result<(), AddBlockError> {
+        let last = self.last_block();
+
+        if block.header.index != last.header.index + 1 {
+            return Err(AddBlockError::IndexMismatch { expected: last.header.index + 1, got: block.header.index });
+        }
+
+        self.check_header(&last.header, &block.header, self.adjusted_target(), self.genesis.chain_id)?;
+
+        //only blocks arriving live are held to our clock, history is judged on its own timestamps
+        if block.header.timestamp > self.clock.now_millis() + self.max_future_drift_ms {
+            return Err(AddBlockError::TimestampTooFarAhead);
+        }
+
+        let mut nonces = self.account_nonces();
+        let mut balances = self.balances();
+        self.check_body(self.chain.blocks(), &block, &mut nonces, &mut balances)?;
+
+        //also drops pending transactions whose nonce this block already used up
+        self.mempool.retain(|tx| {
+            !block.body.transactions.contains(tx) && tx.nonce >= nonces.get(&tx.from).copied().unwrap_or(0)
+        });
+        debug!(block_index = block.header.index, hash = block.header.hash; "block added");
+        self.hash_index.insert(block.header.hash.clone(), self.chain.len());
+        Blockchain::index_transactions(&mut self.tx_index, &block);
+        self.work = self.work.saturating_add(Blockchain::block_work(&block.header.hash));
+        if self.new_blocks.receiver_count() > 0 {
+            let _ = self.new_blocks.send(block.clone());
+        }
+        self.tree.insert(block.clone());
+        self.chain.append(block);
+        self.record_progress();
+        Ok(())
+    }
+
+    //target for the block that would follow `chain`, given the one its tip was mined at.
+    //retargets every `adjustment_interval` blocks by a bit for each factor of two the window was
+    //off by, at most MAX_RETARGET_BITS, and keeps the result within min_difficulty and
+    //max_difficulty; the genesis timestamp is never part of a window.
+    fn next_target<B: AsRef<BlockHeader>>(&self, chain: &[B], current: Target) -> Target {
+        let interval = self.adjustment_interval;
+        let height = chain.len();
+
+        if interval == 0 || height <= 2 * interval || !(height - 1).is_multiple_of(interval) {
+            return current;
+        }
+
+        //difficulty 0 and proof-of-stake chains stay free of work however fast blocks come
+        if self.base_target() == Target::MAX {
+            return current;
+        }
+
+        let first = chain[height - 1 - interval].as_ref();
+        let last = chain[height - 1].as_ref();
+        let elapsed = last.timestamp.saturating_sub(first.timestamp);
+        let expected = self.target_block_time_ms * interval as u128;
+
+        let mut target = current;
+        for _ in (1..=MAX_RETARGET_BITS).take_while(|&bits| elapsed < expected >> bits) {
+            target = target.harder();
+        }
+        for _ in (1..=MAX_RETARGET_BITS).take_while(|&bits| elapsed > expected << bits) {
+            target = target.easier();
+        }
+        //not clamp, a saved chain may carry bounds the wrong way round
+        target.max(Target::from_leading_zero_bits(self.max_difficulty)).min(Target::from_leading_zero_bits(self.min_difficulty))
+    }
+
+    fn adjusted_target(&self) -> Target {
+        let mut target = self.base_target();
+        for height in 1..=self.chain.len() {
+            target = self.next_target(&self.chain.blocks()[..height], target);
+        }
+        target
+    }
+
+    fn reward_at_height(&self, height: u64) -> u64 {
+        if self.halving_interval == 0 {
+            return self.block_reward;
+        }
+
+        let halvings = height / self.halving_interval;
+        if halvings >= 64 { 0 } else { self.block_reward >> halvings }
+    }
+
+    //pays the block reward plus the fees of the block's other transactions
+    fn coinbase(&self, height: u64, to: &Address, fees: u64) -> Transaction {
+        Transaction {
+            from: String::from(COINBASE),
+            outputs: vec![(to.clone(), self.reward_at_height(height).saturating_add(fees))],
+            fee: 0,
+            //the height keeps every coinbase transaction hash unique
+            nonce: height,
+            signature: Vec::new(),
+            public_key: Vec::new(),
+        }
+    }
+
+    fn total_fees(transactions: &[Transaction]) -> Option<u64> {
+        transactions.iter()
+            .filter(|tx| tx.from != COINBASE)
+            .try_fold(0u64, |total, tx| total.checked_add(tx.fee))
+    }
+
+    //exactly one coinbase, first in the block, paying the scheduled reward and the block's fees
+    fn valid_coinbase(&self, block: &Block) -> bool {
+        let Some(first) = block.body.transactions.first() else { return false; };
+        let Some(fees) = Blockchain::total_fees(&block.body.transactions) else { return false; };
+
+        first.from == COINBASE
+            && first.fee == 0
+            && first.amount() == self.reward_at_height(block.header.index).checked_add(fees)
+            && block.body.transactions.iter().filter(|tx| tx.from == COINBASE).count() == 1
+    }
+
+    //credits every output and debits senders the outputs' sum plus fee in order, false if any
+    //non-coinbase sender would go negative. fees reach the miner through the coinbase amount
+    fn apply_transactions(balances: &mut HashMap<String, i64>, transactions: &[Transaction]) -> bool {
+        for tx in transactions {
+            if tx.from != COINBASE {
+                let Some(cost) = tx.cost() else { return false; };
+                let sender = balances.entry(tx.from.clone()).or_insert(0);
+                if *sender < cost {
+                    return false;
+                }
+                *sender -= cost;
+            }
+
+            for (to, amount) in &tx.outputs {
+                let Ok(amount) = i64::try_from(*amount) else { return false; };
+                let recipient = balances.entry(to.payload_hex()).or_insert(0);
+                match recipient.checked_add(amount) {
+                    Some(total) => *recipient = total,
+                    None => return false,
+                }
+            }
+        }
+        true
+    }
+
+    fn balances(&self) -> HashMap<String, i64> {
+        Blockchain::replay(self.chain.blocks())
+    }
+
+    fn replay(blocks: &[Block]) -> HashMap<String, i64> {
+        let mut balances = HashMap::new();
+        for block in blocks {
+            Blockchain::apply_transactions(&mut balances, &block.body.transactions);
+        }
+        balances
+    }
+
+    fn record_progress(&mut self) {
+        self.last_progress_ms = Some(self.clock.now_millis());
+    }
+
+    //healthy when the chain has moved within the last `window_ms`. a chain that never recorded
+    //any progress is not
+    fn health(&self, window_ms: u128) -> Health {
+        let idle_ms = self.last_progress_ms.map(|last| self.clock.now_millis().saturating_sub(last));
+        Health {
+            healthy: idle_ms.is_some_and(|idle| idle <= window_ms),
+            height: self.last_block().header.index,
+            idle_ms,
+            window_ms,
+        }
+    }
+
+    //the genesis timestamp comes from the genesis config rather than a miner, so it is left out
+    //of the average interval
+    fn stats(&self) -> ChainStats {
+        let mined = &self.chain.blocks()[1..];
+        let avg_block_interval_ms = match (mined.first(), mined.last()) {
+            (Some(first), Some(last)) if mined.len() >= 2 => {
+                Some(last.header.timestamp.saturating_sub(first.header.timestamp) / (mined.len() as u128 - 1))
+            }
+            _ => None,
+        };
+
+        ChainStats {
+            height: self.last_block().header.index,
+            transactions: self.iter().map(|block| block.body.transactions.len()).sum(),
+            coinbase_issued: self.iter()
+                .filter_map(|block| {
+                    let credited = block.body.transactions.iter()
+                        .filter(|tx| tx.from == COINBASE)
+                        .fold(0u64, |total, tx| total.saturating_add(tx.amount().unwrap_or(u64::MAX)));
+                    Some(credited.saturating_sub(Blockchain::total_fees(&block.body.transactions)?))
+                })
+                .fold(0u64, u64::saturating_add),
+            avg_block_interval_ms,
+            difficulty_bits: self.adjusted_target().leading_zero_bits(),
+            mempool_size: self.mempool.len(),
+        }
+    }
+
+    //every nonzero balance right after block `height`, None past the tip
+    fn balances_at_height(&self, height: u64) -> Option<HashMap<String, i64>> {
+        let end = usize::try_from(height).ok()?.checked_add(1)?;
+        let mut balances = Blockchain::replay(self.chain.blocks().get(..end)?);
+        balances.retain(|_, balance| *balance != 0);
+        Some(balances)
+    }
+
+    fn balance_of(&self, address: &Address) -> i64 {
+        self.balances().get(&address.payload_hex()).copied().unwrap_or(0)
+    }
+
+    //like balance_of, counting only blocks with at least `confirmations` blocks built on top of
+    //them, so 0 counts the tip too. a transfer in the tip has no confirmations yet
+    fn confirmed_balance_of(&self, address: &Address, confirmations: u64) -> i64 {
+        let buried = self.chain.len().saturating_sub(usize::try_from(confirmations).unwrap_or(usize::MAX));
+        Blockchain::replay(&self.chain.blocks()[..buried]).get(&address.payload_hex()).copied().unwrap_or(0)
+    }
+
+    //a fee likely to get a transaction into one of the next `target_blocks` blocks: a percentile of
+    //what transactions in the last FEE_ESTIMATE_BLOCKS blocks paid, the 90th for the next block and
+    //10 lower for each block more, down to the 10th. default_fee until there are enough of them
+    fn estimate_fee(&self, target_blocks: u64) -> u64 {
+        let mut fees: Vec<u64> = self.chain.blocks()[1..].iter()
+            .rev()
+            .take(FEE_ESTIMATE_BLOCKS)
+            .flat_map(|block| &block.body.transactions)
+            .filter(|tx| tx.from != COINBASE)
+            .map(|tx| tx.fee)
+            .collect();
+        if fees.len() < FEE_ESTIMATE_MIN_SAMPLES {
+            return self.default_fee;
+        }
+
+        fees.sort_unstable();
+        let percentile = 90u64.saturating_sub(target_blocks.saturating_sub(1).saturating_mul(10)).max(10);
+        fees[(fees.len() - 1) * percentile as usize / 100]
+    }
+
+    //every sender's transactions carry nonces 0, 1, 2, ... so a signed transfer can't be replayed.
+    //advances `nonces` past `transactions`, false on the first one out of sequence
+    fn apply_nonces(nonces: &mut HashMap<String, u64>, transactions: &[Transaction]) -> bool {
+        for tx in transactions.iter().filter(|tx| tx.from != COINBASE) {
+            let expected = nonces.entry(tx.from.clone()).or_insert(0);
+            if tx.nonce != *expected {
+                return false;
+            }
+            *expected += 1;
+        }
+        true
+    }
+
+    fn account_nonces(&self) -> HashMap<String, u64> {
+        let mut nonces = HashMap::new();
+        for block in self.chain.blocks() {
+            Blockchain::apply_nonces(&mut nonces, &block.body.transactions);
+        }
+        nonces
+    }
+
+    //nonce the sender's next transaction has to carry to be mined
+    fn account_nonce(&self, address: &str) -> u64 {
+        self.account_nonces().get(address).copied().unwrap_or(0)
+    }
+
+    //nonce for a new transaction from `address`, counting the ones already waiting in the mempool
+    fn next_nonce(&self, address: &str) -> u64 {
+        self.account_nonce(address) + self.mempool.iter().filter(|tx| tx.from == address).count() as u64
+    }
+
+    //coinbase credits not yet spendable in a block at `height`, per address. `blocks` run up to
+    //that height, only the last coinbase_maturity of them are looked at
+    fn immature_rewards<'a>(&self, blocks: impl DoubleEndedIterator<Item = &'a Block>, height: u64) -> HashMap<String, i64> {
+        let mut immature = HashMap::new();
+        //genesis allocations aren't rewards, they can be spent right away
+        for block in blocks.rev().take_while(|block| block.header.index + self.coinbase_maturity > height).filter(|block| block.header.index > 0) {
+            for (to, amount) in block.body.transactions.iter().filter(|tx| tx.from == COINBASE).flat_map(|tx| &tx.outputs) {
+                let credit: &mut i64 = immature.entry(to.payload_hex()).or_default();
+                *credit = credit.saturating_add(i64::try_from(*amount).unwrap_or(i64::MAX));
+            }
+        }
+        immature
+    }
+
+    //the next block's transactions after its coinbase, best fee first. each pick still has to be
+    //its sender's next nonce and affordable, so a cheap transaction can hold back a pricier one
+    //queued behind it by the same sender
+    fn select_transactions(&self, coinbase_to: &Address) -> Vec<Transaction> {
+        let height = self.last_block().header.index + 1;
+        let immature = self.immature_rewards(self.chain.blocks().iter(), height);
+        let mut pending: Vec<&Transaction> = self.mempool.iter().collect();
+        //stable, so equal fees keep arrival order
+        pending.sort_by_key(|tx| std::cmp::Reverse(tx.fee));
+
+        let mut nonces = self.account_nonces();
+        let mut balances = self.balances();
+        let mut fees = 0u64;
+        let mut txs = vec![self.coinbase(height, coinbase_to, fees)];
+
+        while txs.len() < self.max_txs_per_block {
+            let ready = pending.iter().position(|tx| {
+                nonces.get(&tx.from).copied().unwrap_or(0) == tx.nonce
+                    && tx.cost().is_some_and(|cost| {
+                        balances.get(&tx.from).copied().unwrap_or(0).saturating_sub(cost) >= immature.get(&tx.from).copied().unwrap_or(0)
+                    })
+            });
+            let Some(pos) = ready else { break; };
+            let tx = pending.remove(pos);
+
+            txs.push(tx.clone());
+            txs[0] = self.coinbase(height, coinbase_to, fees.saturating_add(tx.fee));
+            if Block::payload_size(&txs) > self.max_block_bytes {
+                txs.pop();
+                txs[0] = self.coinbase(height, coinbase_to, fees);
+                break;
+            }
+
+            fees = fees.saturating_add(tx.fee);
+            nonces.insert(tx.from.clone(), tx.nonce + 1);
+            Blockchain::apply_transactions(&mut balances, std::slice::from_ref(tx));
+        }
+
+        txs
+    }
+
+    //whether `tx` could join the mempool: it's not already known, its nonce follows the sender's
+    //last one and the sender can cover it on top of what the mempool already spends
+    fn check_pending(&self, tx: &Transaction) -> Result<(), MempoolError> {
+        if tx.from == COINBASE {
+            return Err(MempoolError::Coinbase);
+        }
+
+        if !tx.verify() {
+            return Err(MempoolError::InvalidSignature);
+        }
+
+        if self.mempool.contains(tx) || self.find_transaction(&tx.hash()).is_some() {
+            return Err(MempoolError::Known);
+        }
+
+        let mut nonces = self.account_nonces();
+        if !Blockchain::apply_nonces(&mut nonces, &self.mempool)
+            || !Blockchain::apply_nonces(&mut nonces, std::slice::from_ref(tx)) {
+            return Err(MempoolError::InvalidNonce);
+        }
+
+        let mut balances = self.balances();
+        if !Blockchain::apply_transactions(&mut balances, &self.mempool)
+            || !Blockchain::apply_transactions(&mut balances, std::slice::from_ref(tx)) {
+            return Err(MempoolError::Overspend);
+        }
+
+        let immature = self.immature_rewards(self.chain.blocks().iter(), self.last_block().header.index + 1);
+        if balances.get(&tx.from).copied().unwrap_or(0) < immature.get(&tx.from).copied().unwrap_or(0) {
+            return Err(MempoolError::ImmatureReward);
+        }
+
+        Ok(())
+    }
+
+    //queues a pending transaction, false if check_pending turns it down
+    fn add_to_mempool(&mut self, tx: Transaction) -> bool {
+        match self.check_pending(&tx) {
+            Ok(()) => {}
+            Err(MempoolError::Known) => return false,
+            Err(e) => {
+                warn!(from = tx.from, nonce = tx.nonce, tx_hash = tx.hash(); "transaction not queued: {}", e);
+                return false;
+            }
+        }
+
+        self.mempool_since.insert(tx.hash(), self.clock.now_millis());
+        self.mempool.push(tx);
+        true
+    }
+
+    //drops and returns pending transactions queued more than `max_age_ms` ago
+    fn evict_expired(&mut self, max_age_ms: u128) -> Vec<Transaction> {
+        let now = self.clock.now_millis();
+        let hashes: Vec<String> = self.mempool.iter().map(Transaction::hash).collect();
+        //entries of transactions that have since been mined or dropped
+        self.mempool_since.retain(|hash, _| hashes.contains(hash));
+
+        let mut expired = Vec::new();
+        let mut kept = Vec::new();
+        for (tx, hash) in std::mem::take(&mut self.mempool).into_iter().zip(hashes) {
+            let since = *self.mempool_since.entry(hash.clone()).or_insert(now);
+            if now.saturating_sub(since) > max_age_ms {
+                self.mempool_since.remove(&hash);
+                expired.push(tx);
+            } else {
+                kept.push(tx);
+            }
+        }
+        self.mempool = kept;
+        expired
+    }
+
+    //linkage, timestamp, hash, checkpoint and consensus checks for `header` on top of `parent`,
+    //everything that doesn't need the transactions
+    fn check_header(&self, parent: &BlockHeader, header: &BlockHeader, target: Target, chain_id: u64) -> Result<(), AddBlockError> {
+        if header.previous_hash != parent.hash {
+            return Err(AddBlockError::PreviousHashMismatch);
+        }
+
+        if self.conflicts_with_checkpoint(header) {
+            return Err(AddBlockError::CheckpointMismatch);
+        }
+
+        if header.timestamp <= parent.timestamp {
+            return Err(AddBlockError::TimestampNotAfterParent);
+        }
+
+        if header.recompute_hash(self.hasher(), chain_id) != header.hash {
+            return Err(AddBlockError::HashMismatch);
+        }
+
+        //a recorded target only describes the block, it can't lower the bar the chain sets
+        if header.target.is_some_and(|recorded| recorded != target) {
+            return Err(AddBlockError::TargetMismatch);
+        }
+
+        if !self.meets_consensus(header, target) {
+            return Err(match self.consensus {
+                ConsensusMode::ProofOfWork { .. } => AddBlockError::DifficultyNotMet,
+                ConsensusMode::ProofOfStake { .. } => AddBlockError::UnexpectedValidator,
+            });
+        }
+
+        Ok(())
+    }
+
+    //checks the transactions against the header and the ledger, advancing `nonces` and `balances`
+    //past them. `parents` are the blocks below it
+    fn check_body(&self, parents: &[Block], block: &Block, nonces: &mut HashMap<String, u64>, balances: &mut HashMap<String, i64>) -> Result<(), AddBlockError> {
+        let count = block.body.transactions.len();
+        if count > self.max_txs_per_block {
+            return Err(AddBlockError::TooManyTransactions { count, max: self.max_txs_per_block });
+        }
+
+        let size = Block::payload_size(&block.body.transactions);
+        if size > self.max_block_bytes {
+            return Err(AddBlockError::TooLarge { size, max: self.max_block_bytes });
+        }
+
+        if !block.verify_merkle_root() {
+            return Err(AddBlockError::MerkleRootMismatch);
+        }
+
+        if !block.verify_signatures() {
+            return Err(AddBlockError::InvalidSignature);
+        }
+
+        if !self.valid_coinbase(block) {
+            return Err(AddBlockError::InvalidCoinbase);
+        }
+
+        if !Blockchain::apply_nonces(nonces, &block.body.transactions) {
+            return Err(AddBlockError::InvalidNonce);
+        }
+
+        //one at a time against the running balances, so two transfers in this block can't both
+        //spend the same funds. the coinbase is known to be first, without a maturity its reward is
+        //spendable after it
+        let immature = self.immature_rewards(parents.iter().chain([block]), block.header.index);
+        for (tx_index, tx) in block.body.transactions.iter().enumerate() {
+            if !Blockchain::apply_transactions(balances, std::slice::from_ref(tx)) {
+                return Err(AddBlockError::Overspend { tx_index });
+            }
+            if tx.from != COINBASE && balances.get(&tx.from).copied().unwrap_or(0) < immature.get(&tx.from).copied().unwrap_or(0) {
+                return Err(AddBlockError::ImmatureReward { tx_index });
+            }
+        }
+
+        Ok(())
+    }
+
+    //a forged root would otherwise pass as long as the rest links to it
+    fn check_genesis(&self, genesis_header: &BlockHeader, genesis: &GenesisConfig) -> Result<(), ChainError> {
+        if *genesis_header != Block::genesis_block(self.hasher(), genesis).header {
+            return Err(ChainError::GenesisMismatch);
+        }
+        if self.conflicts_with_checkpoint(genesis_header) {
+            return Err(ChainError::Block { index: 0, error: AddBlockError::CheckpointMismatch });
+        }
+        Ok(())
+    }
+
+    //what can be checked of a chain without its transactions
+    fn validate_header_chain<B: AsRef<BlockHeader>>(&self, chain: &[B], genesis: &GenesisConfig) -> Result<(), ChainError> {
+        let Some(first) = chain.first() else { return Err(ChainError::Empty); };
+        self.check_genesis(first.as_ref(), genesis)?;
+
+        let mut target = self.base_target();
+        for i in 1..chain.len() {
+            target = self.next_target(&chain[..i], target);
+            self.check_header(chain[i - 1].as_ref(), chain[i].as_ref(), target, genesis.chain_id)
+                .map_err(|error| ChainError::Block { index: i, error })?;
+        }
+        Ok(())
+    }
+
+    //the same checks append_block runs, block by block from genesis, stopping at the first failure
+    fn validate_chain(&self, chain: &[Block], genesis: &GenesisConfig) -> Result<(), ChainError> {
+        let Some(first) = chain.first() else { return Err(ChainError::Empty); };
+        self.check_genesis(&first.header, genesis)?;
+        if first.body.transactions != Block::genesis_block(self.hasher(), genesis).body.transactions {
+            return Err(ChainError::GenesisMismatch);
+        }
+        //also caught below as a previous hash mismatch, this names it for what it is
+        if chain.get(1).is_some_and(|block| block.header.previous_hash != first.header.hash) {
+            return Err(ChainError::GenesisLinkMismatch);
+        }
+
+        let mut nonces = HashMap::new();
+        let mut balances = Blockchain::replay(&chain[..1]);
+        let mut target = self.base_target();
+        for i in 1..chain.len() {
+            target = self.next_target(&chain[..i], target);
+            self.check_header(&chain[i - 1].header, &chain[i].header, target, genesis.chain_id)
+                .and_then(|()| self.check_body(&chain[..i], &chain[i], &mut nonces, &mut balances))
+                .map_err(|error| ChainError::Block { index: i, error })?;
+        }
+        Ok(())
+    }
+
+    //compressed with zstd when the path ends in .zst, plain json otherwise
+    fn save_to_file(&self, path: &Path) -> io::Result<()> {
+        let json = serde_json::to_vec_pretty(self)?;
+        if path.extension().is_some_and(|ext| ext == "zst") {
+            fs::write(path, zstd::encode_all(json.as_slice(), ZSTD_LEVEL)?)
+        } else {
+            fs::write(path, json)
+        }
+    }
+
+    //a chain of just genesis under the same rules, to validate a snapshot of `chain` with
+    //while the original stays unlocked
+    fn with_same_rules(&self) -> Blockchain {
+        let mut blockchain = Blockchain::with_hasher(self.consensus.clone(), self.hash_algorithm, self.genesis.clone());
+        blockchain.adjustment_interval = self.adjustment_interval;
+        blockchain.target_block_time_ms = self.target_block_time_ms;
+        blockchain.min_difficulty = self.min_difficulty;
+        blockchain.max_difficulty = self.max_difficulty;
+        blockchain.checkpoints = self.checkpoints.clone();
+        blockchain.max_txs_per_block = self.max_txs_per_block;
+        blockchain.max_block_bytes = self.max_block_bytes;
+        blockchain.coinbase_maturity = self.coinbase_maturity;
+        blockchain.block_reward = self.block_reward;
+        blockchain.halving_interval = self.halving_interval;
+        blockchain
+    }
+
+    fn load_from_file(path: &Path) -> io::Result<Blockchain> {
+        let bytes = read_chain_file(path)?;
+        let mut blockchain: Blockchain = serde_json::from_slice(&bytes)?;
+
+        if let Err(e) = blockchain.validate_chain(blockchain.chain.blocks(), &blockchain.genesis) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("invalid chain in {}: {}", path.display(), e)));
+        }
+
+        blockchain.reindex();
+        Ok(blockchain)
+    }
+
+    //switches to a heavier valid chain that forks from ours. blocks past the common ancestor are rolled
+    //back and their transactions go back to the mempool, unless the new branch already includes them
+    fn reorganize(&mut self, new_chain: Vec<Block>) -> ReorgResult {
+        let common = self.chain.blocks().iter()
+            .zip(&new_chain)
+            .take_while(|(ours, theirs)| ours.header.hash == theirs.header.hash)
+            .count();
+
+        if common == 0 {
+            return ReorgResult::NoCommonAncestor;
+        }
+
+        let depth = self.chain.len() - common;
+        if self.max_reorg_depth.is_some_and(|max| depth > max) {
+            return ReorgResult::TooDeep { depth };
+        }
+
+        let Some(tip) = new_chain.last() else { return ReorgResult::Rejected; };
+        if !self.outweighs(Blockchain::total_work(&new_chain), new_chain.len(), &tip.header.hash) {
+            return ReorgResult::Rejected;
+        }
+        if let Err(e) = self.validate_chain(&new_chain, &self.genesis) {
+            warn!(chain_len = new_chain.len(); "heavier chain is invalid: {}", e);
+            return ReorgResult::Rejected;
+        }
+
+        let rolled_back = self.chain.truncate(common);
+        for block in new_chain.into_iter().skip(common) {
+            self.chain.append(block);
+        }
+        self.reindex();
+
+        //reindex covered the new blocks, anything they confirmed leaves the mempool
+        self.mempool.retain(|tx| !self.tx_index.contains_key(&tx.hash()));
+
+        //coinbases only pay out on the branch that mined them
+        for tx in rolled_back.iter().flat_map(|b| &b.body.transactions).filter(|tx| tx.from != COINBASE) {
+            self.add_to_mempool(tx.clone());
+        }
+
+        if self.new_blocks.receiver_count() > 0 {
+            for block in &self.chain.blocks()[common..] {
+                let _ = self.new_blocks.send(block.clone());
+            }
+        }
+        self.record_progress();
+
+        self.connect_orphans();
+
+        ReorgResult::Reorganized {
+            removed: rolled_back.len(),
+            added: self.chain.len() - common,
+        }
+    }
+}
+
+//holds block and transaction hashes alike
+const SEEN_CACHE_CAPACITY: usize = 1024;
+
+//how far a new transaction may travel from the node it entered at, plenty for the topologies
+//main builds
+const TX_HOPS: u8 = 16;
+
+//each invalid block or bad sync reply costs a peer this much score, each accepted block gives
+//back one point up to 0. at BAN_SCORE the peer is disconnected for good
+const PEER_PENALTY: i32 = 20;
+const BAN_SCORE: i32 = -100;
+
+//how often a node looks for mempool transactions past their age limit
+const MEMPOOL_SWEEP_INTERVAL_MS: u64 = 1000;
+//how often a self-mining node checks for a free mining slot, e.g. once the tip moved and its
+//miner was cancelled
+const SELF_MINE_POLL_MS: u64 = 50;
+
+//a chain request that didn't bring the tip up to the block that triggered it is sent again,
+//after SYNC_RETRY_BASE_MS and then twice as long each time up to SYNC_RETRY_MAX_MS. the node
+//gives up after SYNC_MAX_RETRIES and waits for the next block it can't place
+const SYNC_RETRY_BASE_MS: u64 = 500;
+const SYNC_RETRY_MAX_MS: u64 = 8000;
+const SYNC_MAX_RETRIES: u32 = 6;
+
+//blocks a slow websocket client can fall behind by before it starts missing some
+const BLOCK_FEED_CAPACITY: usize = 64;
+
+fn block_feed() -> broadcast::Sender<Block> {
+    broadcast::channel(BLOCK_FEED_CAPACITY).0
+}
+
+//blocks past the fork point being fetched from `peer` page by page, adopted once all are in
+struct BlockDownload {
+    peer: usize,
+    //height of the first block wanted
+    from: u64,
+    //hashes of the validated headers the blocks have to match, in order
+    expected: Vec<String>,
+    blocks: Vec<Block>,
+}
+
+impl BlockDownload {
+    fn next_index(&self) -> u64 {
+        self.from + self.blocks.len() as u64
+    }
+
+    fn remaining(&self) -> u32 {
+        u32::try_from(self.expected.len() - self.blocks.len()).unwrap_or(u32::MAX)
+    }
+}
+
+//a chain request still waiting to bring the tip up to `target_height`, see SYNC_RETRY_BASE_MS
+struct PendingSync {
+    target_height: u64,
+    retries: u32,
+    retry_at: tokio::time::Instant,
+}
+
+//bounded set of hashes a node has already processed, oldest entries are evicted first
+struct SeenCache {
+    hashes: HashSet<String>,
+    order: VecDeque<String>,
+    capacity: usize,
+}
+
+impl SeenCache {
+    fn new(capacity: usize) -> Self {
+        SeenCache {
+            hashes: HashSet::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    fn contains(&self, hash: &str) -> bool {
+        self.hashes.contains(hash)
+    }
+
+    //returns false if the hash was already seen
+    fn insert(&mut self, hash: &str) -> bool {
+        if self.hashes.contains(hash) {
+            return false;
+        }
+
+        if self.order.len() == self.capacity
+            && let Some(oldest) = self.order.pop_front() {
+            self.hashes.remove(&oldest);
+        }
+
+        self.hashes.insert(hash.to_string());
+        self.order.push_back(hash.to_string());
+        true
+    }
+}
+
+struct Node {
+    id: usize,
+    blockchain: Arc<Mutex<crate::Blockchain>>,
+    senders: Vec<mpsc::Sender<Message>>,
+    //node ids of the peers behind `senders`, in the same order
+    peer_ids: Vec<usize>,
+    //how well each peer has behaved, see PEER_PENALTY. missing means 0
+    peer_scores: HashMap<usize, i32>,
+    //peers that were cut off, blocks and sync replies still relayed from them are ignored
+    banned: HashSet<usize>,
+    receiver: mpsc::Receiver<Message>,
+    //shared by every miner started on the current tip, tripped once the tip moves
+    cancel_mining: Arc<AtomicBool>,
+    seen: SeenCache,
+    //the sync in progress, a newer ChainHeaders replaces it
+    download: Option<BlockDownload>,
+    pending_sync: Option<PendingSync>,
+    //most blocks sent in reply to one GetBlocks, the requester asks again for the rest
+    max_blocks_per_response: u32,
+    metrics: Arc<metrics::Metrics>,
+    //threads each proof-of-work search is split across
+    mine_workers: usize,
+    //nonces each of them tries between yielding its thread, 0 never yields
+    mine_yield_every: u64,
+    //nonces each of them tries between logging how the search is going, None never logs
+    mine_progress_every: Option<u64>,
+    //pending transactions older than this are evicted by the periodic sweep
+    max_mempool_age_ms: u128,
+    //where the chain is saved on shutdown
+    state_path: Option<PathBuf>,
+    //how many peers a gossiped message goes to, None means all of them
+    fanout: Option<usize>,
+    paused: bool,
+    paused_queue: VecDeque<Message>,
+    //where this node's coinbase rewards go
+    wallet_address: Address,
+    //one permit per mining task allowed to run at once, Mine requests beyond that are dropped
+    mining_slots: Arc<Semaphore>,
+    //a freshly mined block waits a random number of ms from this range before it's sent out, so
+    //blocks mined at the same height don't all reach the network at once
+    broadcast_jitter_ms: std::ops::RangeInclusive<u64>,
+    //whether Mine still produces a coinbase-only block when no pending transaction fits. only
+    //what this node mines depends on it, empty blocks from peers are valid either way
+    mine_empty_blocks: bool,
+    //mine on its own whenever a mining slot is free and there is something to mine, instead of
+    //waiting for Mine messages
+    self_mine: bool,
+    //how often the node validates its own chain from scratch, None never does
+    self_check_interval_ms: Option<u64>,
+    //the self-check in progress, a tick while it runs starts none
+    self_check: Option<tokio::task::JoinHandle<()>>,
+    //notified by a self-check that found the chain invalid, stops the node if halt_on_corruption
+    corruption: Arc<Notify>,
+    halt_on_corruption: bool,
+}
+
+impl Node {
+    fn new(id: usize, difficulty: usize, genesis: GenesisConfig) -> (Self, mpsc::Sender<Message>) {
+
+        let (tx, rx) = mpsc::channel(100);
+        let blockchain = crate::Blockchain::new(difficulty, genesis);
+
+        (
+            Node {
+                id,
+                blockchain: Arc::new(Mutex::new(blockchain)),
+                senders: Vec::new(),
+                peer_ids: Vec::new(),
+                peer_scores: HashMap::new(),
+                banned: HashSet::new(),
+                receiver: rx,
+                cancel_mining: Arc::new(AtomicBool::new(false)),
+                seen: SeenCache::new(SEEN_CACHE_CAPACITY),
+                download: None,
+                pending_sync: None,
+                max_blocks_per_response: 64,
+                metrics: Arc::new(metrics::Metrics::default()),
+                mine_workers: 1,
+                mine_yield_every: 0,
+                mine_progress_every: None,
+                max_mempool_age_ms: 60_000,
+                state_path: None,
+                fanout: None,
+                paused: false,
+                paused_queue: VecDeque::new(),
+                wallet_address: node_address(id),
+                mining_slots: Arc::new(Semaphore::new(1)),
+                broadcast_jitter_ms: 0..=0,
+                mine_empty_blocks: true,
+                self_mine: false,
+                self_check_interval_ms: None,
+                self_check: None,
+                corruption: Arc::new(Notify::new()),
+                halt_on_corruption: false,
+            },
+            tx,
+        )
+    }
+
+    fn connect (&mut self, peer_id: usize, sender: mpsc::Sender<Message>) {
+        self.senders.push(sender);
+        self.peer_ids.push(peer_id);
+    }
+
+    fn reward (&mut self, peer_id: usize) {
+        if let Some(score) = self.peer_scores.get_mut(&peer_id) {
+            *score = (*score + 1).min(0);
+        }
+    }
+
+    fn penalize (&mut self, peer_id: usize) {
+        if peer_id == self.id {
+            return;
+        }
+
+        let score = self.peer_scores.entry(peer_id).or_insert(0);
+        *score -= PEER_PENALTY;
+        let score = *score;
+        if score > BAN_SCORE || !self.banned.insert(peer_id) {
+            return;
+        }
+
+        warn!(peer_id = peer_id, score = score; "banning peer");
+        while let Some(i) = self.peer_ids.iter().position(|&id| id == peer_id) {
+            self.peer_ids.remove(i);
+            self.senders.remove(i);
+        }
+    }
+
+    //aborts in-flight miners, later Mine requests get a fresh flag
+    fn cancel_mining(&mut self) {
+        self.cancel_mining.store(true, Ordering::Relaxed);
+        self.cancel_mining = Arc::new(AtomicBool::new(false));
+    }
+    
+    //gossip: with a fanout set only a random subset of peers hears it, they forward it in turn
+    fn broadcast (&mut self, msg: Message) {
+        let all: Vec<usize> = (0..self.senders.len()).collect();
+        let peers: Vec<usize> = match self.fanout {
+            Some(fanout) => all.choose_multiple(&mut rand::thread_rng(), fanout).copied().collect(),
+            None => all,
+        };
+        self.send_to(&peers, msg);
+    }
+
+    //sync messages are meant for one particular neighbour, so they skip the fanout
+    fn broadcast_all (&mut self, msg: Message) {
+        let peers: Vec<usize> = (0..self.senders.len()).collect();
+        self.send_to(&peers, msg);
+    }
+
+    //`peers` index into `senders`. peers whose node has stopped are forgotten
+    fn send_to (&mut self, peers: &[usize], msg: Message) {
+        let mut gone = Vec::new();
+        for &i in peers {
+            if !Node::deliver(&self.senders[i], msg.clone(), &self.metrics) {
+                gone.push(i);
+            }
+        }
+
+        gone.sort_unstable();
+        for i in gone.into_iter().rev() {
+            self.senders.remove(i);
+            let peer_id = self.peer_ids.remove(i);
+            info!(peer_id = peer_id; "peer is gone, no longer sending to it");
+        }
+    }
+
+    //never waits: peers are sending to us too, waiting on a full queue could leave two nodes stuck
+    //on each other. a message for a full queue is dropped and counted, gossip and sync recover from
+    //a lost one. false once the peer's receiver is gone
+    fn deliver (peer: &mpsc::Sender<Message>, msg: Message, metrics: &metrics::Metrics) -> bool {
+        match peer.try_send(msg) {
+            Ok(()) => true,
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                metrics::Metrics::inc(&metrics.messages_dropped);
+                debug!("peer queue full, dropped a message");
+                true
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => false,
+        }
+    }
+
+    //handles messages until `shutdown` fires, then drains the queue and saves the chain
+    async fn run (self, shutdown: broadcast::Receiver<()>) {
+        let id = self.id;
+        logging::in_node_span(id, self.process_messages(shutdown)).await
+    }
+
+    async fn process_messages (mut self, mut shutdown: broadcast::Receiver<()>) {
+        let mut mempool_sweep = tokio::time::interval(std::time::Duration::from_millis(MEMPOOL_SWEEP_INTERVAL_MS));
+        let mut self_mine = tokio::time::interval(std::time::Duration::from_millis(SELF_MINE_POLL_MS));
+        //never ticks without an interval, see the branch below
+        let mut self_check = tokio::time::interval(std::time::Duration::from_millis(self.self_check_interval_ms.unwrap_or(1)));
+        loop {
+            let sync_retry_at = self.pending_sync.as_ref().map(|sync| sync.retry_at);
+            tokio::select! {
+                msg = self.receiver.recv() => match msg {
+                    Some(msg) => self.dispatch(msg).await,
+                    None => break,
+                },
+                _ = mempool_sweep.tick() => self.sweep_mempool().await,
+                _ = self_mine.tick(), if self.self_mine => self.mine_if_idle().await,
+                _ = tokio::time::sleep_until(sync_retry_at.unwrap_or_else(tokio::time::Instant::now)), if sync_retry_at.is_some() => self.retry_sync().await,
+                _ = self_check.tick(), if self.self_check_interval_ms.is_some() => self.start_self_check(),
+                //a corrupt chain isn't worth saving, the node stops where it is
+                _ = self.corruption.notified(), if self.halt_on_corruption => {
+                    self.cancel_mining();
+                    warn!(chain_len = self.blockchain.lock().await.chain.len(); "halting, our own chain failed the self-check");
+                    return;
+                }
+                _ = shutdown.recv() => break,
+            }
+        }
+
+        //whatever peers already queued still gets applied before the chain is saved
+        self.cancel_mining();
+        while let Ok(msg) = self.receiver.try_recv() {
+            self.dispatch(msg).await;
+        }
+        self.paused = false;
+        while let Some(msg) = self.paused_queue.pop_front() {
+            self.handle_message(msg).await;
+        }
+
+        let bchain = self.blockchain.lock().await;
+        if let Some(path) = &self.state_path
+            && let Err(e) = bchain.save_to_file(path) {
+            warn!("couldn't save {}: {}", path.display(), e);
+        }
+        info!(chain_len = bchain.chain.len(), peers = format!("{:?}", self.peer_ids); "node stopped");
+    }
+
+    //validates a copy of the chain on a blocking thread, so messages keep being handled meanwhile.
+    //the node's own chain is only ever built from valid blocks, failing means a bug corrupted it
+    fn start_self_check (&mut self) {
+        if self.self_check.as_ref().is_some_and(|check| !check.is_finished()) {
+            return;
+        }
+
+        let (id, blockchain, corruption) = (self.id, self.blockchain.clone(), self.corruption.clone());
+        self.self_check = Some(tokio::spawn(logging::in_node_span(id, async move {
+            let (validator, chain) = {
+                let bchain = blockchain.lock().await;
+                (bchain.with_same_rules(), bchain.chain.load_all())
+            };
+            let chain_len = chain.len();
+            let checked = tokio::task::spawn_blocking(move || validator.validate_chain(&chain, &validator.genesis))
+                .await
+                .expect("self-check panicked");
+
+            match checked {
+                Ok(()) => debug!(chain_len = chain_len; "self-check passed"),
+                Err(e) => {
+                    warn!(chain_len = chain_len; "SELF-CHECK FAILED, our own chain is invalid: {}", e);
+                    corruption.notify_one();
+                }
+            }
+        })));
+    }
+
+    async fn sweep_mempool (&self) {
+        let mut bchain = self.blockchain.lock().await;
+        for tx in bchain.evict_expired(self.max_mempool_age_ms) {
+            info!(tx_hash = tx.hash(), from = tx.from, nonce = tx.nonce; "evicted expired transaction");
+        }
+    }
+
+    //asks every peer for the chain and keeps asking until the tip reaches `target_height`. a
+    //request already pending keeps its backoff, it only raises the height to wait for
+    fn request_chain (&mut self, locator: Vec<String>, target_height: u64) {
+        match &mut self.pending_sync {
+            Some(sync) => sync.target_height = sync.target_height.max(target_height),
+            None => {
+                self.pending_sync = Some(PendingSync {
+                    target_height,
+                    retries: 0,
+                    retry_at: tokio::time::Instant::now() + std::time::Duration::from_millis(SYNC_RETRY_BASE_MS),
+                });
+            }
+        }
+        self.broadcast_all(Message::RequestChain { requester: self.id, locator });
+    }
+
+    //the pending sync is done once the tip got there, by sync or by gossip
+    fn finish_sync_at (&mut self, tip_index: u64) {
+        if self.pending_sync.as_ref().is_some_and(|sync| tip_index >= sync.target_height) {
+            debug!(height = tip_index; "caught up, sync no longer pending");
+            self.pending_sync = None;
+        }
+    }
+
+    async fn retry_sync (&mut self) {
+        let bchain = self.blockchain.lock().await;
+        let tip_index = bchain.last_block().header.index;
+        let locator = bchain.locator();
+        drop(bchain);
+
+        self.finish_sync_at(tip_index);
+        let Some(sync) = self.pending_sync.as_mut() else { return; };
+        if sync.retries >= SYNC_MAX_RETRIES {
+            warn!(height = tip_index, target = sync.target_height, retries = sync.retries; "chain sync gave up without catching up");
+            self.pending_sync = None;
+            return;
+        }
+
+        sync.retries += 1;
+        let delay = SYNC_RETRY_BASE_MS.saturating_mul(1 << sync.retries).min(SYNC_RETRY_MAX_MS);
+        sync.retry_at = tokio::time::Instant::now() + std::time::Duration::from_millis(delay);
+        info!(height = tip_index, target = sync.target_height, retry = sync.retries, next_in_ms = delay; "still behind, requesting chain again");
+        self.broadcast_all(Message::RequestChain { requester: self.id, locator });
+    }
+
+    //starts a miner the way a Mine message would, as long as a slot is free. the NewBlock handler
+    //cancels it when the tip moves, which frees the slot for a miner on the new tip
+    async fn mine_if_idle (&mut self) {
+        if self.paused || self.mining_slots.available_permits() == 0 {
+            return;
+        }
+        if !self.mine_empty_blocks && self.blockchain.lock().await.mempool.is_empty() {
+            return;
+        }
+        self.handle_message(Message::Mine).await;
+    }
+
+    //while paused, mining and incoming blocks wait in arrival order and run once the node resumes
+    async fn dispatch (&mut self, msg: Message) {
+        if self.paused && matches!(msg, Message::Mine | Message::NewBlock { .. }) {
+            self.paused_queue.push_back(msg);
+            return;
+        }
+
+        self.handle_message(msg).await;
+
+        if !self.paused {
+            while let Some(msg) = self.paused_queue.pop_front() {
+                self.handle_message(msg).await;
+            }
+        }
+    }
+
+    async fn handle_message (&mut self, msg: Message) {
+        match msg {
+            Message::Pause => {
+                info!("paused");
+                self.paused = true;
+            }
+
+            Message::Resume => {
+                info!(queued = self.paused_queue.len(); "resumed");
+                self.paused = false;
+            }
+
+            //the chain stays as it is until a peer's headers show a heavier one, so a resync
+            //that finds nothing better changes nothing
+            Message::Resync => {
+                let locator = self.blockchain.lock().await.locator();
+                info!(abandoned_download = self.download.is_some(); "resyncing from peers");
+                self.download = None;
+                self.broadcast_all(Message::RequestChain { requester: self.id, locator });
+            }
+
+            Message::Mine => {
+                //held by the task until its block is added or it gives up
+                let Ok(permit) = self.mining_slots.clone().try_acquire_owned() else {
+                    warn!("already mining at the concurrency limit, dropping Mine request");
+                    return;
+                };
+
+                let blockchain_clone = self.blockchain.clone();
+                let senders_clone = self.senders.clone();
+                let cancel = self.cancel_mining.clone();
+                let metrics_clone = self.metrics.clone();
+                let my_id = self.id;
+                let wallet_address = self.wallet_address.clone();
+                let workers = self.mine_workers;
+                let yield_every = self.mine_yield_every;
+                let progress_every = self.mine_progress_every;
+                let jitter = self.broadcast_jitter_ms.clone();
+                let mine_empty = self.mine_empty_blocks;
+
+                //mining
+                tokio::spawn(logging::in_node_span(my_id, async move {
+                    let (index, previous_hash, parent_timestamp, target, validator, txs, algorithm, chain_id, clock) = {
+                        let bchain = blockchain_clone.lock().await;
+                        let last = bchain.last_block();
+
+                        //a forged block only counts from the validator the parent picked, any other
+                        //node would just be putting its name to a block the network rejects
+                        let validator = bchain.expected_validator(&last.header.hash);
+                        if let ConsensusMode::ProofOfStake { .. } = bchain.consensus
+                            && validator.as_deref() != Some(wallet_address.to_string().as_str()) {
+                            debug!(block_index = last.header.index + 1, validator = validator.unwrap_or_default(); "not our turn to forge, skipping");
+                            return;
+                        }
+
+                        //left in the mempool until the block is accepted, so a cancelled miner loses nothing
+                        let txs = bchain.select_transactions(&wallet_address);
+                        //the coinbase is always there
+                        if txs.len() == 1 && !mine_empty {
+                            info!("no pending transactions to mine, skipping");
+                            return;
+                        }
+
+                        (last.header.index + 1, last.header.hash.clone(), last.header.timestamp, bchain.adjusted_target(), validator, txs, bchain.hash_algorithm, bchain.genesis.chain_id, bchain.clock.clone())
+                    };
+
+                    //a block has to come after its parent, and a retry after the attempt before it,
+                    //even when the clock hasn't moved as with --mock-clock
+                    let mut earliest = parent_timestamp + 1;
+                    let block_mined = loop {
+                        let timestamp = clock.now_millis().max(earliest);
+                        earliest = timestamp + 1;
+
+                        if let Some(validator) = &validator {
+                            break crate::Block::forge_block(algorithm.hasher(), chain_id, index, timestamp, txs, previous_hash, validator.clone());
+                        }
+
+                        let txs = txs.clone();
+                        let previous_hash = previous_hash.clone();
+                        let cancel = cancel.clone();
+
+                        let mined = tokio::task::spawn_blocking(move || {
+                            //parallel workers run on threads of their own, outside the node's span
+                            let report = |tried: u64, best: u32| logging::in_node_span_sync(my_id, || {
+                                info!(block_index = index, tried = tried, best_zero_bits = best, needed_zero_bits = target.leading_zero_bits(); "still mining");
+                            });
+                            let progress = progress_every.map(|every| MiningProgress { every, report: &report });
+                            logging::in_node_span_sync(my_id, || {
+                                if workers > 1 {
+                                    crate::Block::mine_block_parallel(algorithm.hasher(), chain_id, index, timestamp, txs, previous_hash, target, &cancel, workers, yield_every, progress.as_ref())
+                                } else {
+                                    crate::Block::mine_block(algorithm.hasher(), chain_id, index, timestamp, txs, previous_hash, target, &cancel, yield_every, progress.as_ref())
+                                }
+                            })
+                        }).await.expect("mining task panicked");
 
+                        match mined {
+                            Ok(block) => break block,
+                            Err(MineError::NonceExhausted) => {
+                                warn!(block_index = index; "exhausted the nonce space, retrying with a new timestamp");
+                            }
+                            Err(MineError::Cancelled) => {
+                                debug!(block_index = index; "stopped mining, the tip moved");
+                                return;
+                            }
+                        }
+                    };
 
+                    let mut bchain = blockchain_clone.lock().await;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct Block {
-    index: u64,
-    timestamp: u128,
-    data: String,
-    previous_hash: String,
-    hash: String,
-    nonce: u64,
-}
+                    match bchain.add_block(block_mined.clone()) {
+                        Ok(()) => {
+                            info!(block_index = block_mined.header.index, hash = block_mined.header.hash; "mined block");
+                            metrics::Metrics::inc(&metrics_clone.blocks_mined);
 
-impl Block {
+                            drop(bchain);
+                            //the block is ours already, waiting to send it shouldn't hold up the next one
+                            drop(permit);
 
-    fn compute_hash(index: u64, timestamp: u128, data: &str, previous_hash: &str, nonce: u64) -> String {
-        let mut hasher = Sha256::new();
-        hasher.update(index.to_string());
-        hasher.update(timestamp.to_string());
-        hasher.update(data);
-        hasher.update(previous_hash);
-        hasher.update(nonce.to_string());
+                            let delay = rand::Rng::gen_range(&mut rand::thread_rng(), jitter);
+                            if delay > 0 {
+                                debug!(block_index = block_mined.header.index, delay_ms = delay; "holding back mined block");
+                                tokio::time::sleep(std::time::Duration::from_millis(delay)).await;
+                            }
 
-        let result = hasher.finalize();
-        hex::encode(result)
-}
+                            //a peer that's gone is forgotten the next time the node itself sends
+                            for p in &senders_clone {
+                                Node::deliver(p, Message::NewBlock { from: my_id, block: block_mined.clone() }, &metrics_clone);
+                            }
+                        }
+                        Err(e) => {
+                            warn!(block_index = block_mined.header.index; "mined block but couldn't add it locally: {}", e);
+                        }
+                    }
 
-    fn new_block(index: u64, timestamp: u128, data: String, previous_hash: String, nonce: u64) -> Self {
-        let hash = Block::compute_hash(index, timestamp, &data, &previous_hash, nonce);
+                }));
 
-        Block {
-            index,
-            timestamp,
-            data,
-            previous_hash,
-            nonce,
-            hash,
-        }
-    }
+            }
+            
+            Message::NewBlock { from, block } => {
+                if self.banned.contains(&from) || !self.seen.insert(&block.header.hash) {
+                    return;
+                }
 
-    fn genesis_block() -> Self {
-        let index = 0;
-        let timestamp = 0;
-        let data = String::from("Hi There");
-        let previous_hash = String::from("0");
-        let nonce = 0;
+                let mut bchain = self.blockchain.lock().await;
 
-        Block::new_block(index, timestamp, data, previous_hash, nonce)
-    }
+                //our own mined blocks come back from peers without passing through the seen cache,
+                //and a block we already hold is no sign that we're behind
+                if bchain.block_by_hash(&block.header.hash).is_some() {
+                    debug!(block_index = block.header.index, hash = block.header.hash; "already have block, ignoring");
+                    return;
+                }
+
+                let tip_index = bchain.last_block().header.index;
+
+                match bchain.add_block(block.clone()) {
+                    Ok(()) => {
+                        info!(block_index = block.header.index, hash = block.header.hash; "block accepted, broadcasting");
+                        metrics::Metrics::inc(&self.metrics.blocks_accepted);
+                        let tip_index = bchain.last_block().header.index;
+                        drop(bchain);
+                        self.reward(from);
+                        self.finish_sync_at(tip_index);
+                        self.cancel_mining();
+                        self.broadcast(Message::NewBlock { from: self.id, block });
+                    }
+                    Err(AddBlockError::SideBranch) => {
+                        //peers tracking forks want it as much as we did, but our tip didn't move
+                        info!(block_index = block.header.index, hash = block.header.hash; "block kept on a side branch, broadcasting");
+                        drop(bchain);
+                        self.reward(from);
+                        self.broadcast(Message::NewBlock { from: self.id, block });
+                    }
+                    Err(AddBlockError::Orphan) if block.header.index == tip_index + 2 => {
+                        //one block behind, the missing parent is most likely still in flight
+                        debug!(block_index = block.header.index; "waiting for parent of orphan block");
+                    }
+                    Err(e) if e.needs_chain() => {
+                        warn!(block_index = block.header.index, hash = block.header.hash; "block rejected, requesting chain: {}", e);
+                        if e != AddBlockError::Orphan {
+                            metrics::Metrics::inc(&self.metrics.blocks_rejected);
+                        }
+
+                        //requesting the chain with our id
+                        let locator = bchain.locator();
+                        drop(bchain);
+                        self.request_chain(locator, block.header.index);
+                    }
+                    Err(e) => {
+                        warn!(block_index = block.header.index, hash = block.header.hash, peer_id = from; "invalid block rejected: {}", e);
+                        metrics::Metrics::inc(&self.metrics.blocks_rejected);
+                        drop(bchain);
+                        self.penalize(from);
+                    }
+                }
+            }
+
+            Message::Tx { tx, hops_left } => {
+                //forwarded at most once, even after it has left the mempool again. one turned down
+                //isn't marked, it may only have arrived ahead of the sender's previous transaction
+                let tx_hash = tx.hash();
+                if self.seen.contains(&tx_hash) {
+                    return;
+                }
+
+                let mut bchain = self.blockchain.lock().await;
+                if bchain.add_to_mempool(tx.clone()) {
+                    self.seen.insert(&tx_hash);
+                    debug!(tx_hash = tx_hash, mempool = bchain.mempool.len(), hops_left = hops_left; "queued transaction");
+                    drop(bchain);
+                    if hops_left > 0 {
+                        self.broadcast(Message::Tx { tx, hops_left: hops_left - 1 });
+                    }
+                }
+            }
 
+            Message::RequestChain { requester, locator } => {
+                let bchain = self.blockchain.lock().await;
+                //any other locator ends at genesis, so no match means another network
+                let fork = if locator.is_empty() { Some(0) } else { bchain.find_fork(&locator) };
+                let Some(fork) = fork else { return; };
+                let from = fork + 1;
+                let headers = bchain.headers_since(from);
+                drop(bchain);
+                if headers.is_empty() {
+                    return;
+                }
 
-    fn mine_block(index: u64, timestamp: u128, data: String, previous_hash: String, difficulty: usize) -> Self {
+                debug!(requester = requester, from = from; "chain requested, sending headers");
+                self.broadcast_all(Message::ChainHeaders { peer: self.id, requester, from, headers });
+            }
 
-        let prefix_target = "0".repeat(difficulty);
-        let mut nonce = 0;
+            Message::ChainHeaders { peer, requester, from, headers } => {
+                if requester != self.id || self.banned.contains(&peer) {
+                    return;
+                }
 
-        loop {
-            let hash = Block::compute_hash(index, timestamp, &data, &previous_hash, nonce);
+                //turned down before taking the lock, a flood of replies shouldn't hold up the node.
+                //length alone can't rule a chain out, a shorter one may still carry more work
+                let Some(tip) = headers.last() else {
+                    debug!(peer = peer; "peer sent no headers, ignoring the reply");
+                    return;
+                };
+                if headers.iter().zip(from..).any(|(header, index)| header.index != index) {
+                    warn!(peer = peer, from = from; "peer sent headers that don't count up from their height, ignoring them");
+                    self.penalize(peer);
+                    return;
+                }
+
+                let bchain = self.blockchain.lock().await;
+                let Some(ours) = usize::try_from(from).ok().and_then(|from| bchain.chain.blocks().get(..from)) else { return; };
+
+                //the peer's chain as far as we can tell: ours up to where its headers start, then theirs
+                let headers: Vec<BlockHeader> = ours.iter().map(|block| block.header.clone()).chain(headers.iter().cloned()).collect();
+                let work = headers.iter().fold(0u128, |work, header| work.saturating_add(Blockchain::block_work(&header.hash)));
+                if !bchain.outweighs(work, headers.len(), &tip.hash) {
+                    return;
+                }
+
+                //the claimed work is only real if the hashes check out, no need to fetch bodies otherwise
+                if let Err(e) = bchain.validate_header_chain(&headers, &bchain.genesis) {
+                    warn!(peer = peer, chain_len = headers.len(); "peer sent invalid headers, ignoring them: {}", e);
+                    drop(bchain);
+                    self.penalize(peer);
+                    return;
+                }
+
+                let common = bchain.common_prefix_len(&headers);
+                drop(bchain);
 
-            if hash.starts_with(&prefix_target) {
-                println!("Block minted with nonce: {} -> hash:  {}", nonce, hash);
+                if common == 0 {
+                    warn!(peer = peer, chain_len = headers.len(); "peer's chain shares no blocks with ours, ignoring it");
+                    return;
+                }
+
+                debug!(peer = peer, from = common, to = headers.len(); "requesting missing blocks");
+                let download = BlockDownload {
+                    peer,
+                    from: common as u64,
+                    expected: headers[common..].iter().map(|header| header.hash.clone()).collect(),
+                    blocks: Vec::new(),
+                };
+                let count = download.remaining();
+                self.download = Some(download);
+                self.broadcast_all(Message::GetBlocks { peer, requester: self.id, from: common as u64, count });
+            }
+
+            Message::GetBlocks { peer, requester, from, count } => {
+                if peer != self.id {
+                    return;
+                }
+
+                let count = count.min(self.max_blocks_per_response) as usize;
+                let bchain = self.blockchain.lock().await;
+                let blocks: Vec<Block> = bchain.blocks_since(from).iter().take(count).cloned().collect();
+                drop(bchain);
+
+                self.broadcast_all(Message::Blocks { peer, requester, blocks });
+            }
+
+            Message::Blocks { peer, requester, blocks } => {
+                if requester != self.id || self.banned.contains(&peer) {
+                    return;
+                }
+                let Some(download) = self.download.as_mut().filter(|download| download.peer == peer) else { return; };
+
+                //a page has to pick up where the last one ended and match the headers we checked
+                let start = download.blocks.len();
+                let fits = blocks.first().is_some_and(|b| b.header.index == download.next_index())
+                    && blocks.len() <= download.expected.len() - start
+                    && blocks.iter().zip(&download.expected[start..]).all(|(b, hash)| b.header.hash == *hash);
+                if !fits {
+                    warn!(peer = peer, from = download.next_index(); "peer sent blocks that don't match its headers, abandoning sync");
+                    self.download = None;
+                    self.penalize(peer);
+                    return;
+                }
+
+                download.blocks.extend(blocks);
+                if download.remaining() > 0 {
+                    let (from, count) = (download.next_index(), download.remaining());
+                    debug!(peer = peer, from = from, remaining = count; "requesting next page of blocks");
+                    self.broadcast_all(Message::GetBlocks { peer, requester: self.id, from, count });
+                    return;
+                }
+
+                let Some(download) = self.download.take() else { return; };
+                let mut bchain = self.blockchain.lock().await;
+                let from = download.from as usize;
+                if from == 0 || from > bchain.chain.len() {
+                    return;
+                }
+
+                //our blocks up to the fork point plus the peer's branch
+                let mut candidate = bchain.chain.blocks()[..from].to_vec();
+                candidate.extend(download.blocks);
+                let chain_len = candidate.len();
+
+                match bchain.reorganize(candidate) {
+                    ReorgResult::Reorganized { removed, added } => {
+                        info!(chain_len = chain_len, removed = removed, added = added; "reorganized onto a heavier chain");
+                        let tip = bchain.last_block().clone();
+                        drop(bchain);
+                        self.finish_sync_at(tip.header.index);
+                        self.cancel_mining();
+
+                        //peers that aren't linked to whoever we synced from learn about the new tip this way
+                        self.seen.insert(&tip.header.hash);
+                        self.broadcast(Message::NewBlock { from: self.id, block: tip });
+                    }
+                    ReorgResult::NoCommonAncestor => {
+                        warn!(chain_len = chain_len; "received chain shares no blocks with ours, ignoring it");
+                    }
+                    ReorgResult::TooDeep { depth } => {
+                        warn!(chain_len = chain_len, depth = depth; "received chain forks deeper than the maximum reorg depth, ignoring it");
+                    }
+                    ReorgResult::Rejected => {}
+                }
+            }
+
+            //net consumes these during the handshake, one getting this far has nothing to act on
+            Message::Hello { node_id, .. } => {
+                debug!(peer_id = node_id; "ignoring hello outside a handshake");
+            }
+
+            Message::BadFrame { peer } => self.penalize(peer),
+
+            Message::GetTxProof { requester, tx_hash } => {
+                if requester == self.id {
+                    return;
+                }
+
+                let Some((block_header, merkle_proof)) = self.blockchain.lock().await.tx_proof(&tx_hash) else { return; };
+                let height = block_header.index;
+                self.broadcast_all(Message::TxProof { peer: self.id, requester, tx_hash, block_header: Box::new(block_header), merkle_proof, height });
+            }
+
+            //checked the way a light client would, from the header alone
+            Message::TxProof { peer, requester, tx_hash, block_header, merkle_proof, height } => {
+                if requester != self.id || self.banned.contains(&peer) {
+                    return;
+                }
 
-                return Block { index, timestamp, data, previous_hash, hash, nonce };
+                let (hasher, chain_id, known_hash) = {
+                    let bchain = self.blockchain.lock().await;
+                    (bchain.hasher(), bchain.genesis.chain_id, bchain.chain.get(height).map(|block| block.header.hash.clone()))
+                };
+                //a peer further along than us isn't lying, we just can't tell yet
+                let Some(known_hash) = known_hash else {
+                    debug!(peer = peer, height = height; "can't check a proof past our tip for transaction {}", tx_hash);
+                    return;
+                };
+                if height == block_header.index && block_header.proves_inclusion(hasher, chain_id, &known_hash, &tx_hash, &merkle_proof) {
+                    info!(peer = peer, height = height, block = block_header.hash; "transaction {} proven", tx_hash);
+                } else {
+                    warn!(peer = peer, height = height; "peer sent an invalid proof for transaction {}", tx_hash);
+                    self.penalize(peer);
+                }
             }
-            nonce = nonce + 1;
         }
     }
-
 }
 
-struct Blockchain {
-    chain: Vec<Block>,
-    difficulty: usize
+const USAGE: &str = "usage: smblockchain [--nodes N] [--difficulty D] [--run-time SECS] [--mine-interval MS] [--mine-workers N] [--mine-yield-every NONCES] [--mine-progress-every NONCES] [--state-dir DIR] [--tcp-port PORT] [--http-port PORT] [--fanout N] [--chain-id ID] [--pause-node I] [--codec json|bincode] [--checkpoint HEIGHT:HASH]... [--allocate ADDRESS:AMOUNT]... [--max-miners N] [--stake NODE:AMOUNT]... [--mock-clock START_MS] [--max-mempool-age MS] [--seed N] [--max-blocks-per-response N] [--broadcast-jitter MIN_MS:MAX_MS] [--latency MIN_MS:MAX_MS] [--mine-empty-blocks true|false] [--self-mine true|false] [--health-window MS] [--coinbase-maturity BLOCKS] [--max-reorg-depth BLOCKS] [--min-difficulty BITS] [--max-difficulty BITS] [--self-check-interval MS] [--halt-on-corruption true|false] [--default-fee N]";
+
+struct Config {
+    nodes: usize,
+    difficulty: usize,
+    run_time: u64,
+    mine_interval: u64,
+    mine_workers: usize,
+    mine_yield_every: u64,
+    mine_progress_every: Option<u64>,
+    state_dir: Option<PathBuf>,
+    tcp_port: Option<u16>,
+    http_port: Option<u16>,
+    fanout: Option<usize>,
+    chain_id: u64,
+    pause_node: Option<usize>,
+    codec: codec::CodecKind,
+    checkpoints: HashMap<u64, String>,
+    allocations: Vec<(Address, u64)>,
+    max_miners: usize,
+    //(node id, stake) of each proof-of-stake validator, proof-of-work when empty
+    stakes: Vec<(usize, u64)>,
+    mock_clock: Option<u128>,
+    max_mempool_age: u128,
+    seed: Option<u64>,
+    max_blocks_per_response: u32,
+    broadcast_jitter: std::ops::RangeInclusive<u64>,
+    latency: std::ops::RangeInclusive<u64>,
+    mine_empty_blocks: bool,
+    self_mine: bool,
+    health_window: u128,
+    coinbase_maturity: u64,
+    max_reorg_depth: Option<usize>,
+    min_difficulty: u32,
+    max_difficulty: u32,
+    self_check_interval: Option<u64>,
+    halt_on_corruption: bool,
+    default_fee: u64,
 }
 
-impl Blockchain {
-    
-    fn new(difficulty: usize) -> Self {
-        Blockchain {
-            chain: vec![Block::genesis_block()],
-            difficulty,
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            nodes: 4,
+            difficulty: 3,
+            run_time: 10,
+            mine_interval: 800,
+            mine_workers: 1,
+            mine_yield_every: 0,
+            mine_progress_every: None,
+            state_dir: None,
+            tcp_port: None,
+            http_port: None,
+            fanout: None,
+            chain_id: 0,
+            pause_node: None,
+            codec: codec::CodecKind::default(),
+            checkpoints: HashMap::new(),
+            allocations: Vec::new(),
+            max_miners: 1,
+            stakes: Vec::new(),
+            mock_clock: None,
+            max_mempool_age: 60_000,
+            health_window: 30_000,
+            coinbase_maturity: 0,
+            max_reorg_depth: None,
+            min_difficulty: default_min_difficulty(),
+            max_difficulty: default_max_difficulty(),
+            self_check_interval: None,
+            halt_on_corruption: false,
+            default_fee: 1,
+            seed: None,
+            max_blocks_per_response: 64,
+            broadcast_jitter: 0..=0,
+            latency: 0..=0,
+            mine_empty_blocks: true,
+            self_mine: false,
         }
     }
+}
 
-    fn last_block(&self) -> &Block {
-        self.chain.last().unwrap()
-    }
+impl Config {
+    fn from_args(args: &[String]) -> Result<Config, String> {
+        let mut config = Config::default();
+        let mut args = args.iter();
+
+        while let Some(flag) = args.next() {
+            let value = args.next().ok_or_else(|| format!("missing value for {}", flag))?;
+
+            match flag.as_str() {
+                "--nodes" => config.nodes = parse_flag(flag, value)?,
+                "--difficulty" => config.difficulty = parse_flag(flag, value)?,
+                "--run-time" => config.run_time = parse_flag(flag, value)?,
+                "--mine-interval" => config.mine_interval = parse_flag(flag, value)?,
+                "--mine-workers" => config.mine_workers = parse_flag(flag, value)?,
+                "--mine-yield-every" => config.mine_yield_every = parse_flag(flag, value)?,
+                "--mine-progress-every" => config.mine_progress_every = Some(parse_flag(flag, value)?),
+                "--state-dir" => config.state_dir = Some(PathBuf::from(value)),
+                "--tcp-port" => config.tcp_port = Some(parse_flag(flag, value)?),
+                "--http-port" => config.http_port = Some(parse_flag(flag, value)?),
+                "--fanout" => config.fanout = Some(parse_flag(flag, value)?),
+                "--chain-id" => config.chain_id = parse_flag(flag, value)?,
+                "--pause-node" => config.pause_node = Some(parse_flag(flag, value)?),
+                "--codec" => config.codec = parse_flag(flag, value)?,
+                "--max-miners" => config.max_miners = parse_flag(flag, value)?,
+                "--mock-clock" => config.mock_clock = Some(parse_flag(flag, value)?),
+                "--max-mempool-age" => config.max_mempool_age = parse_flag(flag, value)?,
+                "--mine-empty-blocks" => config.mine_empty_blocks = parse_flag(flag, value)?,
+                "--self-mine" => config.self_mine = parse_flag(flag, value)?,
+                "--health-window" => config.health_window = parse_flag(flag, value)?,
+                "--coinbase-maturity" => config.coinbase_maturity = parse_flag(flag, value)?,
+                "--max-reorg-depth" => config.max_reorg_depth = Some(parse_flag(flag, value)?),
+                "--min-difficulty" => config.min_difficulty = parse_flag(flag, value)?,
+                "--max-difficulty" => config.max_difficulty = parse_flag(flag, value)?,
+                "--self-check-interval" => config.self_check_interval = Some(parse_flag(flag, value)?),
+                "--halt-on-corruption" => config.halt_on_corruption = parse_flag(flag, value)?,
+                "--default-fee" => config.default_fee = parse_flag(flag, value)?,
+                "--seed" => config.seed = Some(parse_flag(flag, value)?),
+                "--max-blocks-per-response" => config.max_blocks_per_response = parse_flag(flag, value)?,
+                "--checkpoint" => {
+                    let (height, hash) = value.split_once(':').ok_or_else(|| format!("invalid value for {}: {}", flag, value))?;
+                    config.checkpoints.insert(parse_flag(flag, height)?, hash.to_string());
+                }
+                "--allocate" => config.allocations.push(parse_allocation(flag, value)?),
+                "--stake" => {
+                    let (node, stake) = value.split_once(':').ok_or_else(|| format!("invalid value for {}: {}", flag, value))?;
+                    config.stakes.push((parse_flag(flag, node)?, parse_flag(flag, stake)?));
+                }
+                "--broadcast-jitter" => {
+                    let (min, max) = value.split_once(':').ok_or_else(|| format!("invalid value for {}: {}", flag, value))?;
+                    config.broadcast_jitter = parse_flag(flag, min)?..=parse_flag(flag, max)?;
+                }
+                "--latency" => {
+                    let (min, max) = value.split_once(':').ok_or_else(|| format!("invalid value for {}: {}", flag, value))?;
+                    config.latency = parse_flag(flag, min)?..=parse_flag(flag, max)?;
+                }
+                _ => return Err(format!("unknown argument {}", flag)),
+            }
+        }
 
-    fn add_block(&mut self, block: Block) -> bool {
-        let last = self.last_block();
+        if config.nodes < 1 {
+            return Err(String::from("--nodes must be at least 1"));
+        }
 
-        if block.index != last.index + 1 {
-            println!("Index mismatch");
-            return false;
+        if config.mine_workers < 1 {
+            return Err(String::from("--mine-workers must be at least 1"));
         }
 
-        if block.previous_hash != last.hash {
-            println!("Previous hash mismatch");
-            return false;
+        if config.max_miners < 1 {
+            return Err(String::from("--max-miners must be at least 1"));
         }
 
-        let hash_expected = Block::compute_hash(
-            block.index,
-            block.timestamp,
-            &block.data,
-            &block.previous_hash,
-            block.nonce,
-        );
+        if config.max_blocks_per_response < 1 {
+            return Err(String::from("--max-blocks-per-response must be at least 1"));
+        }
 
-        if hash_expected != block.hash {
-            println!("Hash mismatch");
-            return false;
+        if config.broadcast_jitter.is_empty() {
+            return Err(String::from("--broadcast-jitter must not have MIN above MAX"));
         }
 
-        self.chain.push(block);
-        println!("Block added successfully");
-        true
-    }
+        if config.latency.is_empty() {
+            return Err(String::from("--latency must not have MIN above MAX"));
+        }
 
-    fn is_valid_chain(chain: &[Block], difficulty: usize) -> bool {
-        if chain.is_empty() { return false; }
-        for i in 1..chain.len() {
-            let prev = &chain[i - 1];
-            let cur = &chain[i];
-            if cur.previous_hash != prev.hash { return false; }
-            let recomputed = Block::compute_hash(cur.index, cur.timestamp, &cur.previous_hash, &cur.data, cur.nonce);
-            if recomputed != cur.hash { return false; }
-            if !cur.hash.starts_with(&"0".repeat(difficulty)) { return false; }
+        if config.pause_node.is_some_and(|node| node >= config.nodes) {
+            return Err(String::from("--pause-node must name one of the nodes"));
         }
-        true
-    }
 
-    fn replace_chain(&mut self, new_chain: Vec<Block>) -> bool {
-        if new_chain.len() > self.chain.len() && Blockchain::is_valid_chain(&new_chain, self.difficulty) {
-            self.chain = new_chain;
-            true
-        } else {
-            false
+        if config.stakes.iter().any(|&(node, _)| node >= config.nodes) {
+            return Err(String::from("--stake must name one of the nodes"));
         }
-    }
-}
 
-struct Node {
-    id: usize,
-    blockchain: Arc<Mutex<crate::Blockchain>>,
-    senders: Vec<mpsc::Sender<Message>>,
-    receiver: mpsc::Receiver<Message>,
-}
+        if !config.stakes.is_empty() && config.stakes.iter().all(|&(_, stake)| stake == 0) {
+            return Err(String::from("--stake needs at least one validator with a stake above 0"));
+        }
 
-impl Node {
-    fn new(id: usize, difficulty: usize) -> (Self, mpsc::Sender<Message>) {
+        if config.fanout == Some(0) {
+            return Err(String::from("--fanout must be at least 1"));
+        }
 
-        let (tx, rx) = mpsc::channel(100);
-        let blockchain = crate::Blockchain::new(difficulty);
+        if config.mine_progress_every == Some(0) {
+            return Err(String::from("--mine-progress-every must be at least 1"));
+        }
 
-        (
-            Node {
-                id,
-                blockchain: Arc::new(Mutex::new(blockchain)),
-                senders: Vec::new(),
-                receiver: rx,
-            },
-            tx,
-        )
+        if config.self_check_interval == Some(0) {
+            return Err(String::from("--self-check-interval must be at least 1"));
+        }
+
+        if config.difficulty > 8 {
+            return Err(String::from("--difficulty must be at most 8"));
+        }
+
+        check_difficulty_bounds(config.difficulty, config.min_difficulty, config.max_difficulty)?;
+
+        Ok(config)
     }
+}
 
-    fn connect (&mut self, sender: mpsc::Sender<Message>) {
-        self.senders.push(sender);
+//the hardest target has to stay findable, and a starting target outside the bounds would be
+//pulled back in at the first retarget
+fn check_difficulty_bounds(difficulty: usize, min: u32, max: u32) -> Result<(), String> {
+    if max > 255 {
+        return Err(String::from("--max-difficulty must be at most 255"));
     }
-    
-    async fn broadcast (&self, msg: Message) {
-        for p in &self.senders {
-            let _ = p.send(msg.clone()).await;
+    if min > max {
+        return Err(String::from("--min-difficulty must not be above --max-difficulty"));
+    }
+    let bits = Target::from_leading_zeros(difficulty).leading_zero_bits();
+    if difficulty > 0 && !(min..=max).contains(&bits) {
+        return Err(format!("--difficulty {} is {} bits, outside --min-difficulty {} and --max-difficulty {}", difficulty, bits, min, max));
+    }
+    Ok(())
+}
 
+const VALIDATE_USAGE: &str = "usage: smblockchain validate --file FILE [--difficulty D] [--min-difficulty BITS] [--max-difficulty BITS] [--chain-id ID] [--allocate ADDRESS:AMOUNT]...";
+
+//offline check of a chain saved with --state-dir or fetched from GET /chain, using the same
+//validation a node runs before switching chains. exits 0 when valid, 1 when not and 2 when the
+//file can't be read
+fn validate_command(args: &[String]) -> i32 {
+    let mut file = None;
+    let mut difficulty = Config::default().difficulty;
+    let mut min_difficulty = default_min_difficulty();
+    let mut max_difficulty = default_max_difficulty();
+    let mut chain_id = 0;
+    let mut allocations = Vec::new();
+
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        let Some(value) = args.next() else {
+            eprintln!("error: missing value for {}\n{}", flag, VALIDATE_USAGE);
+            return 2;
+        };
+        let parsed = match flag.as_str() {
+            "--file" => {
+                file = Some(PathBuf::from(value));
+                Ok(())
+            }
+            "--difficulty" => parse_flag(flag, value).map(|d| difficulty = d),
+            "--min-difficulty" => parse_flag(flag, value).map(|bits| min_difficulty = bits),
+            "--max-difficulty" => parse_flag(flag, value).map(|bits| max_difficulty = bits),
+            "--chain-id" => parse_flag(flag, value).map(|id| chain_id = id),
+            "--allocate" => parse_allocation(flag, value).map(|allocation| allocations.push(allocation)),
+            _ => Err(format!("unknown argument {}", flag)),
+        };
+        if let Err(e) = parsed {
+            eprintln!("error: {}\n{}", e, VALIDATE_USAGE);
+            return 2;
         }
     }
 
-    async fn run (mut self) {
-        while let Some(msg) = self.receiver.recv().await {
-            match msg {
-                Message::Mine(data) => {
-                    let blockchain_clone = self.blockchain.clone();
-                    let senders_clone = self.senders.clone();
-                    let my_id = self.id;
+    let Some(file) = file else {
+        eprintln!("error: --file is required\n{}", VALIDATE_USAGE);
+        return 2;
+    };
 
-                    //mining
-                    tokio::spawn(async move {
+    if let Err(e) = check_difficulty_bounds(difficulty, min_difficulty, max_difficulty) {
+        eprintln!("error: {}\n{}", e, VALIDATE_USAGE);
+        return 2;
+    }
 
-                        let (index, previous_hash, difficulty) = {
-                            let bchain = blockchain_clone.lock().await;
+    let chain = read_chain_file(&file).map_err(|e| e.to_string()).and_then(|bytes| {
+        let value: serde_json::Value = serde_json::from_slice(&bytes).map_err(|e| e.to_string())?;
+        //a saved node keeps its blocks under "chain", GET /chain returns them bare
+        let blocks = match value {
+            serde_json::Value::Object(mut state) => state.remove("chain").unwrap_or_default(),
+            blocks => blocks,
+        };
+        serde_json::from_value::<Vec<Block>>(blocks).map_err(|e| e.to_string())
+    });
+    let chain = match chain {
+        Ok(chain) => chain,
+        Err(e) => {
+            eprintln!("error: couldn't read {}: {}", file.display(), e);
+            return 2;
+        }
+    };
+
+    let mut validator = Blockchain::new(difficulty, GenesisConfig { chain_id, allocations, ..GenesisConfig::default() });
+    validator.min_difficulty = min_difficulty;
+    validator.max_difficulty = max_difficulty;
+    match validator.validate_chain(&chain, &validator.genesis) {
+        Ok(()) => {
+            println!("valid, {} blocks", chain.len());
+            if let Some(tip) = chain.last() {
+                println!("tip {}", tip);
+            }
+            0
+        }
+        Err(e) => {
+            println!("invalid, {}", e);
+            let index = match e {
+                ChainError::Block { index, .. } => Some(index),
+                ChainError::GenesisLinkMismatch => Some(1),
+                ChainError::Empty | ChainError::GenesisMismatch => None,
+            };
+            if let Some(block) = index.and_then(|index| chain.get(index)) {
+                println!("block {}", block);
+            }
+            1
+        }
+    }
+}
 
-                            (bchain.last_block().index + 1, bchain.last_block().hash.clone(), bchain.difficulty)
-                        };
+const STATS_USAGE: &str = "usage: smblockchain stats --file FILE";
 
-                        let timestamp = std::time::SystemTime::now()
-                            .duration_since(std::time::UNIX_EPOCH)
-                            .unwrap()
-                            .as_millis();
-                    
-                        let block_mined = tokio::task::spawn_blocking(move || {
-                            crate::Block::mine_block(index, timestamp, data, previous_hash, difficulty)
-                        }).await.expect("mining task panicked");
+//summary of a chain saved with --state-dir, as the node's GET /stats would report it. the file is
+//validated on load like at startup. exits 0, or 2 when the file can't be loaded
+fn stats_command(args: &[String]) -> i32 {
+    let file = match args {
+        [flag, file] if flag == "--file" => PathBuf::from(file),
+        _ => {
+            eprintln!("error: --file is required\n{}", STATS_USAGE);
+            return 2;
+        }
+    };
 
-                        let mut bchain = blockchain_clone.lock().await;
+    match Blockchain::load_from_file(&file) {
+        Ok(blockchain) => {
+            println!("{}", serde_json::to_string_pretty(&blockchain.stats()).expect("stats serialize"));
+            0
+        }
+        Err(e) => {
+            eprintln!("error: couldn't load {}: {}", file.display(), e);
+            2
+        }
+    }
+}
 
-                        if bchain.add_block(block_mined.clone()) {
-                            println!("node {} mined block {}", my_id, block_mined.index);
+const WALLET_USAGE: &str = "usage: smblockchain wallet new [--out FILE]\n       smblockchain wallet sign --key FILE --tx JSON";
 
-                            drop(bchain);
+//what `wallet sign` takes, a transaction before it has a sender and a signature
+#[derive(Deserialize)]
+struct UnsignedTransaction {
+    outputs: Vec<(Address, u64)>,
+    #[serde(default)]
+    fee: u64,
+    nonce: u64,
+}
 
-                            for p in senders_clone {
-                                let _ = p.send(Message::NewBlock(block_mined.clone())).await;
-                            }
-                        } else {
-                            println!("node is {}. but couldn't add it locally", my_id)
-                        }
+//`new` makes a keypair and prints its address, and the secret unless it goes to --out. `sign`
+//signs a transaction with a secret written by `new` and prints it ready for submitTransaction.
+//exits 0 on success and 2 otherwise
+fn wallet_command(args: &[String]) -> i32 {
+    let Some((action, args)) = args.split_first() else {
+        eprintln!("error: expected new or sign\n{}", WALLET_USAGE);
+        return 2;
+    };
+
+    let known: &[&str] = match action.as_str() {
+        "new" => &["--out"],
+        "sign" => &["--key", "--tx"],
+        _ => {
+            eprintln!("error: unknown wallet command {}\n{}", action, WALLET_USAGE);
+            return 2;
+        }
+    };
+
+    let mut flags = HashMap::new();
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        let Some(value) = args.next() else {
+            eprintln!("error: missing value for {}\n{}", flag, WALLET_USAGE);
+            return 2;
+        };
+        if !known.contains(&flag.as_str()) {
+            eprintln!("error: unknown argument {}\n{}", flag, WALLET_USAGE);
+            return 2;
+        }
+        flags.insert(flag.as_str(), value.as_str());
+    }
 
-                    });
+    let result = match (action.as_str(), flags.get("--key"), flags.get("--tx")) {
+        ("new", _, _) => wallet_new(flags.get("--out").map(Path::new)),
+        (_, Some(key), Some(tx)) => wallet_sign(Path::new(key), tx),
+        _ => Err(String::from("--key and --tx are required")),
+    };
+
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("error: {}\n{}", e, WALLET_USAGE);
+            2
+        }
+    }
+}
 
-                }
-                
-                Message::NewBlock(block) => {
+fn wallet_new(out: Option<&Path>) -> Result<(), String> {
+    let keypair = ed25519::Keypair::generate();
+    let address = Address::from_payload(keypair.public.to_vec());
+    let secret = hex::encode(keypair.secret);
+    let Some(out) = out else {
+        println!("address {}", address);
+        println!("secret {}", secret);
+        return Ok(());
+    };
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    //readable by the owner only, the file is all it takes to spend the address's coins
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(out).map_err(|e| format!("couldn't create {}: {}", out.display(), e))?;
+    io::Write::write_all(&mut file, format!("{}\n", secret).as_bytes()).map_err(|e| format!("couldn't write {}: {}", out.display(), e))?;
+    println!("address {}", address);
+    println!("secret written to {}", out.display());
+    Ok(())
+}
 
-                    let mut bchain = self.blockchain.lock().await;
+fn wallet_sign(key: &Path, tx: &str) -> Result<(), String> {
+    let tx = signed_transaction(key, tx)?;
+    println!("{}", serde_json::to_string(&tx).expect("transaction serializes"));
+    Ok(())
+}
 
-                    if bchain.add_block(block.clone()) {
-                        println!("node {}, {} block is accepted and broadcasting", self.id, block.index);
-                        drop(bchain);
-                        self.broadcast(Message::NewBlock(block)).await;
-                        
-                    } else {
-                        println!("node is {}, block is rejected {} -- requesting chain", self.id, block.index);
-                        drop(bchain);
+//`tx`, an UnsignedTransaction in JSON, signed with the secret key in the file at `key`
+fn signed_transaction(key: &Path, tx: &str) -> Result<Transaction, String> {
+    let secret = fs::read_to_string(key).map_err(|e| format!("couldn't read {}: {}", key.display(), e))?;
+    let mut bytes = [0u8; 32];
+    hex::decode_to_slice(secret.trim(), &mut bytes).map_err(|_| format!("{} doesn't hold a hex secret key", key.display()))?;
+    let unsigned: UnsignedTransaction = serde_json::from_str(tx).map_err(|e| format!("invalid transaction: {}", e))?;
+
+    let mut tx = Transaction {
+        from: String::new(),
+        outputs: unsigned.outputs,
+        fee: unsigned.fee,
+        nonce: unsigned.nonce,
+        signature: Vec::new(),
+        public_key: Vec::new(),
+    };
+    tx.sign(&ed25519::Keypair::from_secret(bytes));
+    debug_assert!(tx.verify());
+    Ok(tx)
+}
 
-                        //requesting the chain with our id
-                        self.broadcast(Message::RequestChain(self.id)).await;
-                    }
-                }
+fn parse_flag<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("invalid value for {}: {}", flag, value))
+}
 
-                Message::RequestChain(from_id) => {
-                    let bchain = self.blockchain.lock().await;
-                    let blockchain_copy = bchain.chain.clone();
+//`ADDRESS:AMOUNT`, a genesis allocation
+fn parse_allocation(flag: &str, value: &str) -> Result<(Address, u64), String> {
+    let (address, amount) = value.split_once(':').ok_or_else(|| format!("invalid value for {}: {}", flag, value))?;
+    let address = Address::parse(address).map_err(|e| format!("invalid value for {}: {}", flag, e))?;
+    Ok((address, parse_flag(flag, amount)?))
+}
 
-                    drop(bchain);
+//who each node is linked to. full mesh without a fanout; with one, a ring keeps every node
+//reachable and random links are added until each node has twice the fanout in peers, so
+//gossip really only reaches part of them. links always go both ways so sync replies get back
+fn topology(nodes: usize, fanout: Option<usize>, rng: &mut impl rand::Rng) -> Vec<Vec<usize>> {
+    let Some(fanout) = fanout else {
+        return (0..nodes).map(|i| (0..nodes).filter(|&j| j != i).collect()).collect();
+    };
+
+    let mut links: Vec<Vec<usize>> = vec![Vec::new(); nodes];
+    let link = |links: &mut [Vec<usize>], a: usize, b: usize| {
+        if a != b && !links[a].contains(&b) {
+            links[a].push(b);
+            links[b].push(a);
+        }
+    };
+
+    for i in 0..nodes {
+        link(&mut links, i, (i + 1) % nodes);
+    }
 
-                    println!("node {}, chain is requesting from id: {}", self.id, from_id);
-                    self.broadcast(Message::Chain(blockchain_copy)).await;
+    let degree = (fanout * 2).min(nodes.saturating_sub(1));
+    for i in 0..nodes {
+        while links[i].len() < degree {
+            link(&mut links, i, rng.gen_range(0..nodes));
+        }
+    }
 
-                }
+    links
+}
+
+//one round of simulated traffic: the node asked to mine, and a payment from `sender` to
+//`outputs` handed to `entry_node`. drawn in a fixed order, so a seed replays the same rounds
+#[derive(Debug, PartialEq)]
+struct Round {
+    miner: usize,
+    sender: usize,
+    entry_node: usize,
+    outputs: Vec<(Address, u64)>,
+    fee: u64,
+}
 
-                Message::Chain(in_chain) => {
-                    let mut bchain = self.blockchain.lock().await;
+fn draw_round(rng: &mut impl rand::Rng, nodes: usize) -> Round {
+    let miner = rng.gen_range(0..nodes);
+    let sender = rng.gen_range(0..nodes);
+    let entry_node = rng.gen_range(0..nodes);
+    //now and then a payment split between a few recipients
+    let outputs = (0..rng.gen_range(1..=3))
+        .map(|_| (node_address(rng.gen_range(0..nodes)), rng.gen_range(1u64..10)))
+        .collect();
+    let fee = rng.gen_range(0u64..3);
+    Round { miner, sender, entry_node, outputs, fee }
+}
 
-                    if bchain.replace_chain(in_chain.clone()) {
-                        println!("node: {}, new chain replaced the old chain (len {})", self.id, in_chain.len());
+//stands in for a slow link: what is sent on the returned sender reaches `peer` after a delay drawn
+//from `latency_ms` for each message. a message never overtakes an earlier one that drew a longer
+//delay, so a link keeps its order like a tcp connection would
+fn delayed_link(peer: mpsc::Sender<Message>, latency_ms: std::ops::RangeInclusive<u64>) -> mpsc::Sender<Message> {
+    let (tx, mut rx) = mpsc::channel(100);
+
+    tokio::spawn(async move {
+        let mut in_flight: VecDeque<(tokio::time::Instant, Message)> = VecDeque::new();
+        let mut open = true;
+        while open || !in_flight.is_empty() {
+            let next_at = in_flight.front().map(|(at, _)| *at);
+            tokio::select! {
+                msg = rx.recv(), if open => match msg {
+                    Some(msg) => {
+                        let delay = rand::Rng::gen_range(&mut rand::thread_rng(), latency_ms.clone());
+                        let at = tokio::time::Instant::now() + std::time::Duration::from_millis(delay);
+                        let at = in_flight.back().map_or(at, |(last, _)| at.max(*last));
+                        in_flight.push_back((at, msg));
+                    }
+                    None => open = false,
+                },
+                _ = tokio::time::sleep_until(next_at.unwrap_or_else(tokio::time::Instant::now)), if next_at.is_some() => {
+                    //a full peer queue holds the link up, like a congested connection
+                    if let Some((_, msg)) = in_flight.pop_front()
+                        && peer.send(msg).await.is_err() {
+                        return;
                     }
                 }
             }
         }
-    }
-}
+    });
 
+    tx
+}
 
 #[tokio::main]
 async fn main() {
+    logging::init();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().is_some_and(|arg| arg == "validate") {
+        std::process::exit(validate_command(&args[1..]));
+    }
+    if args.first().is_some_and(|arg| arg == "stats") {
+        std::process::exit(stats_command(&args[1..]));
+    }
+    if args.first().is_some_and(|arg| arg == "wallet") {
+        std::process::exit(wallet_command(&args[1..]));
+    }
 
-    let node_total = 4usize;
-    let difficulty = 3usize;
-    let run_time = 10u64;
+    let config = match Config::from_args(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("error: {}\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    let node_total = config.nodes;
+    let genesis = GenesisConfig { chain_id: config.chain_id, allocations: config.allocations.clone(), ..GenesisConfig::default() };
+    //validators stake for their node's wallet, the address it forges under
+    let consensus = match config.stakes.as_slice() {
+        [] => ConsensusMode::ProofOfWork { difficulty: config.difficulty },
+        stakes => ConsensusMode::ProofOfStake {
+            validators: stakes.iter().map(|&(node, stake)| (node_address(node).to_string(), stake)).collect(),
+        },
+    };
+    let state_dir = config.state_dir.clone();
+    //one clock for every node so their timestamps agree, main moves it on each round of traffic
+    let mock_clock = config.mock_clock.map(|start| Arc::new(clock::MockClock::new(start)));
+    let node_clock: Arc<dyn Clock> = match &mock_clock {
+        Some(mock) => mock.clone(),
+        None => clock::system(),
+    };
+
+    if let Some(dir) = &state_dir {
+        fs::create_dir_all(dir).expect("couldn't create state dir");
+    }
 
     //nodes and senders creations
 
     let mut nodes = Vec::new();
     let mut transactions = Vec::new();
+    let mut chains = Vec::new();
+    let mut node_metrics = Vec::new();
+    let mut wallets = Vec::new();
 
     for i in 0..node_total {
-        let (node, tx) = Node::new(i, difficulty);
+        let (mut node, tx) = Node::new(i, config.difficulty, genesis.clone());
+        node.mine_workers = config.mine_workers;
+        node.mine_yield_every = config.mine_yield_every;
+        node.mine_progress_every = config.mine_progress_every;
+        node.max_blocks_per_response = config.max_blocks_per_response;
+        node.broadcast_jitter_ms = config.broadcast_jitter.clone();
+        node.mine_empty_blocks = config.mine_empty_blocks;
+        node.self_mine = config.self_mine;
+        node.self_check_interval_ms = config.self_check_interval;
+        node.halt_on_corruption = config.halt_on_corruption;
+        node.max_mempool_age_ms = config.max_mempool_age;
+        node.fanout = config.fanout;
+        node.mining_slots = Arc::new(Semaphore::new(config.max_miners));
+        {
+            let mut bchain = node.blockchain.lock().await;
+            bchain.consensus = consensus.clone();
+            bchain.checkpoints = config.checkpoints.clone();
+            bchain.max_reorg_depth = config.max_reorg_depth;
+            bchain.default_fee = config.default_fee;
+            bchain.coinbase_maturity = config.coinbase_maturity;
+            bchain.min_difficulty = config.min_difficulty;
+            bchain.max_difficulty = config.max_difficulty;
+        }
+
+        if let Some(dir) = &state_dir {
+            let path = dir.join(format!("node{}.json", i));
+            if path.exists() {
+                match Blockchain::load_from_file(&path) {
+                    Ok(bchain) if bchain.genesis != genesis => {
+                        warn!(node_id = i, chain_id = bchain.genesis.chain_id; "{} belongs to another network, starting from genesis", path.display());
+                    }
+                    //a saved proof-of-work chain keeps its own difficulty, but switching to or from
+                    //proof-of-stake, or to other validators, needs a new chain
+                    Ok(bchain) if bchain.consensus != consensus
+                        && !matches!((&bchain.consensus, &consensus), (ConsensusMode::ProofOfWork { .. }, ConsensusMode::ProofOfWork { .. })) => {
+                        warn!(node_id = i; "{} was built under other consensus rules than --stake gives, starting from genesis", path.display());
+                    }
+                    Ok(mut bchain) => {
+                        bchain.checkpoints = config.checkpoints.clone();
+                        bchain.max_reorg_depth = config.max_reorg_depth;
+                        bchain.default_fee = config.default_fee;
+                        let saved_maturity = std::mem::replace(&mut bchain.coinbase_maturity, config.coinbase_maturity);
+                        let saved_bounds = (bchain.min_difficulty, bchain.max_difficulty);
+                        bchain.min_difficulty = config.min_difficulty;
+                        bchain.max_difficulty = config.max_difficulty;
+                        if bchain.iter().any(|block| bchain.conflicts_with_checkpoint(&block.header)) {
+                            warn!(node_id = i; "{} conflicts with a checkpoint, starting from genesis", path.display());
+                        } else if saved_maturity != bchain.coinbase_maturity && bchain.validate_chain(bchain.chain.blocks(), &bchain.genesis).is_err() {
+                            warn!(node_id = i, saved = saved_maturity; "{} spends rewards before --coinbase-maturity, starting from genesis", path.display());
+                        } else if saved_bounds != (bchain.min_difficulty, bchain.max_difficulty) && bchain.validate_chain(bchain.chain.blocks(), &bchain.genesis).is_err() {
+                            warn!(node_id = i, saved_min = saved_bounds.0, saved_max = saved_bounds.1; "{} was retargeted outside --min-difficulty and --max-difficulty, starting from genesis", path.display());
+                        } else {
+                            info!(node_id = i, chain_len = bchain.chain.len(); "loaded chain from {}", path.display());
+                            node.blockchain = Arc::new(Mutex::new(bchain));
+                        }
+                    }
+                    Err(e) => warn!(node_id = i; "couldn't load {}: {}, starting from genesis", path.display(), e),
+                }
+            }
+            node.state_path = Some(path);
+        }
+        {
+            let mut bchain = node.blockchain.lock().await;
+            bchain.clock = node_clock.clone();
+            //a node that never adds a block is judged from when it started
+            bchain.record_progress();
+        }
 
+        chains.push(node.blockchain.clone());
+        node_metrics.push(node.metrics.clone());
+        wallets.push(node.wallet_address.clone());
         nodes.push(node);
         transactions.push(tx);
     }
 
+    //node i serves its chain over http on base_port + i
+    if let Some(base_port) = config.http_port {
+        for (i, bchain) in chains.iter().enumerate() {
+            let addr = std::net::SocketAddr::from(([127, 0, 0, 1], base_port + i as u16));
+            let listener = tokio::net::TcpListener::bind(addr).await.expect("couldn't bind http listener");
+            info!(node_id = i; "serving http on {}", addr);
+            tokio::spawn(http::serve(listener, bchain.clone(), node_metrics[i].clone(), wallets[i].clone(), transactions[i].clone(), config.health_window));
+        }
+    }
+
     //connect receivers
-    for i in 0..node_total {
-        for j in 0..node_total {
-            if i == j {
-                continue;
+    //the topology and the traffic below all come from here, a run is repeated by passing its seed
+    let seed = config.seed.unwrap_or_else(rand::random);
+    info!(seed = seed; "simulation seed");
+    let mut rng = StdRng::seed_from_u64(seed);
+    let peers = topology(node_total, config.fanout, &mut rng);
+    //every link a node sends on, delayed when --latency asks for it
+    let link = |sender: mpsc::Sender<Message>| match *config.latency.end() {
+        0 => sender,
+        _ => delayed_link(sender, config.latency.clone()),
+    };
+
+    if let Some(base_port) = config.tcp_port {
+        //node i listens on base_port + i and dials each of its peers' listeners
+        let addrs: Vec<std::net::SocketAddr> = (0..node_total)
+            .map(|i| std::net::SocketAddr::from(([127, 0, 0, 1], base_port + i as u16)))
+            .collect();
+        let identity = |node_id| net::Identity { node_id, chain_id: genesis.chain_id };
+
+        for (i, (addr, tx)) in addrs.iter().zip(&transactions).enumerate() {
+            let listener = tokio::net::TcpListener::bind(addr).await.expect("couldn't bind tcp listener");
+            tokio::spawn(net::serve(listener, tx.clone(), config.codec.codec(), identity(i)));
+        }
+
+        for (node, node_peers) in nodes.iter_mut().zip(&peers) {
+            for &j in node_peers {
+                match net::connect(addrs[j], config.codec.codec(), identity(node.id)).await {
+                    Ok((sender, peer_id)) => node.connect(peer_id, link(sender)),
+                    Err(e) => warn!(node_id = node.id, peer_id = j; "couldn't connect to peer: {}", e),
+                }
+            }
+        }
+    } else {
+        for (node, node_peers) in nodes.iter_mut().zip(&peers) {
+            for &j in node_peers {
+                node.connect(j, link(transactions[j].clone()));
             }
-            nodes[i].connect(transactions[j].clone()    );
         }
     }
 
+    let (shutdown, _) = broadcast::channel(1);
+    let mut running = tokio::task::JoinSet::new();
     for node in nodes {
-        tokio::spawn(node.run());
+        running.spawn(node.run(shutdown.subscribe()));
     }
 
-    use rand::Rng;
-
-    let mut rng = rand::thread_rng();
-    let start = std::time::SystemTime::now();
+    let keypairs: Vec<ed25519::Keypair> = (0..node_total).map(node_keypair).collect();
+    let start = std::time::Instant::now();
+    //the highest tip across the network and when it was reached, on the nodes' clock
+    let mut best_height = 0;
+    let mut best_since = node_clock.now_millis();
 
 
     //mine random node
-    while std::time::SystemTime::now()
-        .duration_since(start).unwrap().as_secs() < run_time {
-            let somene = rng.gen_range(0..node_total);
-            let data = format!("transaction: {}", rng.gen_range(0u64..u64::MAX));
+    //the paused node sits out the middle third of the run
+    let run_time = std::time::Duration::from_secs(config.run_time);
+    let mut pause_sent = false;
+    let mut resume_sent = false;
+
+    while start.elapsed() < run_time {
+            if let Some(node) = config.pause_node {
+                if !pause_sent && start.elapsed() >= run_time / 3 {
+                    let _ = transactions[node].send(Message::Pause).await;
+                    pause_sent = true;
+                }
+                if pause_sent && !resume_sent && start.elapsed() >= run_time * 2 / 3 {
+                    let _ = transactions[node].send(Message::Resume).await;
+                    resume_sent = true;
+                }
+            }
+
+            let round = draw_round(&mut rng, node_total);
+            let sender = &keypairs[round.sender];
+            //ask the node the transaction is handed to, so the nonce follows what it already knows
+            let nonce = chains[round.entry_node].lock().await.next_nonce(&hex::encode(sender.public));
+
+            let mut tx = Transaction {
+                from: String::new(),
+                outputs: round.outputs,
+                fee: round.fee,
+                nonce,
+                signature: Vec::new(),
+                public_key: Vec::new(),
+            };
+            tx.sign(sender);
+
+            let _ = transactions[round.entry_node].send(Message::Tx { tx, hops_left: TX_HOPS }).await;
+            //self-mining nodes don't need asking, the pick is still drawn so a seed gives the same traffic
+            //only the validator the tip picks can forge, so under proof-of-stake every node is asked
+            if !config.self_mine {
+                match consensus {
+                    ConsensusMode::ProofOfWork { .. } => {
+                        debug!(node_id = round.miner; "asking node to mine");
+                        let _ = transactions[round.miner].send(Message::Mine).await;
+                    }
+                    ConsensusMode::ProofOfStake { .. } => {
+                        for tx in &transactions {
+                            let _ = tx.send(Message::Mine).await;
+                        }
+                    }
+                }
+            }
 
-            let _ = transactions[somene].send(Message::Mine(data)).await;
+            tokio::time::sleep(std::time::Duration::from_millis(config.mine_interval)).await;
+            if let Some(mock) = &mock_clock {
+                mock.advance(config.mine_interval as u128);
+            }
 
-            tokio::time::sleep(std::time::Duration::from_millis(800)).await;
+            let mut height = 0;
+            for bchain in &chains {
+                height = height.max(bchain.lock().await.last_block().header.index);
+            }
+            let now = node_clock.now_millis();
+            if height > best_height {
+                best_height = height;
+                best_since = now;
+            } else if now.saturating_sub(best_since) > config.health_window {
+                warn!(height = best_height, idle_ms = now - best_since; "network tip hasn't advanced");
+                //warns again only after another full window without progress
+                best_since = now;
+            }
 
         }
 
@@ -321,20 +3480,57 @@ async fn main() {
         tokio::time::sleep(std::time::Duration::from_secs(1)).await;
 
         for tx in &transactions {
-            let _ = tx.send(Message::RequestChain(999)).await;
+            let _ = tx.send(Message::Resync).await;
         }
 
         tokio::time::sleep(std::time::Duration::from_secs(1)).await;
 
-        println!("Finished the simulation")
+        let _ = shutdown.send(());
+        while let Some(result) = running.join_next().await {
+            if let Err(e) = result {
+                warn!("node task failed: {}", e);
+            }
+        }
+
+        for (i, bchain) in chains.iter().enumerate() {
+            let bchain = bchain.lock().await;
+            info!(node_id = i, chain_len = bchain.chain.len(), wallet = wallets[i].to_string(), balance = bchain.balance_of(&wallets[i]); "final state");
+        }
+
+        info!("finished the simulation");
 
     
 }
 
-#[derive(Clone)]
+#[derive(Serialize, Deserialize, Clone)]
 enum Message {
-    Mine(String),
-    NewBlock(crate::Block),
-    RequestChain(usize),
-    Chain(Vec<crate::Block>),
+    Mine,
+    //stop mining and applying blocks until Resume, everything else is still handled
+    Pause,
+    Resume,
+    //drop any sync in progress and ask every peer for its chain, adopting it only if it
+    //outweighs ours. sent by main and POST /resync
+    Resync,
+    //`hops_left` is how many more times it may be forwarded, see TX_HOPS
+    Tx { tx: crate::Transaction, hops_left: u8 },
+    //`from` is the node that sent it on, mined or relayed
+    NewBlock { from: usize, block: crate::Block },
+    //`locator` is what `requester` holds, see Blockchain::locator. an empty one asks for
+    //everything past genesis
+    RequestChain { requester: usize, locator: Vec<String> },
+    //`peer`'s headers from height `from` on, the block below `from` is one the requester has
+    ChainHeaders { peer: usize, requester: usize, from: u64, headers: Vec<BlockHeader> },
+    //asks `peer` for `count` blocks of its chain starting at height `from`, it may send fewer
+    GetBlocks { peer: usize, requester: usize, from: u64, count: u32 },
+    Blocks { peer: usize, requester: usize, blocks: Vec<crate::Block> },
+    //first message on a tcp connection, the other side hangs up unless chain and version match
+    Hello { node_id: usize, chain_id: u64, version: u16 },
+    //from net, never a peer: `peer` sent a frame that didn't decode
+    BadFrame { peer: usize },
+    //a light client's request for proof that `tx_hash` is in a block, answered by every node
+    //that has it in its chain
+    GetTxProof { requester: usize, tx_hash: String },
+    //the header of the block at `height` holding `tx_hash` and the merkle branch up to its root,
+    //see BlockHeader::proves_inclusion. boxed, it would otherwise be the largest message by far
+    TxProof { peer: usize, requester: usize, tx_hash: String, block_header: Box<BlockHeader>, merkle_proof: Vec<(String, bool)>, height: u64 },
 }
\ No newline at end of file
@@ -0,0 +1,52 @@
+//per-node counters, bumped by the node's tasks and scraped over http.
+//gauges are read off the chain at scrape time so they can't drift from it
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::Blockchain;
+
+#[derive(Default)]
+pub struct Metrics {
+    pub blocks_mined: AtomicU64,
+    pub blocks_accepted: AtomicU64,
+    pub blocks_rejected: AtomicU64,
+    pub messages_dropped: AtomicU64,
+}
+
+impl Metrics {
+    pub fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    //prometheus text exposition format
+    pub fn render(&self, bchain: &Blockchain) -> String {
+        let mut out = String::new();
+
+        let counters = [
+            ("blocks_mined_total", "Blocks mined by this node and added to its chain.", &self.blocks_mined),
+            ("blocks_accepted_total", "Blocks received from peers and added to the chain.", &self.blocks_accepted),
+            ("blocks_rejected_total", "Blocks received from peers that could not be added.", &self.blocks_rejected),
+            ("messages_dropped_total", "Messages not sent because the peer's queue was full.", &self.messages_dropped),
+        ];
+        for (name, help, counter) in counters {
+            write_metric(&mut out, name, "counter", help, counter.load(Ordering::Relaxed));
+        }
+
+        let gauges = [
+            ("chain_length", "Number of blocks in the chain, genesis included.", bchain.chain.len() as u64),
+            ("difficulty_bits", "Leading zero bits the next block's hash needs to meet the target.", bchain.adjusted_target().leading_zero_bits() as u64),
+            ("mempool_size", "Transactions waiting to be mined.", bchain.mempool.len() as u64),
+        ];
+        for (name, help, value) in gauges {
+            write_metric(&mut out, name, "gauge", help, value);
+        }
+
+        out
+    }
+}
+
+fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP smblockchain_{} {}", name, help);
+    let _ = writeln!(out, "# TYPE smblockchain_{} {}", name, kind);
+    let _ = writeln!(out, "smblockchain_{} {}", name, value);
+}
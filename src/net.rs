@@ -0,0 +1,341 @@
+use std::{io, net::SocketAddr};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+use crate::Message;
+use crate::codec::{Codec, Frame};
+
+//frames are a big-endian u32 length followed by that many bytes of the encoded message
+const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+//frames an inbound peer may send that don't decode before it is disconnected. each one is
+//skipped and costs the peer score like an invalid block
+const MAX_BAD_FRAMES: u32 = 5;
+
+//sent in every frame's envelope and in Hello. message types a newer version adds are skipped by
+//older nodes, so only a change to an existing one, or to how blocks hash, needs a new
+//MIN_PROTOCOL_VERSION
+pub const PROTOCOL_VERSION: u16 = 5;
+//oldest version we talk to, the first one whose transaction ids leave out the signature. older
+//nodes' merkle roots, and so their block hashes, wouldn't match ours
+const MIN_PROTOCOL_VERSION: u16 = 5;
+
+//who we are to the peers we connect to
+#[derive(Clone, Copy)]
+pub struct Identity {
+    pub node_id: usize,
+    pub chain_id: u64,
+}
+
+impl Identity {
+    fn hello(&self) -> Message {
+        Message::Hello { node_id: self.node_id, chain_id: self.chain_id, version: PROTOCOL_VERSION }
+    }
+
+    //the peer's node id if its hello says it speaks our protocol on our chain
+    fn check_hello(&self, msg: Option<Message>) -> io::Result<usize> {
+        let refuse = |reason: String| Err(io::Error::new(io::ErrorKind::ConnectionRefused, reason));
+        match msg {
+            Some(Message::Hello { version, .. }) if version < MIN_PROTOCOL_VERSION => {
+                refuse(format!("peer speaks protocol {}, we need at least {}", version, MIN_PROTOCOL_VERSION))
+            }
+            Some(Message::Hello { chain_id, .. }) if chain_id != self.chain_id => {
+                refuse(format!("peer is on chain {}, we are on {}", chain_id, self.chain_id))
+            }
+            Some(Message::Hello { node_id, .. }) => Ok(node_id),
+            Some(_) => refuse(String::from("peer didn't start with a hello")),
+            None => refuse(String::from("peer closed the connection during the handshake")),
+        }
+    }
+}
+
+async fn write_frame<W: AsyncWriteExt + Unpin>(writer: &mut W, codec: &dyn Codec, msg: &Message) -> io::Result<()> {
+    let bytes = codec.encode(msg)?;
+    if bytes.len() > MAX_FRAME_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "frame too large"));
+    }
+
+    writer.write_all(&(bytes.len() as u32).to_be_bytes()).await?;
+    writer.write_all(&bytes).await?;
+    writer.flush().await
+}
+
+//one frame's bytes, Ok(None) means the peer closed the connection cleanly. an error leaves the
+//stream somewhere inside a frame, nothing more can be read from it
+async fn read_frame_bytes<R: AsyncReadExt + Unpin>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    match reader.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "frame too large"));
+    }
+
+    let mut bytes = vec![0u8; len];
+    reader.read_exact(&mut bytes).await?;
+    Ok(Some(bytes))
+}
+
+//None for a message type we don't know, see Frame::Unknown
+fn decode_frame(codec: &dyn Codec, bytes: &[u8]) -> io::Result<Option<Message>> {
+    match codec.decode(bytes)? {
+        Frame::Message(msg) => Ok(Some(msg)),
+        Frame::Unknown { version, variant } => {
+            warn!(version = version, variant = variant; "skipping a message type from a newer protocol");
+            Ok(None)
+        }
+    }
+}
+
+//Ok(None) means the peer closed the connection cleanly. frames holding a message type we don't
+//know are skipped, the connection goes on with the next one
+async fn read_frame<R: AsyncReadExt + Unpin>(reader: &mut R, codec: &dyn Codec) -> io::Result<Option<Message>> {
+    loop {
+        let Some(bytes) = read_frame_bytes(reader).await? else { return Ok(None); };
+        if let Some(msg) = decode_frame(codec, &bytes)? {
+            return Ok(Some(msg));
+        }
+    }
+}
+
+//accepts inbound peer connections and feeds every decoded message into the node's own channel.
+//the dialer says hello first, we answer with ours either way so it can tell why it was refused
+pub async fn serve(listener: TcpListener, inbound: mpsc::Sender<Message>, codec: &'static dyn Codec, identity: Identity) {
+    loop {
+        let (mut stream, addr) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                warn!("accept failed: {}", e);
+                continue;
+            }
+        };
+
+        let inbound = inbound.clone();
+        tokio::spawn(async move {
+            let peer_hello = read_frame(&mut stream, codec).await;
+            let handshake = match peer_hello {
+                Ok(msg) => match write_frame(&mut stream, codec, &identity.hello()).await {
+                    Ok(()) => identity.check_hello(msg),
+                    Err(e) => Err(e),
+                },
+                Err(e) => Err(e),
+            };
+            let peer_id = match handshake {
+                Ok(peer_id) => peer_id,
+                Err(e) => {
+                    warn!(peer = addr; "refused inbound connection: {}", e);
+                    return;
+                }
+            };
+            debug!(peer = addr, peer_id = peer_id; "inbound peer connected");
+
+            let mut bad_frames = 0;
+            loop {
+                let bytes = match read_frame_bytes(&mut stream).await {
+                    Ok(Some(bytes)) => bytes,
+                    Ok(None) => return,
+                    Err(e) => {
+                        warn!(peer = addr; "inbound connection dropped: {}", e);
+                        return;
+                    }
+                };
+
+                //a frame is whole even when it doesn't decode, so the next one can still be read
+                let msg = match decode_frame(codec, &bytes) {
+                    Ok(Some(msg)) => msg,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        bad_frames += 1;
+                        warn!(peer = addr, peer_id = peer_id, bad_frames = bad_frames; "skipping a frame that doesn't decode: {}", e);
+                        if inbound.send(Message::BadFrame { peer: peer_id }).await.is_err() || bad_frames >= MAX_BAD_FRAMES {
+                            return;
+                        }
+                        continue;
+                    }
+                };
+
+                match msg {
+                    //blocks and sync replies are scored by who sent them, so a peer can't pass its own
+                    //off as another's and get that one banned
+                    Message::NewBlock { from: claimed, .. }
+                    | Message::ChainHeaders { peer: claimed, .. }
+                    | Message::Blocks { peer: claimed, .. }
+                    | Message::TxProof { peer: claimed, .. } if claimed != peer_id => {
+                        warn!(peer = addr, peer_id = peer_id, claimed = claimed; "dropping a message sent under another node's id");
+                    }
+                    //only we say which peer sent a bad frame
+                    Message::BadFrame { .. } => {
+                        warn!(peer = addr, peer_id = peer_id; "dropping a bad frame report sent by a peer");
+                    }
+                    //the operator's controls, sent by main and POST /resync, never by a peer
+                    Message::Mine | Message::Pause | Message::Resume | Message::Resync => {
+                        warn!(peer = addr, peer_id = peer_id; "dropping a control message sent by a peer");
+                    }
+                    msg => {
+                        if inbound.send(msg).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+        });
+    }
+}
+
+//opens an outbound connection to a peer and exchanges hellos; messages sent on the returned
+//channel are written to the socket. also returns the peer's node id
+pub async fn connect(addr: SocketAddr, codec: &'static dyn Codec, identity: Identity) -> io::Result<(mpsc::Sender<Message>, usize)> {
+    let mut stream = TcpStream::connect(addr).await?;
+    write_frame(&mut stream, codec, &identity.hello()).await?;
+    let peer_id = identity.check_hello(read_frame(&mut stream, codec).await?)?;
+    let (tx, mut rx) = mpsc::channel::<Message>(100);
+
+    tokio::spawn(async move {
+        while let Some(msg) = rx.recv().await {
+            if let Err(e) = write_frame(&mut stream, codec, &msg).await {
+                warn!(peer = addr; "outbound connection dropped: {}", e);
+                return;
+            }
+        }
+    });
+
+    Ok((tx, peer_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::CodecKind;
+
+    #[tokio::test]
+    async fn peers_cannot_send_operator_controls() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (inbound, mut received) = mpsc::channel(16);
+        let codec = CodecKind::default().codec();
+        tokio::spawn(serve(listener, inbound, codec, Identity { node_id: 0, chain_id: 0 }));
+
+        let (peer, peer_id) = connect(addr, codec, Identity { node_id: 1, chain_id: 0 }).await.unwrap();
+        assert_eq!(peer_id, 0);
+        for msg in [Message::Mine, Message::Pause, Message::Resume, Message::Resync] {
+            peer.send(msg).await.unwrap();
+        }
+        peer.send(Message::GetTxProof { requester: 1, tx_hash: String::from("ab") }).await.unwrap();
+
+        //frames arrive in order, so anything let through would come first
+        match received.recv().await {
+            Some(Message::GetTxProof { requester: 1, tx_hash }) => assert_eq!(tx_hash, "ab"),
+            _ => panic!("a control message got through"),
+        }
+    }
+
+    #[tokio::test]
+    async fn a_peer_cannot_send_blocks_or_replies_under_another_peers_id() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (inbound, mut received) = mpsc::channel(16);
+        let codec = CodecKind::default().codec();
+        tokio::spawn(serve(listener, inbound, codec, Identity { node_id: 0, chain_id: 0 }));
+
+        let (peer, _) = connect(addr, codec, Identity { node_id: 1, chain_id: 0 }).await.unwrap();
+        let block = crate::Blockchain::new(0, crate::GenesisConfig::default()).last_block().clone();
+        let under = |id: usize| [
+            Message::NewBlock { from: id, block: block.clone() },
+            Message::ChainHeaders { peer: id, requester: 0, from: 0, headers: vec![block.header.clone()] },
+            Message::Blocks { peer: id, requester: 0, blocks: vec![block.clone()] },
+            Message::TxProof { peer: id, requester: 0, tx_hash: String::from("ab"), block_header: Box::new(block.header.clone()), merkle_proof: Vec::new(), height: 0 },
+        ];
+        for msg in under(2).into_iter().chain(under(1)) {
+            peer.send(msg).await.unwrap();
+        }
+
+        //frames arrive in order, so anything let through under id 2 would come first
+        for _ in 0..4 {
+            match received.recv().await {
+                Some(Message::NewBlock { from: 1, .. } | Message::ChainHeaders { peer: 1, .. } | Message::Blocks { peer: 1, .. } | Message::TxProof { peer: 1, .. }) => {}
+                _ => panic!("a message under another peer's id got through"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn a_message_type_we_dont_know_is_skipped_and_the_connection_goes_on() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (inbound, mut received) = mpsc::channel(16);
+        let codec = CodecKind::Json.codec();
+        tokio::spawn(serve(listener, inbound, codec, Identity { node_id: 0, chain_id: 0 }));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        write_frame(&mut stream, codec, &Identity { node_id: 1, chain_id: 0 }.hello()).await.unwrap();
+        assert_eq!(Identity { node_id: 1, chain_id: 0 }.check_hello(read_frame(&mut stream, codec).await.unwrap()).unwrap(), 0);
+
+        let newer = serde_json::json!({ "version": PROTOCOL_VERSION + 1, "payload": { "Gossip": { "peers": [2] } } }).to_string();
+        stream.write_all(&(newer.len() as u32).to_be_bytes()).await.unwrap();
+        stream.write_all(newer.as_bytes()).await.unwrap();
+        write_frame(&mut stream, codec, &Message::GetTxProof { requester: 1, tx_hash: String::from("ab") }).await.unwrap();
+
+        match received.recv().await {
+            Some(Message::GetTxProof { requester: 1, tx_hash }) => assert_eq!(tx_hash, "ab"),
+            Some(Message::BadFrame { .. }) => panic!("the unknown message counted as a bad frame"),
+            _ => panic!("the connection didn't survive the unknown message"),
+        }
+    }
+
+    #[tokio::test]
+    async fn garbage_from_one_peer_cuts_off_only_that_peer() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (inbound, mut received) = mpsc::channel(16);
+        let codec = CodecKind::default().codec();
+        tokio::spawn(serve(listener, inbound, codec, Identity { node_id: 0, chain_id: 0 }));
+
+        let mut garbled = TcpStream::connect(addr).await.unwrap();
+        write_frame(&mut garbled, codec, &Identity { node_id: 1, chain_id: 0 }.hello()).await.unwrap();
+        read_frame(&mut garbled, codec).await.unwrap();
+        let (peer, _) = connect(addr, codec, Identity { node_id: 2, chain_id: 0 }).await.unwrap();
+
+        for _ in 0..MAX_BAD_FRAMES {
+            garbled.write_all(&4u32.to_be_bytes()).await.unwrap();
+            garbled.write_all(&[0xde, 0xad, 0xbe, 0xef]).await.unwrap();
+        }
+        for _ in 0..MAX_BAD_FRAMES {
+            assert!(matches!(received.recv().await, Some(Message::BadFrame { peer: 1 })));
+        }
+        //past the limit the garbled connection is closed
+        let mut rest = Vec::new();
+        assert_eq!(garbled.read_to_end(&mut rest).await.unwrap(), 0);
+
+        peer.send(Message::GetTxProof { requester: 2, tx_hash: String::from("ab") }).await.unwrap();
+        match received.recv().await {
+            Some(Message::GetTxProof { requester: 2, tx_hash }) => assert_eq!(tx_hash, "ab"),
+            _ => panic!("the other peer wasn't served"),
+        }
+    }
+
+    #[tokio::test]
+    async fn a_peer_on_another_chain_or_protocol_is_refused_at_the_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (inbound, _received) = mpsc::channel(16);
+        let codec = CodecKind::default().codec();
+        let ours = Identity { node_id: 0, chain_id: 7 };
+        tokio::spawn(serve(listener, inbound, codec, ours));
+
+        //the listener tells us its chain in its own hello, so either side can refuse
+        let refused = connect(addr, codec, Identity { node_id: 1, chain_id: 8 }).await.expect_err("another chain got through");
+        assert_eq!(refused.kind(), io::ErrorKind::ConnectionRefused);
+        assert!(refused.to_string().contains("chain 7"), "{}", refused);
+        assert_eq!(connect(addr, codec, Identity { node_id: 1, chain_id: 7 }).await.map(|(_, peer_id)| peer_id).ok(), Some(0));
+
+        let hello = |version| Some(Message::Hello { node_id: 1, chain_id: 7, version });
+        assert!(ours.check_hello(hello(MIN_PROTOCOL_VERSION - 1)).is_err());
+        assert_eq!(ours.check_hello(hello(PROTOCOL_VERSION)).ok(), Some(1));
+        assert!(ours.check_hello(Some(Message::Mine)).is_err());
+        assert!(ours.check_hello(None).is_err());
+    }
+}
@@ -0,0 +1,117 @@
+//JSON-RPC 2.0 over POST /rpc, the one way to write to a node from outside. single requests only,
+//batches are turned down as invalid
+use serde_json::{json, Value};
+use tokio::sync::{mpsc, Mutex};
+
+use crate::{Address, Blockchain, Message, Transaction};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+//the node turned the transaction down, the message says why
+const TX_REJECTED: i64 = -32000;
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> RpcError {
+        RpcError { code, message: message.into() }
+    }
+}
+
+//the response to send back, None for a notification, which gets none
+pub async fn handle(body: &[u8], blockchain: &Mutex<Blockchain>, inbound: &mpsc::Sender<Message>) -> Option<Value> {
+    let request: Value = match serde_json::from_slice(body) {
+        Ok(request) => request,
+        Err(e) => return Some(error_response(Value::Null, RpcError::new(PARSE_ERROR, e.to_string()))),
+    };
+
+    let id = match request.get("id") {
+        None => None,
+        Some(id @ (Value::Null | Value::Number(_) | Value::String(_))) => Some(id.clone()),
+        Some(_) => return Some(error_response(Value::Null, RpcError::new(INVALID_REQUEST, "id must be a string, number or null"))),
+    };
+
+    let method = match (request.get("jsonrpc").and_then(Value::as_str), request.get("method").and_then(Value::as_str)) {
+        (Some("2.0"), Some(method)) => method,
+        _ => return Some(error_response(id.unwrap_or(Value::Null), RpcError::new(INVALID_REQUEST, "expected a jsonrpc 2.0 request object"))),
+    };
+    let params = request.get("params").unwrap_or(&Value::Null);
+
+    let outcome = match method {
+        "submitTransaction" => submit_transaction(params, blockchain, inbound).await,
+        "getBalance" => get_balance(params, blockchain).await,
+        "getConfirmedBalance" => get_confirmed_balance(params, blockchain).await,
+        "estimateFee" => estimate_fee(params, blockchain).await,
+        _ => Err(RpcError::new(METHOD_NOT_FOUND, format!("unknown method {}", method))),
+    };
+
+    let id = id?;
+    Some(match outcome {
+        Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+        Err(e) => error_response(id, e),
+    })
+}
+
+fn error_response(id: Value, error: RpcError) -> Value {
+    json!({ "jsonrpc": "2.0", "error": { "code": error.code, "message": error.message }, "id": id })
+}
+
+//params may be positional or named, `[.., value]` with the value at `position` and
+//`{"name": value}` both work
+fn param<'a>(params: &'a Value, position: usize, name: &str) -> Result<&'a Value, RpcError> {
+    match params {
+        Value::Array(values) => values.get(position),
+        Value::Object(fields) => fields.get(name),
+        _ => None,
+    }
+    .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("missing param {}", name)))
+}
+
+//checked against the chain here so the caller hears why a transaction is refused, then handed to
+//the node like a gossiped one so it is queued and passed on to peers. returns the tx hash
+async fn submit_transaction(params: &Value, blockchain: &Mutex<Blockchain>, inbound: &mpsc::Sender<Message>) -> Result<Value, RpcError> {
+    let tx: Transaction = serde_json::from_value(param(params, 0, "tx")?.clone())
+        .map_err(|e| RpcError::new(INVALID_PARAMS, format!("invalid transaction: {}", e)))?;
+
+    blockchain.lock().await.check_pending(&tx).map_err(|e| RpcError::new(TX_REJECTED, e.to_string()))?;
+
+    let hash = tx.hash();
+    inbound.send(Message::Tx { tx, hops_left: crate::TX_HOPS }).await.map_err(|_| RpcError::new(INTERNAL_ERROR, "node has stopped"))?;
+    Ok(json!(hash))
+}
+
+fn address_param(params: &Value) -> Result<Address, RpcError> {
+    let address = param(params, 0, "address")?
+        .as_str()
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, "address must be a string"))?;
+    Address::parse(address).map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))
+}
+
+async fn get_balance(params: &Value, blockchain: &Mutex<Blockchain>) -> Result<Value, RpcError> {
+    let address = address_param(params)?;
+    Ok(json!(blockchain.lock().await.balance_of(&address)))
+}
+
+//only what blocks with at least `confirmations` blocks on top of them paid the address
+async fn get_confirmed_balance(params: &Value, blockchain: &Mutex<Blockchain>) -> Result<Value, RpcError> {
+    let address = address_param(params)?;
+    let confirmations = param(params, 1, "confirmations")?
+        .as_u64()
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, "confirmations must be a non-negative integer"))?;
+    Ok(json!(blockchain.lock().await.confirmed_balance_of(&address, confirmations)))
+}
+
+//a fee likely to confirm within `blocks` blocks, see Blockchain::estimate_fee
+async fn estimate_fee(params: &Value, blockchain: &Mutex<Blockchain>) -> Result<Value, RpcError> {
+    let blocks = param(params, 0, "blocks")?
+        .as_u64()
+        .filter(|&blocks| blocks > 0)
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, "blocks must be a positive integer"))?;
+    Ok(json!(blockchain.lock().await.estimate_fee(blocks)))
+}
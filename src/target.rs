@@ -0,0 +1,182 @@
+//proof-of-work threshold. a hash meets it when its first 32 bytes, read as a big-endian
+//256-bit number, are strictly below the target
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Target(pub [u8; 32]);
+
+impl Target {
+    //no work requirement, every hash meets it
+    pub const MAX: Target = Target([0xff; 32]);
+
+    //the old rule of `digits` leading zero hex characters, i.e. a target of 2^(256 - 4 * digits)
+    pub fn from_leading_zeros(digits: usize) -> Target {
+        if digits == 0 {
+            return Target::MAX;
+        }
+
+        let mut bytes = [0u8; 32];
+        if let Some(bit) = 256usize.checked_sub(4 * digits) {
+            bytes[31 - bit / 8] = 1 << (bit % 8);
+        }
+        Target(bytes)
+    }
+
+    //2^(256 - bits), the easiest target leading_zero_bits reports as `bits`. MAX for 0, zero
+    //past 256
+    pub fn from_leading_zero_bits(bits: u32) -> Target {
+        if bits == 0 {
+            return Target::MAX;
+        }
+
+        let mut bytes = [0u8; 32];
+        if let Some(bit) = 256u32.checked_sub(bits) {
+            bytes[31 - bit as usize / 8] = 1 << (bit % 8);
+        }
+        Target(bytes)
+    }
+
+    pub fn is_met_by(&self, hash: &str) -> bool {
+        let Some(prefix) = hash.get(..64) else { return false; };
+        let mut bytes = [0u8; 32];
+        if hex::decode_to_slice(prefix, &mut bytes).is_err() {
+            return false;
+        }
+        //no number is below MAX that isn't also all 0xff, so MAX is let through explicitly
+        *self == Target::MAX || bytes < self.0
+    }
+
+    //halves the target, never down to zero so a block stays findable in principle
+    pub fn harder(self) -> Target {
+        let mut bytes = self.0;
+        let mut carry = 0;
+        for byte in bytes.iter_mut() {
+            let next_carry = *byte & 1;
+            *byte = (*byte >> 1) | (carry << 7);
+            carry = next_carry;
+        }
+        if bytes == [0u8; 32] {
+            bytes[31] = 1;
+        }
+        Target(bytes)
+    }
+
+    //doubles the target, capped at MAX
+    pub fn easier(self) -> Target {
+        if self.0[0] & 0x80 != 0 {
+            return Target::MAX;
+        }
+
+        let mut bytes = self.0;
+        let mut carry = 0;
+        for byte in bytes.iter_mut().rev() {
+            let next_carry = *byte >> 7;
+            *byte = (*byte << 1) | carry;
+            carry = next_carry;
+        }
+        Target(bytes)
+    }
+
+    pub fn to_hex(self) -> String {
+        hex::encode(self.0)
+    }
+
+    //how many leading zero bits a hash needs at least to get under the target, those of the
+    //largest hash that does. 4 * digits for from_leading_zeros(digits), 256 when none does
+    pub fn leading_zero_bits(&self) -> u32 {
+        if *self == Target::MAX {
+            return 0;
+        }
+
+        let mut largest = self.0;
+        for byte in largest.iter_mut().rev() {
+            let borrowed = *byte == 0;
+            *byte = byte.wrapping_sub(1);
+            if !borrowed {
+                return leading_zero_bits(&largest);
+            }
+        }
+        256
+    }
+}
+
+//of a 256-bit big-endian number, such as a hash's first 32 bytes
+pub fn leading_zero_bits(bytes: &[u8; 32]) -> u32 {
+    match bytes.iter().position(|&byte| byte != 0) {
+        Some(i) => i as u32 * 8 + bytes[i].leading_zeros(),
+        None => 256,
+    }
+}
+
+//written as 64 hex digits, the same way hashes are
+impl serde::Serialize for Target {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_hex())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Target {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let digits = String::deserialize(deserializer)?;
+        let mut bytes = [0u8; 32];
+        hex::decode_to_slice(&digits, &mut bytes).map_err(serde::de::Error::custom)?;
+        Ok(Target(bytes))
+    }
+}
+
+//e.g. `~12 leading zero bits (0010000000000000000000000000000000000000000000000000000000000000)`
+impl std::fmt::Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "~{} leading zero bits ({})", self.leading_zero_bits(), self.to_hex())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn difficulty_digits_need_four_bits_each() {
+        for digits in 0..=8 {
+            let target = Target::from_leading_zeros(digits);
+            assert_eq!(target.leading_zero_bits(), 4 * digits as u32);
+            assert_eq!(Target::from_leading_zero_bits(4 * digits as u32), target);
+        }
+    }
+
+    #[test]
+    fn leading_zero_bits_is_what_the_largest_hash_under_the_target_has() {
+        let target = Target::from_leading_zeros(4);
+        assert!(target.is_met_by(&format!("0000{}", "f".repeat(60))));
+        assert!(!target.is_met_by(&format!("0001{}", "0".repeat(60))));
+
+        for bits in 0..=256 {
+            assert_eq!(Target::from_leading_zero_bits(bits).leading_zero_bits(), bits);
+        }
+        assert_eq!(Target::from_leading_zero_bits(257), Target([0; 32]));
+        assert_eq!(Target([0; 32]).leading_zero_bits(), 256);
+    }
+
+    #[test]
+    fn a_hash_equal_to_the_target_misses_it() {
+        let target = Target::from_leading_zeros(2);
+        assert_eq!(target.to_hex(), format!("01{}", "0".repeat(62)));
+
+        assert!(!target.is_met_by(&target.to_hex()));
+        assert!(target.is_met_by(&format!("00{}", "f".repeat(62))));
+        assert!(!target.is_met_by(&format!("01{}1", "0".repeat(61))));
+        //anything that isn't 64 hex digits never meets it
+        assert!(!target.is_met_by("00"));
+        assert!(!target.is_met_by(&"0g".repeat(32)));
+
+        assert!(Target::MAX.is_met_by(&"f".repeat(64)));
+        assert!(!Target([0; 32]).is_met_by(&"0".repeat(64)));
+    }
+
+    #[test]
+    fn display_shows_the_bits_and_the_full_target() {
+        assert_eq!(
+            Target::from_leading_zeros(3).to_string(),
+            format!("~12 leading zero bits (001{})", "0".repeat(61)),
+        );
+        assert_eq!(Target::MAX.to_string(), format!("~0 leading zero bits ({})", "f".repeat(64)));
+    }
+}
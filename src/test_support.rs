@@ -0,0 +1,171 @@
+//a network of nodes driven by hand for tests. nothing runs on its own: blocks are only mined by
+//mine_on and messages only handled by deliver_all, so every run takes the same steps
+use crate::*;
+
+//what the genesis block credits every node's wallet with, so tests can spend right away
+pub const ALLOCATION: u64 = 1_000;
+
+pub struct TestNetwork {
+    nodes: Vec<Node>,
+    inbound: Vec<mpsc::Sender<Message>>,
+    clock: Arc<clock::MockClock>,
+}
+
+impl TestNetwork {
+    //`n` nodes all linked to each other
+    pub fn new(n: usize) -> TestNetwork {
+        let mut network = TestNetwork::unlinked(n);
+        for a in 0..n {
+            for b in a + 1..n {
+                network.link(a, b);
+            }
+        }
+        network
+    }
+
+    //`n` nodes at difficulty 0 sharing a clock stopped at 1000 ms, none of them linked yet
+    pub fn unlinked(n: usize) -> TestNetwork {
+        let genesis = GenesisConfig {
+            allocations: (0..n).map(|id| (node_address(id), ALLOCATION)).collect(),
+            ..GenesisConfig::default()
+        };
+        let clock = Arc::new(clock::MockClock::new(1_000));
+
+        let mut nodes = Vec::new();
+        let mut inbound = Vec::new();
+        for id in 0..n {
+            let (node, sender) = Node::new(id, 0, genesis.clone());
+            node.blockchain.try_lock().expect("nothing else holds a test node's chain").clock = clock.clone();
+            nodes.push(node);
+            inbound.push(sender);
+        }
+        TestNetwork { nodes, inbound, clock }
+    }
+
+    //both ways, as main links its nodes
+    pub fn link(&mut self, a: usize, b: usize) {
+        let to_b = self.inbound[b].clone();
+        let to_a = self.inbound[a].clone();
+        self.nodes[a].connect(b, to_b);
+        self.nodes[b].connect(a, to_a);
+    }
+
+    pub fn clock(&self) -> &clock::MockClock {
+        &self.clock
+    }
+
+    //queues `msg` for node `id`, it's handled by the next deliver_all
+    pub fn send(&self, id: usize, msg: Message) {
+        self.inbound[id].try_send(msg).expect("test node queue full");
+    }
+
+    //what a Mine on node `id` would do, minus the waiting: a block holding its coinbase and
+    //`txs` on its tip, added to its chain and sent to its peers. the error is the node's own
+    //reason for refusing it, nothing is sent then
+    pub fn mine_on(&mut self, id: usize, txs: Vec<Transaction>) -> Result<Block, AddBlockError> {
+        let block = next_block(&self.blockchain(id), &self.nodes[id].wallet_address, txs);
+        self.add_and_send(id, block)
+    }
+
+    //like mine_on with no transactions, the block credited at least 2^zero_bits work, see
+    //next_block_with_work
+    pub fn mine_with_work_on(&mut self, id: usize, zero_bits: u32) -> Result<Block, AddBlockError> {
+        let block = next_block_with_work(&self.blockchain(id), &self.nodes[id].wallet_address, zero_bits);
+        self.add_and_send(id, block)
+    }
+
+    fn add_and_send(&mut self, id: usize, block: Block) -> Result<Block, AddBlockError> {
+        let node = &mut self.nodes[id];
+        let mut bchain = node.blockchain.try_lock().expect("nothing else holds a test node's chain");
+        bchain.add_block(block.clone())?;
+        drop(bchain);
+
+        node.cancel_mining();
+        node.broadcast(Message::NewBlock { from: node.id, block: block.clone() });
+        Ok(block)
+    }
+
+    //hands every queued message to its node, and whatever those send in turn, until no node has
+    //anything left. how many messages were handled
+    pub async fn deliver_all(&mut self) -> usize {
+        let mut handled = 0;
+        loop {
+            let before = handled;
+            for node in &mut self.nodes {
+                while let Ok(msg) = node.receiver.try_recv() {
+                    node.dispatch(msg).await;
+                    handled += 1;
+                }
+            }
+            if handled == before {
+                return handled;
+            }
+            assert!(handled < 100_000, "test network never went quiet");
+        }
+    }
+
+    pub fn node(&mut self, id: usize) -> &mut Node {
+        &mut self.nodes[id]
+    }
+
+    pub fn blockchain(&self, id: usize) -> tokio::sync::MutexGuard<'_, Blockchain> {
+        self.nodes[id].blockchain.try_lock().expect("nothing else holds a test node's chain")
+    }
+
+    pub fn chain_of(&self, id: usize) -> Vec<Block> {
+        self.blockchain(id).chain.load_all()
+    }
+}
+
+//a chain without proof of work whose clock is stopped at 1000 ms, so what's mined on it is the
+//same every run
+pub fn test_chain(genesis: GenesisConfig) -> Blockchain {
+    let mut bchain = Blockchain::new(0, genesis);
+    bchain.clock = Arc::new(clock::MockClock::new(1_000));
+    bchain
+}
+
+//the block `miner` would mine next on `bchain` holding `txs` after a coinbase paying it their
+//fees, stamped like the miner does. not added anywhere
+pub fn next_block(bchain: &Blockchain, miner: &Address, txs: Vec<Transaction>) -> Block {
+    let index = bchain.last_block().header.index + 1;
+    let fees = Blockchain::total_fees(&txs).expect("test fees fit a u64");
+
+    let mut transactions = vec![bchain.coinbase(index, miner, fees)];
+    transactions.extend(txs);
+    block_holding(bchain, transactions)
+}
+
+//like next_block with no transactions, ground on past the chain's target until its hash has at
+//least `zero_bits` leading zero bits. how much work a block is credited is then known up front
+//instead of whatever its hash happens to show
+pub fn next_block_with_work(bchain: &Blockchain, miner: &Address, zero_bits: u32) -> Block {
+    let mut block = next_block(bchain, miner, Vec::new());
+    while Blockchain::block_work(&block.header.hash) < 1 << zero_bits {
+        block.header.nonce += 1;
+        block.header.hash = block.header.recompute_hash(bchain.hasher(), bchain.genesis.chain_id);
+    }
+    block
+}
+
+//like next_block, with `transactions` exactly as given, coinbase or not
+pub fn block_holding(bchain: &Blockchain, transactions: Vec<Transaction>) -> Block {
+    let last = bchain.last_block();
+    let timestamp = bchain.clock.now_millis().max(last.header.timestamp + 1);
+    Block::mine_block(bchain.hasher(), bchain.genesis.chain_id, last.header.index + 1, timestamp, transactions, last.header.hash.clone(), bchain.adjusted_target(), &AtomicBool::new(false), 0, None)
+        .expect("a block is found within the nonce space")
+}
+
+//node `from` paying `amount` to `to`, signed
+pub fn transfer(from: usize, to: &Address, amount: u64, fee: u64, nonce: u64) -> Transaction {
+    let mut tx = Transaction {
+        from: String::new(),
+        outputs: vec![(to.clone(), amount)],
+        fee,
+        nonce,
+        signature: Vec::new(),
+        public_key: Vec::new(),
+    };
+    tx.sign(&node_keypair(from));
+    tx
+}
@@ -0,0 +1,2151 @@
+use super::*;
+use crate::test_support::*;
+
+//polls until the chain reaches `height`, a node adds its blocks on a task of its own
+async fn wait_for_height(blockchain: &Mutex<Blockchain>, height: u64) {
+    for _ in 0..2000 {
+        if blockchain.lock().await.last_block().header.index >= height {
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+    }
+    panic!("chain never reached height {}", height);
+}
+
+//what node 0 mines on the default network at difficulty 2 with its clock stopped at 1000 ms.
+//they only change along with the block format, the hash preimage or how coinbases are built
+const GOLDEN_HASHES: [&str; 3] = [
+    "001501bbf80aa5426722fa264e79dc201da355122d4dee2a0533852daa494d01",
+    "001ffd922161f56c7c0c2ab7d8acc759db754b42016c4c6d86271cf62a12efc4",
+    "00b5b0c924f4fa5bd85ee9172eb5de23b29377070dde76ddf30961426b2e2c65",
+];
+
+//runs the node on a task of its own until the returned sender fires or is dropped
+fn start(node: Node) -> (Arc<Mutex<Blockchain>>, broadcast::Sender<()>, tokio::task::JoinHandle<()>) {
+    let blockchain = node.blockchain.clone();
+    let (shutdown, _) = broadcast::channel(1);
+    let running = tokio::spawn(node.run(shutdown.subscribe()));
+    (blockchain, shutdown, running)
+}
+
+#[tokio::test]
+async fn mock_clock_mines_the_same_hashes_every_run() {
+    let (node, inbound) = Node::new(0, 2, GenesisConfig::default());
+    node.blockchain.lock().await.clock = Arc::new(clock::MockClock::new(1_000));
+    let (blockchain, shutdown, running) = start(node);
+
+    for height in 1..=3 {
+        inbound.send(Message::Mine).await.unwrap();
+        wait_for_height(&blockchain, height).await;
+        //reading the clock in between must not move the next block's timestamp
+        blockchain.lock().await.health(0);
+    }
+    let _ = shutdown.send(());
+    running.await.unwrap();
+
+    let bchain = blockchain.lock().await;
+    let mined = &bchain.chain.blocks()[1..];
+    assert_eq!(mined.iter().map(|block| block.header.timestamp).collect::<Vec<_>>(), [1_000, 1_001, 1_002]);
+    assert_eq!(mined.iter().map(|block| block.header.hash.as_str()).collect::<Vec<_>>(), GOLDEN_HASHES);
+}
+
+#[test]
+fn difficulty_bounds_are_checked_in_the_bits_a_hash_needs() {
+    assert!(check_difficulty_bounds(4, 1, 16).is_ok());
+    assert_eq!(
+        check_difficulty_bounds(4, 1, 15),
+        Err(String::from("--difficulty 4 is 16 bits, outside --min-difficulty 1 and --max-difficulty 15")),
+    );
+    assert!(check_difficulty_bounds(4, 17, 40).is_err());
+    //no work at all is never held to the bounds
+    assert!(check_difficulty_bounds(0, 1, 40).is_ok());
+}
+
+fn stakes(validators: &[(&str, u64)]) -> Vec<(String, u64)> {
+    validators.iter().map(|&(address, stake)| (address.to_string(), stake)).collect()
+}
+
+#[test]
+fn the_same_parent_hash_always_picks_the_same_validator() {
+    let validators = stakes(&[("alice", 3), ("bob", 5), ("carol", 2)]);
+    let a = Blockchain::with_consensus(ConsensusMode::ProofOfStake { validators: validators.clone() }, GenesisConfig::default());
+    let b = Blockchain::with_consensus(ConsensusMode::ProofOfStake { validators: validators.clone() }, GenesisConfig::default());
+
+    for seed in [String::new(), a.last_block().header.hash.clone(), "ab".repeat(32)] {
+        let pick = select_validator(&validators, &seed);
+        assert!(pick.is_some());
+        assert_eq!(select_validator(&validators, &seed), pick);
+        assert_eq!(a.expected_validator(&seed), b.expected_validator(&seed));
+        assert_eq!(a.expected_validator(&seed).as_deref(), pick);
+    }
+}
+
+#[test]
+fn stake_weighting_skews_selection() {
+    let validators = stakes(&[("small", 1), ("large", 9), ("none", 0)]);
+    let picks: Vec<&str> = (0..1000).filter_map(|seed| select_validator(&validators, &seed.to_string())).collect();
+
+    let large = picks.iter().filter(|&&pick| pick == "large").count();
+    assert_eq!(picks.len(), 1000);
+    assert!((850..=950).contains(&large), "large was picked {} times out of 1000", large);
+    assert!(!picks.contains(&"none"));
+    assert_eq!(select_validator(&stakes(&[("none", 0)]), "seed"), None);
+}
+
+#[tokio::test]
+async fn only_the_picked_validator_forges() {
+    let validators = vec![(node_address(0).to_string(), 1)];
+    let mut chains = Vec::new();
+    //the nodes stop once these are dropped
+    let mut handles = Vec::new();
+    for id in 0..2 {
+        let (node, inbound) = Node::new(id, 0, GenesisConfig::default());
+        node.blockchain.lock().await.consensus = ConsensusMode::ProofOfStake { validators: validators.clone() };
+        inbound.send(Message::Mine).await.unwrap();
+        let (blockchain, shutdown, _) = start(node);
+        chains.push(blockchain);
+        handles.push((shutdown, inbound));
+    }
+
+    wait_for_height(&chains[0], 1).await;
+    assert_eq!(chains[0].lock().await.last_block().header.validator, node_address(0).to_string());
+
+    //given time to forge, node 1 would have by now
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    assert_eq!(chains[1].lock().await.chain.len(), 1);
+}
+
+#[tokio::test]
+async fn nodes_converge_on_one_chain() {
+    let mut network = TestNetwork::new(3);
+    for round in 0..6 {
+        network.mine_on(round % 3, Vec::new()).unwrap();
+        network.clock().advance(1_000);
+        network.deliver_all().await;
+    }
+
+    let chain = network.chain_of(0);
+    assert_eq!(chain.len(), 7);
+    for id in 1..3 {
+        assert_eq!(network.chain_of(id), chain);
+    }
+}
+
+#[tokio::test]
+async fn a_transaction_reaches_every_chain() {
+    let mut network = TestNetwork::new(3);
+    let tx = transfer(0, &node_address(2), 10, 1, 0);
+    network.send(1, Message::Tx { tx: tx.clone(), hops_left: TX_HOPS });
+    network.deliver_all().await;
+    for id in 0..3 {
+        assert!(network.blockchain(id).mempool.contains(&tx));
+    }
+
+    network.mine_on(2, Vec::new()).unwrap();
+    network.deliver_all().await;
+    let txs = network.blockchain(0).select_transactions(&node_address(0)).split_off(1);
+    network.mine_on(0, txs).unwrap();
+    network.deliver_all().await;
+
+    for id in 0..3 {
+        let bchain = network.blockchain(id);
+        assert!(bchain.mempool.is_empty());
+        assert_eq!(bchain.balance_of(&node_address(2)), (ALLOCATION + 10 + bchain.reward_at_height(1)) as i64);
+    }
+}
+
+#[tokio::test]
+async fn a_partition_heals_onto_the_heavier_side() {
+    let mut network = TestNetwork::unlinked(3);
+    network.link(1, 2);
+    //node 0 alone mines fewer blocks but puts 2^12 work at least into each
+    for _ in 0..2 {
+        network.mine_with_work_on(0, 12).unwrap();
+    }
+    for _ in 0..5 {
+        network.mine_on(1, Vec::new()).unwrap();
+        network.deliver_all().await;
+    }
+    let heavy = network.chain_of(0);
+    let light = network.chain_of(1);
+    assert_eq!(network.chain_of(2), light);
+    assert!(Blockchain::total_work(&heavy) >= 2 * (1 << 12));
+    assert!(Blockchain::total_work(&light) < 1 << 12, "the longer side got lucky");
+
+    network.link(0, 1);
+    network.link(0, 2);
+    for id in 0..3 {
+        network.send(id, Message::Resync);
+    }
+    network.deliver_all().await;
+
+    let tip = &heavy[2].header.hash;
+    for id in 0..3 {
+        assert_eq!(&network.blockchain(id).last_block().header.hash, tip);
+        assert_eq!(network.chain_of(id), heavy);
+    }
+    assert_ne!(light[1], heavy[1]);
+}
+
+fn extend(bchain: &mut Blockchain, miner: usize, blocks: usize) {
+    for _ in 0..blocks {
+        let block = next_block(bchain, &node_address(miner), Vec::new());
+        bchain.add_block(block).unwrap();
+    }
+}
+
+#[test]
+fn a_locator_thins_out_towards_genesis() {
+    let mut bchain = test_chain(GenesisConfig::default());
+    extend(&mut bchain, 0, 99);
+
+    let heights: Vec<u64> = bchain.locator().iter().map(|hash| bchain.block_by_hash(hash).unwrap().header.index).collect();
+    assert_eq!(heights, [99, 98, 97, 96, 95, 94, 93, 92, 91, 90, 88, 84, 76, 60, 28, 0]);
+}
+
+#[test]
+fn the_fork_is_the_highest_block_both_chains_hold() {
+    let mut ours = test_chain(GenesisConfig::default());
+    let mut theirs = test_chain(GenesisConfig::default());
+    for _ in 0..30 {
+        let block = next_block(&ours, &node_address(0), Vec::new());
+        ours.add_block(block.clone()).unwrap();
+        theirs.add_block(block).unwrap();
+    }
+    extend(&mut ours, 0, 2);
+    extend(&mut theirs, 1, 20);
+
+    assert_eq!(theirs.find_fork(&ours.locator()), Some(30));
+    //theirs is longer and its locator skips from 35 to 27, a fork found that way is never
+    //above the real one
+    assert_eq!(ours.find_fork(&theirs.locator()), Some(27));
+    let headers: Vec<BlockHeader> = theirs.iter().map(|block| block.header.clone()).collect();
+    assert_eq!(ours.common_prefix_len(&headers), 31);
+    let other_network = test_chain(GenesisConfig { chain_id: 1, ..GenesisConfig::default() });
+    assert_eq!(ours.find_fork(&other_network.locator()), None);
+}
+
+#[tokio::test]
+async fn an_empty_locator_is_answered_from_genesis() {
+    let mut network = TestNetwork::unlinked(2);
+    for _ in 0..3 {
+        network.mine_on(0, Vec::new()).unwrap();
+    }
+    network.link(0, 1);
+
+    network.send(0, Message::RequestChain { requester: 1, locator: Vec::new() });
+    network.deliver_all().await;
+    assert_eq!(network.chain_of(1), network.chain_of(0));
+}
+
+#[tokio::test]
+async fn transactions_are_found_through_the_index_until_a_reorg_drops_them() {
+    let mut network = TestNetwork::unlinked(2);
+    let tx = transfer(0, &node_address(1), 10, 1, 0);
+    let block = network.mine_on(0, vec![tx.clone()]).unwrap();
+
+    {
+        let bchain = network.blockchain(0);
+        assert_eq!(bchain.find_transaction(&tx.hash()), Some((1, &tx)));
+        assert_eq!(bchain.find_transaction(&block.body.transactions[0].hash()), Some((1, &block.body.transactions[0])));
+        assert_eq!(bchain.find_transaction(&transfer(0, &node_address(1), 10, 1, 1).hash()), None);
+        assert_eq!(bchain.check_pending(&tx), Err(MempoolError::Known));
+    }
+
+    for _ in 0..10 {
+        network.mine_on(1, Vec::new()).unwrap();
+    }
+    assert!(Blockchain::total_work(&network.chain_of(1)) > Blockchain::total_work(&network.chain_of(0)));
+    network.link(0, 1);
+    network.send(0, Message::Resync);
+    network.deliver_all().await;
+
+    let bchain = network.blockchain(0);
+    assert_eq!(bchain.chain.len(), 11);
+    assert_eq!(bchain.find_transaction(&tx.hash()), None);
+    assert_eq!(bchain.tx_proof(&tx.hash()), None);
+    //back to pending, it can go into a block on the new branch
+    assert_eq!(bchain.mempool, vec![tx.clone()]);
+    assert_eq!(bchain.check_pending(&tx), Err(MempoolError::Known));
+}
+
+//a fresh chain on each kind of store, named for failure messages
+fn on_every_store(test: &str) -> Vec<(&'static str, Blockchain)> {
+    let path = std::env::temp_dir().join(format!("smblockchain-{}-{}.jsonl", test, std::process::id()));
+    let _ = fs::remove_file(&path);
+
+    let mut on_file = test_chain(GenesisConfig::default());
+    on_file.chain = Box::new(chain_store::FileStore::open(path, on_file.chain.tip().clone()));
+    on_file.reindex();
+    vec![("memory", test_chain(GenesisConfig::default())), ("file", on_file)]
+}
+
+#[test]
+fn every_store_appends_and_reorganizes_the_same() {
+    let mut heavier = test_chain(GenesisConfig::default());
+    extend(&mut heavier, 1, 20);
+
+    for (store, mut bchain) in on_every_store("reorg") {
+        extend(&mut bchain, 0, 2);
+        assert_eq!(bchain.chain.len(), 3, "{}", store);
+        assert!(bchain.find_transaction(&bchain.chain.get(2).unwrap().body.transactions[0].hash()).is_some(), "{}", store);
+
+        //at difficulty 0 a block's work is whatever its hash happens to show, these are fixed
+        //by the stopped clock
+        assert!(Blockchain::total_work(heavier.chain.blocks()) > bchain.work, "{}", store);
+        assert_eq!(bchain.reorganize(heavier.chain.load_all()), ReorgResult::Reorganized { removed: 2, added: 20 }, "{}", store);
+        assert_eq!(bchain.chain.blocks(), heavier.chain.blocks(), "{}", store);
+        assert_eq!(bchain.balance_of(&node_address(0)), 0, "{}", store);
+    }
+}
+
+#[test]
+fn a_file_store_reloads_what_was_written_through() {
+    let path = std::env::temp_dir().join(format!("smblockchain-reload-{}.jsonl", std::process::id()));
+    let _ = fs::remove_file(&path);
+    let mut bchain = test_chain(GenesisConfig::default());
+    let genesis = bchain.chain.tip().clone();
+    bchain.chain = Box::new(chain_store::FileStore::open(path.clone(), genesis.clone()));
+    extend(&mut bchain, 0, 5);
+    bchain.chain.truncate(4);
+
+    let reopened = chain_store::FileStore::open(path.clone(), genesis);
+    assert_eq!(reopened.blocks(), bchain.chain.blocks());
+    assert_eq!(reopened.len(), 4);
+}
+
+#[test]
+fn a_mined_chain_validates_after_a_round_trip_until_a_byte_changes() {
+    let mut bchain = Blockchain::new(2, GenesisConfig::default());
+    bchain.clock = Arc::new(clock::MockClock::new(1_000));
+    extend(&mut bchain, 0, 3);
+
+    let json = serde_json::to_string(bchain.chain.blocks()).unwrap();
+    let mut chain: Vec<Block> = serde_json::from_str(&json).unwrap();
+    assert_eq!(bchain.validate_chain(&chain, &bchain.genesis), Ok(()));
+
+    chain[2].body.transactions[0].nonce ^= 1;
+    assert_eq!(bchain.validate_chain(&chain, &bchain.genesis), Err(ChainError::Block { index: 2, error: AddBlockError::MerkleRootMismatch }));
+}
+
+#[test]
+fn mining_gives_up_once_the_nonce_would_overflow() {
+    let root = merkle_root(&[]);
+    //no hash is below a zero target, only running out of nonces ends the search
+    let searched = Block::grind_nonces(&Sha256Hasher, 0, 1, 1_000, &root, "parent", Target([0; 32]), u64::MAX - 100, 1, &[&AtomicBool::new(false)], 0, None);
+    assert!(matches!(searched, Err(MineError::NonceExhausted)));
+}
+
+//headers only, `spacing_ms` apart. enough for next_target, which looks at nothing else
+fn spaced_headers(count: usize, spacing_ms: u128) -> Vec<BlockHeader> {
+    (0..count)
+        .map(|i| BlockHeader {
+            index: i as u64,
+            timestamp: i as u128 * spacing_ms,
+            merkle_root: String::new(),
+            previous_hash: String::new(),
+            hash: String::new(),
+            nonce: 0,
+            validator: String::new(),
+            target: None,
+        })
+        .collect()
+}
+
+//the target after the first retarget, with blocks `spacing_ms` apart on a chain aiming for one
+//a second every 10 blocks
+fn retargeted(bchain: &Blockchain, spacing_ms: u128) -> Target {
+    let headers = spaced_headers(21, spacing_ms);
+    bchain.next_target(&headers, bchain.base_target())
+}
+
+#[test]
+fn blocks_coming_fast_raise_the_difficulty_and_slow_ones_lower_it() {
+    let bchain = Blockchain::new(2, GenesisConfig::default());
+    assert_eq!(bchain.base_target().leading_zero_bits(), 8);
+
+    assert_eq!(retargeted(&bchain, 400).leading_zero_bits(), 9);
+    assert_eq!(retargeted(&bchain, 1_000), bchain.base_target());
+    assert_eq!(retargeted(&bchain, 2_500).leading_zero_bits(), 7);
+
+    //only every 10th block retargets
+    let headers = spaced_headers(20, 400);
+    assert_eq!(bchain.next_target(&headers, bchain.base_target()), bchain.base_target());
+}
+
+#[test]
+fn the_order_of_a_blocks_transactions_is_part_of_its_hash() {
+    let txs = vec![transfer(0, &node_address(1), 5, 1, 0), transfer(1, &node_address(0), 7, 1, 0)];
+    let block = |txs: Vec<Transaction>| Block::new_block(&Sha256Hasher, 0, 1, 1_000, txs, String::from("parent"), 0);
+
+    assert_eq!(block(txs.clone()).header.hash, block(txs.clone()).header.hash);
+    let reversed: Vec<Transaction> = txs.iter().rev().cloned().collect();
+    assert_ne!(block(reversed).header.hash, block(txs).header.hash);
+}
+
+#[test]
+fn tampering_with_any_transaction_changes_the_merkle_root_and_the_hash() {
+    let txs: Vec<Transaction> = (0..3).map(|nonce| transfer(0, &node_address(1), 5, 1, nonce)).collect();
+    let block = Block::new_block(&Sha256Hasher, 0, 1, 1_000, txs, String::from("parent"), 0);
+    assert!(block.verify_merkle_root());
+
+    for i in 0..3 {
+        let mut tampered = block.clone();
+        tampered.body.transactions[i].outputs[0].1 += 1;
+        assert!(!tampered.verify_merkle_root());
+
+        let rebuilt = Block::new_block(&Sha256Hasher, 0, 1, 1_000, tampered.body.transactions, String::from("parent"), 0);
+        assert_ne!(rebuilt.header.merkle_root, block.header.merkle_root);
+        assert_ne!(rebuilt.header.hash, block.header.hash);
+    }
+}
+
+#[test]
+fn a_saved_chain_loads_back_block_for_block() {
+    let path = std::env::temp_dir().join(format!("smblockchain-saved-{}.json", std::process::id()));
+    let mut bchain = test_chain(GenesisConfig::default());
+    extend(&mut bchain, 0, 3);
+
+    bchain.save_to_file(&path).unwrap();
+    let loaded = Blockchain::load_from_file(&path);
+    let _ = fs::remove_file(&path);
+    let loaded = loaded.unwrap();
+    assert_eq!(loaded.chain.blocks(), bchain.chain.blocks());
+    assert_eq!(loaded.work, bchain.work);
+}
+
+//the next block node 0 would mine, stamped `timestamp` instead of by the clock
+fn stamped(bchain: &Blockchain, timestamp: u128) -> Block {
+    let last = bchain.last_block();
+    let index = last.header.index + 1;
+    Block::mine_block(bchain.hasher(), 0, index, timestamp, vec![bchain.coinbase(index, &node_address(0), 0)], last.header.hash.clone(), bchain.adjusted_target(), &AtomicBool::new(false), 0, None).unwrap()
+}
+
+#[test]
+fn a_block_has_to_come_after_its_parent_and_not_too_far_ahead() {
+    let mut bchain = test_chain(GenesisConfig::default());
+    extend(&mut bchain, 0, 1);
+    let parent = bchain.last_block().header.timestamp;
+    assert_eq!(parent, 1_000);
+
+    assert_eq!(bchain.add_block(stamped(&bchain, parent)), Err(AddBlockError::TimestampNotAfterParent));
+    assert_eq!(bchain.add_block(stamped(&bchain, parent - 1)), Err(AddBlockError::TimestampNotAfterParent));
+    assert_eq!(bchain.add_block(stamped(&bchain, 1_000 + bchain.max_future_drift_ms + 1)), Err(AddBlockError::TimestampTooFarAhead));
+    assert_eq!(bchain.add_block(stamped(&bchain, 1_000 + bchain.max_future_drift_ms)), Ok(()));
+}
+
+#[test]
+fn a_chain_on_a_tampered_genesis_block_fails_validation() {
+    let mut bchain = test_chain(GenesisConfig::default());
+    extend(&mut bchain, 0, 2);
+    let mut chain = bchain.chain.load_all();
+
+    chain[0] = Block::genesis_block(bchain.hasher(), &GenesisConfig { data: String::from("Hi there"), ..GenesisConfig::default() });
+    assert_eq!(bchain.validate_chain(&chain, &bchain.genesis), Err(ChainError::GenesisMismatch));
+    //the header alone gives it away
+    assert_eq!(bchain.validate_header_chain(&chain[..1], &bchain.genesis), Err(ChainError::GenesisMismatch));
+}
+
+#[test]
+fn a_cancelled_miner_stops_without_a_block() {
+    let cancel = AtomicBool::new(false);
+    let started = std::time::Instant::now();
+    let mined = std::thread::scope(|scope| {
+        let miner = scope.spawn(|| Block::mine_block(&Sha256Hasher, 0, 1, 1_000, Vec::new(), String::from("parent"), Target([0; 32]), &cancel, 0, None));
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        cancel.store(true, Ordering::Relaxed);
+        miner.join().unwrap()
+    });
+
+    assert!(matches!(mined, Err(MineError::Cancelled)));
+    assert!(started.elapsed() < std::time::Duration::from_secs(5));
+}
+
+#[tokio::test]
+async fn every_node_passes_a_new_block_on_at_most_once() {
+    let mut network = TestNetwork::new(3);
+    network.mine_on(0, Vec::new()).unwrap();
+
+    //the miner and each of the other two send it to their two peers, once
+    assert_eq!(network.deliver_all().await, 6);
+    for id in 0..3 {
+        assert_eq!(network.chain_of(id).len(), 2);
+    }
+}
+
+//a chain whose genesis block gives node 0 `amount`
+fn funded(amount: u64) -> Blockchain {
+    test_chain(GenesisConfig { allocations: vec![(node_address(0), amount)], ..GenesisConfig::default() })
+}
+
+//mines `txs` into the next block on `bchain`, the reward going to node 9 so it stays out of
+//the balances tests look at
+fn mine_txs(bchain: &mut Blockchain, txs: Vec<Transaction>) -> Result<(), AddBlockError> {
+    let block = next_block(bchain, &node_address(9), txs);
+    bchain.add_block(block)
+}
+
+#[test]
+fn transfers_move_balances_and_never_overdraw() {
+    let mut bchain = funded(100);
+    mine_txs(&mut bchain, vec![transfer(0, &node_address(1), 30, 0, 0)]).unwrap();
+    assert_eq!(bchain.balance_of(&node_address(0)), 70);
+    assert_eq!(bchain.balance_of(&node_address(1)), 30);
+
+    assert_eq!(mine_txs(&mut bchain, vec![transfer(0, &node_address(1), 71, 0, 1)]), Err(AddBlockError::Overspend { tx_index: 1 }));
+    assert_eq!(bchain.check_pending(&transfer(0, &node_address(1), 71, 0, 1)), Err(MempoolError::Overspend));
+
+    mine_txs(&mut bchain, vec![transfer(0, &node_address(1), 20, 0, 1)]).unwrap();
+    mine_txs(&mut bchain, vec![transfer(1, &node_address(2), 50, 0, 0)]).unwrap();
+    assert_eq!(bchain.balance_of(&node_address(0)), 50);
+    assert_eq!(bchain.balance_of(&node_address(1)), 0);
+    assert_eq!(bchain.balance_of(&node_address(2)), 50);
+    assert_eq!(bchain.balance_of(&node_address(9)), 3 * bchain.block_reward as i64);
+}
+
+#[test]
+fn the_mempool_holds_transactions_until_a_block_includes_them() {
+    let mut bchain = funded(100);
+    let first = transfer(0, &node_address(1), 10, 1, 0);
+    let second = transfer(0, &node_address(2), 10, 1, 1);
+    assert!(bchain.add_to_mempool(first.clone()));
+    assert!(bchain.add_to_mempool(second.clone()));
+    assert!(!bchain.add_to_mempool(first.clone()));
+    assert_eq!(bchain.mempool, vec![first.clone(), second.clone()]);
+
+    mine_txs(&mut bchain, vec![first]).unwrap();
+    assert_eq!(bchain.mempool, vec![second.clone()]);
+    let txs = bchain.select_transactions(&node_address(9)).split_off(1);
+    mine_txs(&mut bchain, txs).unwrap();
+    assert!(bchain.mempool.is_empty());
+}
+
+#[test]
+fn every_block_starts_with_one_coinbase_paying_the_halving_reward() {
+    let mut bchain = test_chain(GenesisConfig::default());
+    extend(&mut bchain, 0, 1);
+    let coinbase = &bchain.last_block().body.transactions[0];
+    assert_eq!(coinbase.from, COINBASE);
+    assert_eq!(coinbase.outputs, vec![(node_address(0), 50)]);
+
+    let coinbase = bchain.coinbase(2, &node_address(0), 0);
+    assert_eq!(bchain.add_block(block_holding(&bchain, Vec::new())), Err(AddBlockError::InvalidCoinbase));
+    assert_eq!(bchain.add_block(block_holding(&bchain, vec![coinbase.clone(), coinbase.clone()])), Err(AddBlockError::InvalidCoinbase));
+    let greedy = bchain.coinbase(2, &node_address(0), 1);
+    assert_eq!(bchain.add_block(block_holding(&bchain, vec![greedy])), Err(AddBlockError::InvalidCoinbase));
+    assert_eq!(bchain.add_block(block_holding(&bchain, vec![coinbase])), Ok(()));
+
+    assert_eq!(bchain.reward_at_height(99), 50);
+    assert_eq!(bchain.reward_at_height(100), 25);
+    assert_eq!(bchain.reward_at_height(250), 12);
+    assert_eq!(bchain.reward_at_height(64 * 100), 0);
+    bchain.halving_interval = 0;
+    assert_eq!(bchain.reward_at_height(64 * 100), 50);
+}
+
+#[test]
+fn each_hash_algorithm_hashes_the_same_block_differently() {
+    let hashes: Vec<String> = [HashAlgorithm::Sha256, HashAlgorithm::DoubleSha256, HashAlgorithm::Sha512].iter()
+        .map(|algorithm| Block::new_block(algorithm.hasher(), 0, 1, 1_000, Vec::new(), String::from("parent"), 0).header.hash)
+        .collect();
+    assert_ne!(hashes[0], hashes[1]);
+    assert_ne!(hashes[0], hashes[2]);
+    assert_ne!(hashes[1], hashes[2]);
+    assert_eq!(hashes[2].len(), 128);
+
+    //a chain only validates under the hasher it was built with
+    let mut sha256 = test_chain(GenesisConfig::default());
+    extend(&mut sha256, 0, 1);
+    let double = Blockchain::with_hasher(sha256.consensus.clone(), HashAlgorithm::DoubleSha256, GenesisConfig::default());
+    assert_eq!(double.validate_chain(sha256.chain.blocks(), &sha256.genesis), Err(ChainError::GenesisMismatch));
+}
+
+#[test]
+fn only_a_transaction_signed_by_its_sender_gets_in() {
+    let mut bchain = funded(100);
+    let tx = transfer(0, &node_address(1), 10, 0, 0);
+    assert!(tx.verify());
+
+    let mut tampered = tx.clone();
+    tampered.outputs[0].1 = 90;
+    assert!(!tampered.verify());
+    assert_eq!(bchain.check_pending(&tampered), Err(MempoolError::InvalidSignature));
+    assert_eq!(mine_txs(&mut bchain, vec![tampered]), Err(AddBlockError::InvalidSignature));
+
+    //signed by node 1 but claiming to come from node 0
+    let mut wrong_key = transfer(1, &node_address(1), 10, 0, 0);
+    wrong_key.from = tx.from.clone();
+    assert!(!wrong_key.verify());
+    assert_eq!(mine_txs(&mut bchain, vec![wrong_key]), Err(AddBlockError::InvalidSignature));
+
+    assert_eq!(mine_txs(&mut bchain, vec![tx]), Ok(()));
+}
+
+#[test]
+fn a_block_ahead_of_its_parent_waits_for_it() {
+    let mut source = test_chain(GenesisConfig::default());
+    extend(&mut source, 0, 2);
+    let blocks = source.chain.load_all();
+
+    let mut bchain = test_chain(GenesisConfig::default());
+    assert_eq!(bchain.add_block(blocks[2].clone()), Err(AddBlockError::Orphan));
+    assert_eq!(bchain.chain.len(), 1);
+    assert_eq!(bchain.add_block(blocks[1].clone()), Ok(()));
+    assert_eq!(bchain.chain.blocks(), blocks.as_slice());
+    assert!(bchain.orphan_pool.is_empty());
+}
+
+//a chain holding the first `keep` blocks of `bchain`, genesis included
+fn fork_of(bchain: &Blockchain, keep: usize) -> Blockchain {
+    let mut fork = test_chain(bchain.genesis.clone());
+    for block in &bchain.chain.blocks()[1..keep] {
+        fork.add_block(block.clone()).unwrap();
+    }
+    fork
+}
+
+#[test]
+fn a_reorg_rolls_back_to_the_fork_and_applies_the_heavier_branch() {
+    for depth in [1, 3, 5] {
+        let mut ours = test_chain(GenesisConfig::default());
+        extend(&mut ours, 0, 5);
+        let mut theirs = fork_of(&ours, 6 - depth);
+        //the work a block at difficulty 0 gets credited with is luck, if fixed luck with the
+        //stopped clock. 30 blocks are enough to outweigh 5 here
+        extend(&mut theirs, 1, 30);
+        assert!(theirs.work > ours.work, "depth {}", depth);
+
+        assert_eq!(ours.reorganize(theirs.chain.load_all()), ReorgResult::Reorganized { removed: depth, added: 30 }, "depth {}", depth);
+        assert_eq!(ours.chain.blocks(), theirs.chain.blocks());
+        assert_eq!(ours.work, theirs.work);
+    }
+
+    let mut ours = test_chain(GenesisConfig::default());
+    extend(&mut ours, 0, 2);
+    let mut elsewhere = test_chain(GenesisConfig { chain_id: 7, ..GenesisConfig::default() });
+    extend(&mut elsewhere, 1, 20);
+    let before = ours.chain.load_all();
+    assert_eq!(ours.reorganize(elsewhere.chain.load_all()), ReorgResult::NoCommonAncestor);
+    assert_eq!(ours.reorganize(fork_of(&ours, 2).chain.load_all()), ReorgResult::Rejected);
+    assert_eq!(ours.chain.blocks(), before.as_slice());
+}
+
+#[test]
+fn a_block_may_fill_the_payload_limit_but_not_pass_it() {
+    let mut bchain = test_chain(GenesisConfig::default());
+    let block = next_block(&bchain, &node_address(0), Vec::new());
+    let size = Block::payload_size(&block.body.transactions);
+
+    bchain.max_block_bytes = size - 1;
+    assert_eq!(bchain.add_block(block.clone()), Err(AddBlockError::TooLarge { size, max: size - 1 }));
+    bchain.max_block_bytes = size;
+    assert_eq!(bchain.add_block(block), Ok(()));
+}
+
+#[test]
+fn moving_bytes_between_hash_fields_changes_the_hash() {
+    //"ab" + "c" and "a" + "bc" read the same once concatenated
+    let hash = |merkle_root: &str, previous_hash: &str| Block::compute_hash(&Sha256Hasher, 0, 1, 1_000, merkle_root, previous_hash, 0, "", None);
+    assert_ne!(hash("ab", "c"), hash("a", "bc"));
+    assert_ne!(Sha256Hasher.hash(&[b"ab", b"c"]), Sha256Hasher.hash(&[b"a", b"bc"]));
+}
+
+#[test]
+fn blocks_are_found_by_hash_until_a_reorg_drops_them() {
+    let mut ours = test_chain(GenesisConfig::default());
+    extend(&mut ours, 0, 2);
+    let dropped = ours.last_block().clone();
+    assert_eq!(ours.block_by_hash(&dropped.header.hash), Some(&dropped));
+    assert_eq!(ours.block_by_hash(&"0".repeat(64)), None);
+
+    let mut theirs = fork_of(&ours, 2);
+    extend(&mut theirs, 1, 30);
+    assert!(matches!(ours.reorganize(theirs.chain.load_all()), ReorgResult::Reorganized { .. }));
+    assert_eq!(ours.block_by_hash(&dropped.header.hash), None);
+    for block in theirs.iter() {
+        assert_eq!(ours.block_by_hash(&block.header.hash), Some(block));
+    }
+}
+
+#[test]
+fn parallel_and_serial_mining_both_find_valid_blocks() {
+    let bchain = Blockchain::new(2, GenesisConfig::default());
+    let last = bchain.last_block();
+    let txs = vec![bchain.coinbase(1, &node_address(0), 0)];
+    let cancel = AtomicBool::new(false);
+
+    let serial = Block::mine_block(bchain.hasher(), 0, 1, 1_000, txs.clone(), last.header.hash.clone(), bchain.adjusted_target(), &cancel, 0, None).unwrap();
+    let parallel = Block::mine_block_parallel(bchain.hasher(), 0, 1, 1_000, txs, last.header.hash.clone(), bchain.adjusted_target(), &cancel, 4, 0, None).unwrap();
+    for block in [serial, parallel] {
+        let chain = [last.clone(), block];
+        assert_eq!(bchain.validate_chain(&chain, &bchain.genesis), Ok(()));
+    }
+}
+
+#[tokio::test]
+async fn a_shutdown_signal_stops_a_node() {
+    let (node, _inbound) = Node::new(0, 0, GenesisConfig::default());
+    let (_, shutdown, running) = start(node);
+    shutdown.send(()).unwrap();
+    tokio::time::timeout(std::time::Duration::from_secs(5), running).await.expect("node kept running").unwrap();
+}
+
+#[test]
+fn the_shared_prefix_of_a_peers_headers_ends_at_the_fork() {
+    let mut ours = test_chain(GenesisConfig::default());
+    extend(&mut ours, 0, 4);
+    let headers = |bchain: &Blockchain| bchain.headers_since(0);
+
+    assert_eq!(ours.common_prefix_len(&headers(&ours)), 5);
+    let mut theirs = fork_of(&ours, 3);
+    extend(&mut theirs, 1, 4);
+    assert_eq!(ours.common_prefix_len(&headers(&theirs)), 3);
+    assert_eq!(ours.common_prefix_len(&headers(&fork_of(&ours, 2))), 2);
+    assert_eq!(ours.common_prefix_len(&headers(&test_chain(GenesisConfig { chain_id: 3, ..GenesisConfig::default() }))), 0);
+
+    //headers sent from past the fork need nothing below it
+    assert_eq!(theirs.headers_since(3), headers(&theirs)[3..]);
+    assert_eq!(theirs.headers_since(99), Vec::new());
+}
+
+#[test]
+fn a_shorter_chain_with_more_work_beats_a_longer_one() {
+    //3 blocks of 2^10 work at least outweigh 20 ordinary ones
+    let mut heavy = test_chain(GenesisConfig::default());
+    for _ in 0..3 {
+        let block = next_block_with_work(&heavy, &node_address(0), 10);
+        heavy.add_block(block).unwrap();
+    }
+    let mut light = test_chain(GenesisConfig::default());
+    extend(&mut light, 1, 20);
+    assert!(heavy.work > light.work);
+    assert_eq!(heavy.work, Blockchain::total_work(heavy.chain.blocks()));
+
+    assert!(!heavy.outweighs(light.work, light.chain.len(), &light.last_block().header.hash));
+    assert!(light.outweighs(heavy.work, heavy.chain.len(), &heavy.last_block().header.hash));
+    assert_eq!(heavy.reorganize(light.chain.load_all()), ReorgResult::Rejected);
+    assert_eq!(light.reorganize(heavy.chain.load_all()), ReorgResult::Reorganized { removed: 20, added: 3 });
+}
+
+#[test]
+fn networks_with_different_chain_ids_reject_each_others_blocks() {
+    let mut ours = test_chain(GenesisConfig::default());
+    let mut theirs = test_chain(GenesisConfig { chain_id: 1, ..GenesisConfig::default() });
+    extend(&mut theirs, 1, 1);
+    assert_ne!(ours.chain.tip().header.hash, theirs.chain.get(0).unwrap().header.hash);
+
+    assert_eq!(ours.add_block(theirs.last_block().clone()), Err(AddBlockError::PreviousHashMismatch));
+    assert_eq!(ours.validate_chain(theirs.chain.blocks(), &ours.genesis), Err(ChainError::GenesisMismatch));
+
+    //even built on our genesis block, a block hashed for network 1 doesn't check out here
+    let parent = ours.last_block().header.hash.clone();
+    let foreign = Block::mine_block(ours.hasher(), 1, 1, 1_000, vec![ours.coinbase(1, &node_address(1), 0)], parent, Target::MAX, &AtomicBool::new(false), 0, None).unwrap();
+    assert_eq!(ours.add_block(foreign), Err(AddBlockError::HashMismatch));
+}
+
+#[test]
+fn a_senders_nonces_count_up_from_zero_and_never_repeat() {
+    let mut bchain = funded(100);
+    let pay = |nonce| transfer(0, &node_address(1), 1, 0, nonce);
+
+    assert_eq!(mine_txs(&mut bchain, vec![pay(1)]), Err(AddBlockError::InvalidNonce));
+    assert_eq!(bchain.check_pending(&pay(1)), Err(MempoolError::InvalidNonce));
+    assert_eq!(mine_txs(&mut bchain, vec![pay(0), pay(1), pay(2)]), Ok(()));
+    assert_eq!(bchain.account_nonce(&pay(0).from), 3);
+    assert_eq!(bchain.next_nonce(&pay(0).from), 3);
+
+    //the same signed transfer again, and a fresh one reusing a spent nonce
+    assert_eq!(bchain.check_pending(&pay(2)), Err(MempoolError::Known));
+    assert_eq!(mine_txs(&mut bchain, vec![pay(2)]), Err(AddBlockError::InvalidNonce));
+    let reused = transfer(0, &node_address(2), 1, 0, 2);
+    assert_eq!(bchain.check_pending(&reused), Err(MempoolError::InvalidNonce));
+    assert_eq!(mine_txs(&mut bchain, vec![reused]), Err(AddBlockError::InvalidNonce));
+
+    assert!(bchain.add_to_mempool(pay(3)));
+    assert_eq!(bchain.next_nonce(&pay(0).from), 4);
+}
+
+#[test]
+fn the_chain_iterates_and_slices_from_any_height() {
+    let mut bchain = test_chain(GenesisConfig::default());
+    extend(&mut bchain, 0, 3);
+
+    assert_eq!(bchain.iter().map(|block| block.header.index).collect::<Vec<_>>(), [0, 1, 2, 3]);
+    assert_eq!(bchain.blocks_since(0).len(), 4);
+    assert_eq!(bchain.blocks_since(2), &bchain.chain.blocks()[2..]);
+    assert_eq!(bchain.blocks_since(3).len(), 1);
+    assert!(bchain.blocks_since(4).is_empty());
+    assert!(bchain.blocks_since(u64::MAX).is_empty());
+}
+
+#[test]
+fn a_merkle_proof_verifies_only_its_own_transaction() {
+    for count in 1..=5 {
+        let txs: Vec<Transaction> = (0..count).map(|nonce| transfer(0, &node_address(1), 1, 0, nonce)).collect();
+        let block = Block::new_block(&Sha256Hasher, 0, 1, 1_000, txs, String::from("parent"), 0);
+        let root = &block.header.merkle_root;
+
+        for (i, tx) in block.body.transactions.iter().enumerate() {
+            let proof = block.merkle_proof(i).unwrap();
+            assert!(verify_merkle_proof(&tx.hash(), &proof, root), "{} of {}", i, count);
+            assert!(block.header.proves_inclusion(&Sha256Hasher, 0, &block.header.hash, &tx.hash(), &proof));
+            let other = transfer(0, &node_address(1), 1, 0, 99);
+            assert!(!verify_merkle_proof(&other.hash(), &proof, root), "{} of {}", i, count);
+            //a header that doesn't hash to itself on the network proves nothing
+            assert!(!block.header.proves_inclusion(&Sha256Hasher, 1, &block.header.hash, &tx.hash(), &proof));
+        }
+        assert_eq!(block.merkle_proof(count as usize), None);
+    }
+}
+
+#[test]
+fn a_blockchain_without_blocks_never_loads() {
+    let mut saved = serde_json::to_value(test_chain(GenesisConfig::default())).unwrap();
+    saved["chain"] = serde_json::Value::Array(Vec::new());
+
+    let error = serde_json::from_value::<Blockchain>(saved).err().expect("an empty chain was accepted");
+    assert!(error.to_string().contains("chain must hold at least the genesis block"), "{}", error);
+}
+
+#[tokio::test]
+async fn a_paused_node_catches_up_once_resumed() {
+    let mut network = TestNetwork::new(3);
+    network.send(2, Message::Pause);
+    network.deliver_all().await;
+    for id in [0, 1] {
+        network.mine_on(id, Vec::new()).unwrap();
+        network.deliver_all().await;
+    }
+    assert_eq!(network.chain_of(2).len(), 1);
+    assert_eq!(network.chain_of(1).len(), 3);
+
+    network.send(2, Message::Resume);
+    network.deliver_all().await;
+    assert_eq!(network.chain_of(2), network.chain_of(0));
+}
+
+#[tokio::test]
+async fn a_nodes_rewards_go_to_its_wallet() {
+    let mut network = TestNetwork::new(2);
+    for _ in 0..3 {
+        network.mine_on(0, Vec::new()).unwrap();
+    }
+    network.deliver_all().await;
+
+    for id in 0..2 {
+        let bchain = network.blockchain(id);
+        assert_eq!(bchain.balance_of(&node_address(0)), (ALLOCATION + 3 * bchain.block_reward) as i64);
+        assert_eq!(bchain.balance_of(&node_address(1)), ALLOCATION as i64);
+    }
+}
+
+#[tokio::test]
+async fn a_node_never_asks_for_a_chain_over_its_own_block() {
+    let mut network = TestNetwork::new(3);
+    for _ in 0..2 {
+        network.mine_on(0, Vec::new()).unwrap();
+        //its own block coming back from both peers is ignored, nothing but the 6 NewBlocks is sent
+        assert_eq!(network.deliver_all().await, 6);
+        assert!(network.node(0).pending_sync.is_none());
+    }
+}
+
+#[test]
+fn the_dot_dump_has_a_node_per_block_and_an_edge_per_parent() {
+    let mut bchain = test_chain(GenesisConfig::default());
+    extend(&mut bchain, 0, 2);
+    let dot = bchain.to_dot();
+    assert!(dot.starts_with("digraph chain {"));
+    assert_eq!(dot.matches("[label=").count(), 3);
+    assert_eq!(dot.matches(" -> ").count(), 2);
+
+    //an orphan points at a placeholder for the parent we don't have
+    let mut source = test_chain(GenesisConfig::default());
+    extend(&mut source, 0, 4);
+    assert_eq!(bchain.add_block(source.last_block().clone()), Err(AddBlockError::Orphan));
+    let dot = bchain.to_dot();
+    assert_eq!(dot.matches("style=dashed").count(), 2);
+    assert_eq!(dot.matches(" -> ").count(), 3);
+}
+
+#[test]
+fn the_best_paying_transactions_go_first_and_their_fees_to_the_miner() {
+    let mut bchain = test_chain(GenesisConfig { allocations: vec![(node_address(0), 100), (node_address(1), 100)], ..GenesisConfig::default() });
+    let cheap = transfer(0, &node_address(2), 10, 1, 0);
+    let pricey = transfer(1, &node_address(2), 10, 5, 0);
+    assert!(bchain.add_to_mempool(cheap.clone()));
+    assert!(bchain.add_to_mempool(pricey.clone()));
+
+    let txs = bchain.select_transactions(&node_address(9));
+    assert_eq!(txs[1..], [pricey.clone(), cheap.clone()]);
+    assert_eq!(txs[0].outputs, vec![(node_address(9), bchain.block_reward + 6)]);
+
+    //room for one besides the coinbase
+    bchain.max_txs_per_block = 2;
+    let txs = bchain.select_transactions(&node_address(9));
+    assert_eq!(txs[1..], [pricey]);
+    assert_eq!(txs[0].outputs, vec![(node_address(9), bchain.block_reward + 5)]);
+    bchain.add_block(block_holding(&bchain, txs)).unwrap();
+    assert_eq!(bchain.balance_of(&node_address(9)), (bchain.block_reward + 5) as i64);
+    assert_eq!(bchain.balance_of(&node_address(1)), 85);
+    assert_eq!(bchain.mempool, vec![cheap]);
+}
+
+#[test]
+fn a_chain_leaving_a_checkpoint_is_refused_however_long() {
+    let mut ours = test_chain(GenesisConfig::default());
+    extend(&mut ours, 0, 2);
+    let mut theirs = fork_of(&ours, 2);
+    extend(&mut theirs, 1, 30);
+    assert!(theirs.work > ours.work);
+
+    ours.checkpoints.insert(2, ours.chain.get(2).unwrap().header.hash.clone());
+    assert_eq!(ours.validate_chain(ours.chain.blocks(), &ours.genesis), Ok(()));
+    assert_eq!(ours.validate_chain(theirs.chain.blocks(), &ours.genesis), Err(ChainError::Block { index: 2, error: AddBlockError::CheckpointMismatch }));
+    assert_eq!(ours.reorganize(theirs.chain.load_all()), ReorgResult::Rejected);
+    assert_eq!(ours.chain.len(), 3);
+}
+
+#[test]
+fn headers_alone_fail_where_the_full_chain_does_on_work_and_linkage() {
+    let mut bchain = Blockchain::new(1, GenesisConfig::default());
+    bchain.clock = Arc::new(clock::MockClock::new(1_000));
+    extend(&mut bchain, 0, 3);
+    let chain = bchain.chain.load_all();
+    //what full validation says, once header-only validation is known to say the same
+    let both = |chain: &[Block]| {
+        let full = bchain.validate_chain(chain, &bchain.genesis);
+        assert_eq!(bchain.validate_header_chain(chain, &bchain.genesis), full);
+        full
+    };
+
+    assert_eq!(both(&chain), Ok(()));
+
+    let mut unlinked = chain.clone();
+    unlinked[2].header.previous_hash = "0".repeat(64);
+    assert_eq!(both(&unlinked), Err(ChainError::Block { index: 2, error: AddBlockError::PreviousHashMismatch }));
+
+    //a nonce that no longer hashes under the target, rehashed so only the work is off
+    let mut unworked = chain.clone();
+    let header = &mut unworked[3].header;
+    header.hash = (0..).map(|nonce| {
+        header.nonce = nonce;
+        header.recompute_hash(&Sha256Hasher, 0)
+    }).find(|hash| !bchain.adjusted_target().is_met_by(hash)).unwrap();
+    assert_eq!(both(&unworked), Err(ChainError::Block { index: 3, error: AddBlockError::DifficultyNotMet }));
+}
+
+#[test]
+fn two_transfers_spending_the_same_funds_sink_the_whole_block() {
+    let mut bchain = funded(100);
+    let first = transfer(0, &node_address(1), 60, 0, 0);
+    let second = transfer(0, &node_address(2), 60, 0, 1);
+
+    assert_eq!(mine_txs(&mut bchain, vec![first.clone(), second.clone()]), Err(AddBlockError::Overspend { tx_index: 2 }));
+    assert_eq!(bchain.chain.len(), 1);
+    assert_eq!(bchain.balance_of(&node_address(1)), 0);
+
+    //the mempool doesn't let the second in behind the first either
+    assert!(bchain.add_to_mempool(first));
+    assert_eq!(bchain.check_pending(&second), Err(MempoolError::Overspend));
+}
+
+#[test]
+fn pending_transactions_expire_once_the_clock_passes_their_ttl() {
+    let mut bchain = funded(100);
+    let clock = Arc::new(clock::MockClock::new(1_000));
+    bchain.clock = clock.clone();
+    let old = transfer(0, &node_address(1), 1, 0, 0);
+    assert!(bchain.add_to_mempool(old.clone()));
+    clock.advance(400);
+    let young = transfer(0, &node_address(1), 1, 0, 1);
+    assert!(bchain.add_to_mempool(young.clone()));
+
+    clock.advance(100);
+    assert!(bchain.evict_expired(500).is_empty());
+    clock.advance(1);
+    assert_eq!(bchain.evict_expired(500), vec![old]);
+    assert_eq!(bchain.mempool, vec![young]);
+}
+
+#[test]
+fn a_block_claiming_an_easier_target_is_refused() {
+    let mut bchain = Blockchain::new(2, GenesisConfig::default());
+    let last = bchain.last_block();
+    let easy = Block::mine_block(bchain.hasher(), 0, 1, 1_000, vec![bchain.coinbase(1, &node_address(0), 0)], last.header.hash.clone(), Target::MAX, &AtomicBool::new(false), 0, None).unwrap();
+    assert_eq!(easy.header.target, Some(Target::MAX));
+    assert_eq!(bchain.add_block(easy), Err(AddBlockError::TargetMismatch));
+}
+
+#[tokio::test]
+async fn blocks_come_in_capped_pages_that_add_up_to_the_chain() {
+    let mut network = TestNetwork::unlinked(2);
+    for _ in 0..10 {
+        network.mine_on(0, Vec::new()).unwrap();
+    }
+    network.node(0).max_blocks_per_response = 4;
+    let (listener, mut heard) = mpsc::channel(16);
+    network.node(0).connect(5, listener);
+
+    network.send(0, Message::GetBlocks { peer: 0, requester: 5, from: 1, count: 100 });
+    network.deliver_all().await;
+    let Ok(Message::Blocks { blocks, .. }) = heard.try_recv() else { panic!("no Blocks reply"); };
+    assert_eq!(blocks, network.chain_of(0)[1..5]);
+
+    network.link(0, 1);
+    network.send(1, Message::Resync);
+    network.deliver_all().await;
+    assert_eq!(network.chain_of(1), network.chain_of(0));
+}
+
+#[test]
+fn difficulty_zero_takes_the_first_nonce() {
+    let mut bchain = test_chain(GenesisConfig::default());
+    assert_eq!(bchain.adjusted_target(), Target::MAX);
+    for _ in 0..25 {
+        let block = next_block(&bchain, &node_address(0), Vec::new());
+        assert_eq!(block.header.nonce, 0);
+        bchain.add_block(block).unwrap();
+    }
+    //blocks coming as fast as they like never make it retarget
+    assert_eq!(bchain.adjusted_target(), Target::MAX);
+}
+
+#[tokio::test]
+async fn a_peer_sending_bad_blocks_is_cut_off() {
+    let mut network = TestNetwork::new(3);
+    let block = next_block(&network.blockchain(0), &node_address(1), Vec::new());
+    for i in 0..(-BAN_SCORE / PEER_PENALTY) {
+        let mut bad = block.clone();
+        bad.header.hash = format!("{:064x}", i);
+        network.send(0, Message::NewBlock { from: 1, block: bad });
+        network.deliver_all().await;
+        assert!(network.node(0).peer_ids.contains(&1) == (i + 1 < -BAN_SCORE / PEER_PENALTY));
+    }
+    assert!(network.node(0).banned.contains(&1));
+    assert_eq!(network.node(0).peer_ids, [2]);
+
+    //what it sends from now on is ignored, the other peer is still heard
+    network.send(0, Message::NewBlock { from: 1, block: block.clone() });
+    network.deliver_all().await;
+    assert_eq!(network.chain_of(0).len(), 1);
+    network.send(0, Message::NewBlock { from: 2, block });
+    network.deliver_all().await;
+    assert_eq!(network.chain_of(0).len(), 2);
+}
+
+#[test]
+fn a_block_reward_is_spendable_only_once_it_matures() {
+    let mut bchain = test_chain(GenesisConfig::default());
+    bchain.coinbase_maturity = 3;
+    let reward = next_block(&bchain, &node_address(0), Vec::new());
+    bchain.add_block(reward).unwrap();
+    let spend = transfer(0, &node_address(1), 10, 0, 0);
+
+    //mined at 1, it can't be spent at heights 2 and 3, neither queued nor in a block
+    for _ in 2..4 {
+        assert_eq!(bchain.check_pending(&spend), Err(MempoolError::ImmatureReward));
+        assert_eq!(mine_txs(&mut bchain, vec![spend.clone()]), Err(AddBlockError::ImmatureReward { tx_index: 1 }));
+        mine_txs(&mut bchain, Vec::new()).unwrap();
+    }
+
+    assert_eq!(bchain.check_pending(&spend), Ok(()));
+    mine_txs(&mut bchain, vec![spend]).unwrap();
+    assert_eq!(bchain.balance_of(&node_address(0)), 40);
+    assert_eq!(bchain.balance_of(&node_address(1)), 10);
+}
+
+#[test]
+fn chains_rank_by_work_then_length_then_the_smaller_tip() {
+    use std::cmp::Ordering::*;
+    let compare = Blockchain::compare_chains;
+
+    assert_eq!(compare((9, 1, "ff"), (8, 5, "00")), Greater);
+    assert_eq!(compare((8, 5, "00"), (9, 1, "ff")), Less);
+    assert_eq!(compare((8, 5, "ff"), (8, 4, "00")), Greater);
+    assert_eq!(compare((8, 4, "00"), (8, 5, "ff")), Less);
+    assert_eq!(compare((8, 5, "0a"), (8, 5, "0b")), Greater);
+    assert_eq!(compare((8, 5, "0b"), (8, 5, "0a")), Less);
+    assert_eq!(compare((8, 5, "0a"), (8, 5, "0a")), Equal);
+
+    //an identical chain never replaces ours
+    let bchain = funded(100);
+    assert!(!bchain.outweighs(bchain.work, bchain.chain.len(), &bchain.last_block().header.hash));
+    assert!(bchain.outweighs(bchain.work, bchain.chain.len() + 1, &bchain.last_block().header.hash));
+}
+
+#[test]
+fn a_retarget_moves_two_bits_at_most_and_stays_within_the_bounds() {
+    let mut bchain = Blockchain::new(2, GenesisConfig::default());
+    for spacing in [0, 1, 100] {
+        assert_eq!(retargeted(&bchain, spacing).leading_zero_bits(), 10);
+    }
+    for spacing in [10_000, 1_000_000, u64::MAX as u128] {
+        assert_eq!(retargeted(&bchain, spacing).leading_zero_bits(), 6);
+    }
+
+    bchain.max_difficulty = 9;
+    bchain.min_difficulty = 7;
+    assert_eq!(retargeted(&bchain, 1).leading_zero_bits(), 9);
+    assert_eq!(retargeted(&bchain, 1_000_000).leading_zero_bits(), 7);
+    //a target already past a bound is pulled back even when the window was on time
+    let headers = spaced_headers(21, 1_000);
+    assert_eq!(bchain.next_target(&headers, Target::from_leading_zero_bits(20)).leading_zero_bits(), 9);
+    assert_eq!(bchain.next_target(&headers, Target::MAX).leading_zero_bits(), 7);
+}
+
+//node 0 signing a payment split over `outputs`
+fn split_payment(outputs: Vec<(Address, u64)>, fee: u64, nonce: u64) -> Transaction {
+    let mut tx = transfer(0, &node_address(1), 0, fee, nonce);
+    tx.outputs = outputs;
+    tx.sign(&node_keypair(0));
+    tx
+}
+
+#[test]
+fn one_transaction_pays_every_output_and_is_debited_their_sum() {
+    let mut bchain = funded(100);
+    let tx = split_payment(vec![(node_address(1), 30), (node_address(2), 20), (node_address(1), 5)], 5, 0);
+    assert_eq!(tx.cost(), Some(60));
+
+    //every output is signed for, redirecting one breaks the signature
+    let mut redirected = tx.clone();
+    redirected.outputs[1].0 = node_address(3);
+    assert!(!redirected.verify());
+    assert_eq!(bchain.check_pending(&redirected), Err(MempoolError::InvalidSignature));
+
+    mine_txs(&mut bchain, vec![tx]).unwrap();
+    assert_eq!(bchain.balance_of(&node_address(0)), 40);
+    assert_eq!(bchain.balance_of(&node_address(1)), 35);
+    assert_eq!(bchain.balance_of(&node_address(2)), 20);
+
+    //each output is affordable, all of them together aren't
+    let overspend = split_payment(vec![(node_address(1), 30), (node_address(2), 10)], 1, 1);
+    assert_eq!(bchain.check_pending(&overspend), Err(MempoolError::Overspend));
+    assert_eq!(mine_txs(&mut bchain, vec![overspend]), Err(AddBlockError::Overspend { tx_index: 1 }));
+    assert_eq!(bchain.balance_of(&node_address(0)), 40);
+}
+
+#[test]
+fn the_fee_estimate_is_a_percentile_of_recent_fees() {
+    let mut bchain = funded(10_000);
+    bchain.default_fee = 7;
+    let mut nonce = 0;
+    let mut pay = |fees: std::ops::RangeInclusive<u64>| -> Vec<Transaction> {
+        fees.map(|fee| {
+            nonce += 1;
+            transfer(0, &node_address(1), 1, fee, nonce - 1)
+        }).collect()
+    };
+
+    //too few samples to go on
+    mine_txs(&mut bchain, pay(1..=9)).unwrap();
+    assert_eq!(bchain.estimate_fee(1), 7);
+
+    mine_txs(&mut bchain, pay(10..=20)).unwrap();
+    assert_eq!(bchain.estimate_fee(1), 18);
+    assert_eq!(bchain.estimate_fee(3), 14);
+    assert_eq!(bchain.estimate_fee(0), 18);
+    assert_eq!(bchain.estimate_fee(u64::MAX), 2);
+    for target_blocks in 1..20 {
+        assert!((2..=18).contains(&bchain.estimate_fee(target_blocks)));
+    }
+
+    //once FEE_ESTIMATE_BLOCKS newer blocks came in, the old fees no longer count
+    for _ in 0..FEE_ESTIMATE_BLOCKS {
+        mine_txs(&mut bchain, pay(3..=3)).unwrap();
+    }
+    assert_eq!(bchain.estimate_fee(1), 3);
+}
+
+#[tokio::test]
+async fn estimate_fee_is_served_over_rpc() {
+    let mut bchain = funded(100);
+    bchain.default_fee = 7;
+    let blockchain = tokio::sync::Mutex::new(bchain);
+    let (inbound, _receiver) = mpsc::channel(1);
+    let call = |params: &str| format!(r#"{{"jsonrpc": "2.0", "method": "estimateFee", "params": {}, "id": 1}}"#, params);
+
+    let response = rpc::handle(call("[3]").as_bytes(), &blockchain, &inbound).await.unwrap();
+    assert_eq!(response["result"], 7);
+    let response = rpc::handle(call(r#"{"blocks": 3}"#).as_bytes(), &blockchain, &inbound).await.unwrap();
+    assert_eq!(response["result"], 7);
+    let response = rpc::handle(call("[0]").as_bytes(), &blockchain, &inbound).await.unwrap();
+    assert_eq!(response["error"]["code"], -32602);
+}
+
+#[test]
+fn balances_at_a_height_leave_out_every_later_block() {
+    let mut bchain = funded(100);
+    mine_txs(&mut bchain, vec![transfer(0, &node_address(1), 30, 0, 0)]).unwrap();
+    mine_txs(&mut bchain, vec![transfer(0, &node_address(1), 70, 0, 1)]).unwrap();
+    let payload = |id| node_address(id).payload_hex();
+
+    let genesis = bchain.balances_at_height(0).unwrap();
+    assert_eq!(genesis, HashMap::from([(payload(0), 100)]));
+    let first = bchain.balances_at_height(1).unwrap();
+    assert_eq!((first[&payload(0)], first[&payload(1)]), (70, 30));
+    //node 0 is drained by the tip, a zero balance isn't listed
+    let tip = bchain.balances_at_height(2).unwrap();
+    assert!(!tip.contains_key(&payload(0)));
+    assert_eq!(tip[&payload(1)], 100);
+    assert_eq!(tip, bchain.balances().into_iter().filter(|(_, balance)| *balance != 0).collect());
+
+    assert!(bchain.balances_at_height(3).is_none());
+    assert!(bchain.balances_at_height(u64::MAX).is_none());
+}
+
+#[tokio::test]
+async fn a_transaction_is_forwarded_at_most_once_and_only_while_hops_remain() {
+    let mut network = TestNetwork::new(4);
+    let tx = transfer(0, &node_address(3), 10, 1, 0);
+    network.send(1, Message::Tx { tx: tx.clone(), hops_left: TX_HOPS });
+    //the one sent in, then each node passing it to its three peers once
+    assert_eq!(network.deliver_all().await, 1 + 4 * 3);
+    for id in 0..4 {
+        assert!(network.blockchain(id).mempool.contains(&tx));
+    }
+    //not even resent once it's been heard of, though it's still pending
+    network.send(2, Message::Tx { tx, hops_left: TX_HOPS });
+    assert_eq!(network.deliver_all().await, 1);
+
+    //down a line 0 - 1 - 2, one hop reaches 1, which queues it without passing it on
+    let mut network = TestNetwork::unlinked(3);
+    network.link(0, 1);
+    network.link(1, 2);
+    let tx = transfer(0, &node_address(2), 10, 1, 0);
+    network.send(0, Message::Tx { tx: tx.clone(), hops_left: 1 });
+    assert_eq!(network.deliver_all().await, 2);
+    assert!(network.blockchain(1).mempool.contains(&tx));
+    assert!(network.blockchain(2).mempool.is_empty());
+}
+
+#[test]
+fn a_zst_file_is_smaller_and_loads_to_the_same_chain() {
+    let dir = std::env::temp_dir();
+    let plain = dir.join(format!("smblockchain-plain-{}.json", std::process::id()));
+    let compressed = dir.join(format!("smblockchain-compressed-{}.zst", std::process::id()));
+    //saved under the wrong extension, the magic bytes still give it away
+    let renamed = dir.join(format!("smblockchain-renamed-{}.json", std::process::id()));
+    let mut bchain = test_chain(GenesisConfig::default());
+    extend(&mut bchain, 0, 10);
+
+    bchain.save_to_file(&plain).unwrap();
+    bchain.save_to_file(&compressed).unwrap();
+    let sizes = (fs::metadata(&plain).map(|m| m.len()), fs::metadata(&compressed).map(|m| m.len()));
+    let copied = fs::copy(&compressed, &renamed);
+    let loaded = [Blockchain::load_from_file(&compressed), Blockchain::load_from_file(&renamed)];
+    for path in [&plain, &compressed, &renamed] {
+        let _ = fs::remove_file(path);
+    }
+
+    copied.unwrap();
+    let (plain_len, compressed_len) = (sizes.0.unwrap(), sizes.1.unwrap());
+    assert!(compressed_len < plain_len, "{} compressed to {}", plain_len, compressed_len);
+    for loaded in loaded {
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.chain.blocks(), bchain.chain.blocks());
+        assert_eq!(loaded.work, bchain.work);
+    }
+}
+
+#[test]
+fn a_block_may_hold_the_transaction_limit_but_not_pass_it() {
+    let mut bchain = funded(100);
+    bchain.max_txs_per_block = 3;
+    //far below the byte limit either way, only the count matters
+    bchain.max_block_bytes = usize::MAX;
+    let txs: Vec<Transaction> = (0..3).map(|nonce| transfer(0, &node_address(1), 1, 0, nonce)).collect();
+
+    let over = next_block(&bchain, &node_address(9), txs.clone());
+    let too_many = || AddBlockError::TooManyTransactions { count: 4, max: 3 };
+    let chain = [bchain.chain.blocks()[0].clone(), over.clone()];
+    assert_eq!(bchain.validate_chain(&chain, &bchain.genesis), Err(ChainError::Block { index: 1, error: too_many() }));
+    assert_eq!(bchain.add_block(over), Err(too_many()));
+
+    //the miner stops at the cap, coinbase included, and leaves the rest pending
+    for tx in &txs {
+        assert!(bchain.add_to_mempool(tx.clone()));
+    }
+    let picked = bchain.select_transactions(&node_address(9));
+    assert_eq!(picked.len(), 3);
+    bchain.add_block(block_holding(&bchain, picked)).unwrap();
+    assert_eq!(bchain.mempool, txs[2..]);
+}
+
+#[test]
+fn competing_branches_are_two_tips_and_the_chain_follows_the_heavier() {
+    let mut ours = test_chain(GenesisConfig::default());
+    extend(&mut ours, 0, 2);
+    let old_chain = ours.chain.load_all();
+    let our_tip = ours.last_block().clone();
+    let mut theirs = fork_of(&ours, 2);
+    extend(&mut theirs, 1, 30);
+    assert!(theirs.work > ours.work);
+
+    for block in &theirs.chain.blocks()[2..] {
+        assert_eq!(ours.add_block(block.clone()), Ok(()));
+        let mut tips: Vec<&str> = ours.tree.tips().iter().map(|tip| tip.header.hash.as_str()).collect();
+        tips.sort_unstable();
+        let mut expected = vec![our_tip.header.hash.as_str(), block.header.hash.as_str()];
+        expected.sort_unstable();
+        assert_eq!(tips, expected);
+
+        let best = ours.tree.best_tip().unwrap();
+        assert_eq!(best, ours.last_block());
+        assert_eq!(ours.tree.path_to(&best.header.hash), ours.chain.load_all());
+    }
+    assert_eq!(ours.chain.blocks(), theirs.chain.blocks());
+    //the losing branch is still there with its own work
+    assert_eq!(ours.tree.path_to(&our_tip.header.hash), old_chain);
+    assert_eq!(ours.tree.work(&our_tip.header.hash), Blockchain::total_work(&old_chain));
+}
+
+#[test]
+fn a_double_hashed_chain_keeps_its_hasher_and_fails_under_a_single_hash() {
+    let mut double = Blockchain::with_hasher(ConsensusMode::ProofOfWork { difficulty: 0 }, HashAlgorithm::DoubleSha256, GenesisConfig::default());
+    double.clock = Arc::new(clock::MockClock::new(1_000));
+    extend(&mut double, 0, 3);
+    let tip = double.last_block().header.clone();
+    let hash_with = |algorithm: HashAlgorithm| {
+        Block::compute_hash(algorithm.hasher(), double.genesis.chain_id, tip.index, tip.timestamp, &tip.merkle_root, &tip.previous_hash, tip.nonce, &tip.validator, tip.target)
+    };
+    assert_eq!(tip.hash, hash_with(HashAlgorithm::DoubleSha256));
+    assert_ne!(tip.hash, hash_with(HashAlgorithm::Sha256));
+
+    //the setting travels with the chain, so it reloads under the right hasher
+    let mut saved = serde_json::to_value(&double).unwrap();
+    let loaded: Blockchain = serde_json::from_value(saved.clone()).unwrap();
+    assert_eq!(loaded.hash_algorithm, HashAlgorithm::DoubleSha256);
+    assert_eq!(loaded.validate_chain(loaded.chain.blocks(), &loaded.genesis), Ok(()));
+
+    saved["hash_algorithm"] = serde_json::json!("Sha256");
+    let relabelled: Blockchain = serde_json::from_value(saved).unwrap();
+    assert!(relabelled.validate_chain(relabelled.chain.blocks(), &relabelled.genesis).is_err());
+    //past a genesis block it would accept, every block still fails its hash check
+    let single = test_chain(GenesisConfig::default());
+    let mut blocks = single.chain.load_all();
+    blocks.extend_from_slice(&double.chain.blocks()[1..]);
+    assert!(single.validate_chain(&blocks, &single.genesis).is_err());
+}
+
+#[test]
+fn of_two_blocks_at_one_height_the_one_built_on_wins() {
+    let mut a = test_chain(GenesisConfig::default());
+    extend(&mut a, 0, 1);
+    let mut b = fork_of(&a, 2);
+    extend(&mut a, 0, 1);
+    extend(&mut b, 1, 1);
+    let mut ours = fork_of(&a, 3);
+
+    //kept, even if for now it loses
+    match ours.add_block(b.last_block().clone()) {
+        Ok(()) | Err(AddBlockError::SideBranch) => {}
+        Err(e) => panic!("rival block refused: {}", e),
+    }
+    assert_eq!(ours.tree.tips().len(), 2);
+    assert!(ours.tree.contains(&b.last_block().header.hash));
+
+    //whichever side is behind now, building on it brings it out ahead
+    let (mut behind, miner) = if ours.last_block() == b.last_block() { (a, 0) } else { (b, 1) };
+    extend(&mut behind, miner, 30);
+    assert!(behind.work > ours.work);
+    for block in &behind.chain.blocks()[3..] {
+        let _ = ours.add_block(block.clone());
+    }
+    assert_eq!(ours.chain.blocks(), behind.chain.blocks());
+}
+
+#[test]
+fn a_reorg_deeper_than_the_maximum_is_refused_however_heavy() {
+    let mut ours = test_chain(GenesisConfig::default());
+    extend(&mut ours, 0, 2);
+    ours.max_reorg_depth = Some(1);
+
+    let mut deep = fork_of(&ours, 1);
+    extend(&mut deep, 1, 30);
+    assert!(deep.work > ours.work);
+    let before = ours.chain.load_all();
+    assert_eq!(ours.reorganize(deep.chain.load_all()), ReorgResult::TooDeep { depth: 2 });
+    assert_eq!(ours.chain.blocks(), before.as_slice());
+    //nor does it get in block by block
+    for block in &deep.chain.blocks()[1..] {
+        let _ = ours.add_block(block.clone());
+    }
+    assert_eq!(ours.chain.blocks(), before.as_slice());
+
+    let mut shallow = fork_of(&ours, 2);
+    extend(&mut shallow, 1, 30);
+    assert!(shallow.work > ours.work);
+    assert_eq!(ours.reorganize(shallow.chain.load_all()), ReorgResult::Reorganized { removed: 1, added: 30 });
+    assert_eq!(ours.chain.blocks(), shallow.chain.blocks());
+}
+
+#[test]
+fn a_block_1_pointing_past_genesis_fails_as_a_genesis_link_mismatch() {
+    let mut bchain = test_chain(GenesisConfig::default());
+    let on_elsewhere = |bchain: &Blockchain, index: u64| {
+        let coinbase = vec![bchain.coinbase(index, &node_address(0), 0)];
+        Block::mine_block(bchain.hasher(), bchain.genesis.chain_id, index, 1_000 + index as u128, coinbase, "ab".repeat(32), bchain.adjusted_target(), &AtomicBool::new(false), 0, None)
+            .unwrap()
+    };
+
+    let chain = [bchain.last_block().clone(), on_elsewhere(&bchain, 1)];
+    assert_eq!(bchain.validate_chain(&chain, &bchain.genesis), Err(ChainError::GenesisLinkMismatch));
+
+    //further up the same fault is just a block on the wrong parent
+    extend(&mut bchain, 0, 1);
+    let mut chain = bchain.chain.load_all();
+    chain.push(on_elsewhere(&bchain, 2));
+    assert_eq!(bchain.validate_chain(&chain, &bchain.genesis), Err(ChainError::Block { index: 2, error: AddBlockError::PreviousHashMismatch }));
+}
+
+#[tokio::test]
+async fn a_transfer_counts_as_confirmed_once_enough_blocks_follow() {
+    let mut bchain = funded(100);
+    mine_txs(&mut bchain, vec![transfer(0, &node_address(1), 30, 0, 0)]).unwrap();
+    let to = node_address(1);
+    assert_eq!(bchain.balance_of(&to), 30);
+    assert_eq!(bchain.confirmed_balance_of(&to, 0), 30);
+    assert_eq!(bchain.confirmed_balance_of(&to, 1), 0);
+    assert_eq!(bchain.confirmed_balance_of(&node_address(0), 1), 100);
+
+    for confirmations in 1..4 {
+        mine_txs(&mut bchain, Vec::new()).unwrap();
+        assert_eq!(bchain.confirmed_balance_of(&to, confirmations), 30);
+        assert_eq!(bchain.confirmed_balance_of(&to, confirmations + 1), 0);
+    }
+
+    let blockchain = tokio::sync::Mutex::new(bchain);
+    let (inbound, _receiver) = mpsc::channel(1);
+    let call = |confirmations: u64| format!(r#"{{"jsonrpc": "2.0", "method": "getConfirmedBalance", "params": ["{}", {}], "id": 1}}"#, to, confirmations);
+    assert_eq!(rpc::handle(call(3).as_bytes(), &blockchain, &inbound).await.unwrap()["result"], 30);
+    assert_eq!(rpc::handle(call(4).as_bytes(), &blockchain, &inbound).await.unwrap()["result"], 0);
+}
+
+#[test]
+fn stats_add_up_a_known_chain() {
+    let mut bchain = funded(100);
+    let clock = Arc::new(clock::MockClock::new(1_000));
+    bchain.clock = clock.clone();
+    //with fewer than two mined blocks there is no interval to average
+    assert_eq!(bchain.stats().avg_block_interval_ms, None);
+    mine_txs(&mut bchain, vec![transfer(0, &node_address(1), 30, 2, 0)]).unwrap();
+    assert_eq!(bchain.stats().avg_block_interval_ms, None);
+
+    for _ in 0..2 {
+        clock.advance(500);
+        mine_txs(&mut bchain, Vec::new()).unwrap();
+    }
+    assert!(bchain.add_to_mempool(transfer(0, &node_address(1), 1, 0, 1)));
+
+    let stats = bchain.stats();
+    assert_eq!(stats.height, 3);
+    //the genesis allocation, two in the first block and a coinbase in each other one
+    assert_eq!(stats.transactions, 1 + 2 + 1 + 1);
+    //the fee was node 0's coins, passing them to the miner issues nothing
+    assert_eq!(stats.coinbase_issued, 100 + 3 * 50);
+    assert_eq!(stats.avg_block_interval_ms, Some(500));
+    assert_eq!(stats.difficulty_bits, 0);
+    assert_eq!(stats.mempool_size, 1);
+}
+
+#[tokio::test]
+async fn empty_misnumbered_or_lighter_header_replies_leave_the_chain_alone() {
+    let mut network = TestNetwork::unlinked(2);
+    for _ in 0..3 {
+        network.mine_on(0, Vec::new()).unwrap();
+    }
+    network.mine_on(1, Vec::new()).unwrap();
+    let ours = network.chain_of(0);
+    let theirs = network.chain_of(1);
+    assert!(Blockchain::total_work(&theirs) < Blockchain::total_work(&ours));
+    let headers_of = |chain: &[Block]| chain[1..].iter().map(|block| block.header.clone()).collect::<Vec<_>>();
+
+    let replies = [
+        (1, Vec::new(), None),
+        //our own headers, said to start one height too far up
+        (2, headers_of(&ours), Some(-PEER_PENALTY)),
+        //a shorter, lighter chain is simply not taken, it costs the peer nothing more
+        (1, headers_of(&theirs), Some(-PEER_PENALTY)),
+    ];
+    for (from, headers, score) in replies {
+        network.send(0, Message::ChainHeaders { peer: 1, requester: 0, from, headers });
+        network.deliver_all().await;
+        assert_eq!(network.chain_of(0), ours);
+        assert!(network.node(0).download.is_none());
+        assert_eq!(network.node(0).peer_scores.get(&1).copied(), score);
+    }
+}
+
+#[tokio::test]
+async fn a_block_mined_on_one_node_reaches_the_other_over_tcp() {
+    let (mut miner, miner_inbound) = Node::new(0, 0, GenesisConfig::default());
+    let (listener_node, listener_inbound) = Node::new(1, 0, GenesisConfig::default());
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let codec = codec::CodecKind::default().codec();
+    tokio::spawn(net::serve(listener, listener_inbound, codec, net::Identity { node_id: 1, chain_id: 0 }));
+
+    let (sender, peer_id) = net::connect(addr, codec, net::Identity { node_id: 0, chain_id: 0 }).await.unwrap();
+    assert_eq!(peer_id, 1);
+    miner.connect(peer_id, sender);
+    let (mined, _stop_miner, _) = start(miner);
+    let (received, _stop_listener, _) = start(listener_node);
+
+    miner_inbound.send(Message::Mine).await.unwrap();
+    wait_for_height(&received, 1).await;
+    assert_eq!(received.lock().await.last_block(), mined.lock().await.chain.blocks().get(1).unwrap());
+}
+
+//serves `node`'s http api on a free local port, then runs the node. also returns its inbox
+async fn start_with_http(node: Node, inbound: mpsc::Sender<Message>) -> (std::net::SocketAddr, Arc<Mutex<Blockchain>>, broadcast::Sender<()>) {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(http::serve(listener, node.blockchain.clone(), node.metrics.clone(), node.wallet_address.clone(), inbound, 60_000));
+    let (blockchain, shutdown, _) = start(node);
+    (addr, blockchain, shutdown)
+}
+
+//the status and body of a GET, the server closes the connection after every response
+async fn http_get(addr: std::net::SocketAddr, path: &str) -> (u16, String) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    stream.write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+
+    let (head, body) = response.split_once("\r\n\r\n").expect("response has a head");
+    let status = head.split_whitespace().nth(1).and_then(|status| status.parse().ok()).expect("response has a status");
+    (status, body.to_string())
+}
+
+#[tokio::test]
+async fn the_http_api_serves_the_live_chain() {
+    let (node, inbound) = Node::new(0, 0, GenesisConfig::default());
+    let (addr, blockchain, _shutdown) = start_with_http(node, inbound.clone()).await;
+    inbound.send(Message::Mine).await.unwrap();
+    wait_for_height(&blockchain, 1).await;
+
+    let (status, body) = http_get(addr, "/chain").await;
+    assert_eq!(status, 200);
+    let chain: Vec<Block> = serde_json::from_str(&body).unwrap();
+    assert_eq!(chain.len(), 2);
+    assert_eq!(chain, blockchain.lock().await.chain.load_all());
+
+    let (status, body) = http_get(addr, "/block/1").await;
+    assert_eq!((status, serde_json::from_str::<Block>(&body).unwrap()), (200, chain[1].clone()));
+    assert_eq!(http_get(addr, "/block/2").await.0, 404);
+    assert_eq!(http_get(addr, "/block/x").await.0, 404);
+}
+
+#[test]
+fn each_rejection_names_its_reason_and_only_linkage_asks_for_a_chain() {
+    let mut bchain = test_chain(GenesisConfig::default());
+    extend(&mut bchain, 0, 2);
+    let tip = bchain.last_block().clone();
+
+    //on the tip, but numbered as if it were much further down
+    let coinbase = vec![bchain.coinbase(1, &node_address(0), 0)];
+    let misnumbered = Block::mine_block(bchain.hasher(), bchain.genesis.chain_id, 1, tip.header.timestamp + 1, coinbase, tip.header.hash.clone(), bchain.adjusted_target(), &AtomicBool::new(false), 0, None).unwrap();
+    assert_eq!(bchain.add_block(misnumbered), Err(AddBlockError::IndexMismatch { expected: 3, got: 1 }));
+
+    //forged by a validator the parent hash didn't pick
+    let validators = vec![(node_address(0).to_string(), 1)];
+    let mut staked = test_chain(GenesisConfig::default());
+    staked.consensus = ConsensusMode::ProofOfStake { validators };
+    let genesis = staked.last_block().clone();
+    let forged = Block::forge_block(staked.hasher(), staked.genesis.chain_id, 1, 1_000, vec![staked.coinbase(1, &node_address(1), 0)], genesis.header.hash.clone(), node_address(1).to_string());
+    assert_eq!(staked.add_block(forged), Err(AddBlockError::UnexpectedValidator));
+
+    assert!(AddBlockError::Orphan.needs_chain());
+    assert!(AddBlockError::PreviousHashMismatch.needs_chain());
+    assert!(AddBlockError::IndexMismatch { expected: 3, got: 1 }.needs_chain());
+    for bad in [AddBlockError::HashMismatch, AddBlockError::InvalidCoinbase, AddBlockError::UnexpectedValidator, AddBlockError::Overspend { tx_index: 1 }, AddBlockError::SideBranch] {
+        assert!(!bad.needs_chain(), "{}", bad);
+    }
+}
+
+#[tokio::test]
+async fn metrics_count_the_blocks_a_node_mined() {
+    let (node, inbound) = Node::new(0, 0, GenesisConfig::default());
+    let (addr, blockchain, _shutdown) = start_with_http(node, inbound.clone()).await;
+    for height in 1..=3 {
+        inbound.send(Message::Mine).await.unwrap();
+        wait_for_height(&blockchain, height).await;
+    }
+
+    let (status, body) = http_get(addr, "/metrics").await;
+    assert_eq!(status, 200);
+    let value = |name: &str| body.lines().find_map(|line| line.strip_prefix(&format!("smblockchain_{} ", name))).map(str::to_string);
+    assert_eq!(value("blocks_mined_total").as_deref(), Some("3"));
+    assert_eq!(value("blocks_accepted_total").as_deref(), Some("0"));
+    assert_eq!(value("chain_length").as_deref(), Some("4"));
+    assert!(body.contains("# TYPE smblockchain_blocks_mined_total counter"));
+}
+
+#[tokio::test]
+async fn on_a_ring_with_a_fanout_of_one_every_node_still_ends_up_with_every_block() {
+    let mut network = TestNetwork::unlinked(5);
+    for id in 0..5 {
+        network.link(id, (id + 1) % 5);
+        network.node(id).fanout = Some(1);
+    }
+
+    for _ in 0..10 {
+        //the miner passes it to one of its two neighbours, who passes it on in turn
+        network.mine_on(0, Vec::new()).unwrap();
+        network.deliver_all().await;
+        network.clock().advance(1_000);
+    }
+    //gossip may have left nodes behind, each asks its neighbours for what it's missing. a node
+    //only gets as far as the neighbours it asked were at the time, so it can take a few rounds
+    let chain = network.chain_of(0);
+    assert_eq!(chain.len(), 11);
+    for _ in 0..5 {
+        for id in 0..5 {
+            network.send(id, Message::Resync);
+        }
+        network.deliver_all().await;
+    }
+    for id in 1..5 {
+        assert_eq!(network.chain_of(id), chain);
+    }
+}
+
+//the payload of the next websocket frame the server sends, which is never masked
+async fn read_ws_frame(stream: &mut tokio::net::TcpStream) -> Vec<u8> {
+    use tokio::io::AsyncReadExt;
+    let mut head = [0u8; 2];
+    stream.read_exact(&mut head).await.unwrap();
+    let len = match head[1] {
+        126 => stream.read_u16().await.unwrap() as usize,
+        127 => stream.read_u64().await.unwrap() as usize,
+        len => len as usize,
+    };
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload).await.unwrap();
+    payload
+}
+
+#[tokio::test]
+async fn a_websocket_client_gets_the_tip_and_then_each_new_block() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let (node, inbound) = Node::new(0, 0, GenesisConfig::default());
+    let (addr, blockchain, _shutdown) = start_with_http(node, inbound.clone()).await;
+    inbound.send(Message::Mine).await.unwrap();
+    wait_for_height(&blockchain, 1).await;
+
+    let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let request = "GET /ws HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n";
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        head.push(stream.read_u8().await.unwrap());
+    }
+    let head = String::from_utf8(head).unwrap();
+    assert!(head.starts_with("HTTP/1.1 101 "));
+    assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+
+    //a late joiner starts at the tip, not from genesis
+    let tip: Block = serde_json::from_slice(&read_ws_frame(&mut stream).await).unwrap();
+    assert_eq!(tip.header.index, 1);
+    inbound.send(Message::Mine).await.unwrap();
+    let next: Block = serde_json::from_slice(&read_ws_frame(&mut stream).await).unwrap();
+    assert_eq!(next.header.index, 2);
+    assert_eq!(next.header.previous_hash, tip.header.hash);
+}
+
+#[tokio::test]
+async fn mine_requests_past_the_limit_are_dropped_and_slots_come_back_on_cancel() {
+    //a target no miner reaches in the time the test takes
+    let (mut node, _inbound) = Node::new(0, 16, GenesisConfig::default());
+    node.mining_slots = Arc::new(Semaphore::new(2));
+    node.dispatch(Message::Mine).await;
+    assert_eq!(node.mining_slots.available_permits(), 1);
+    //two miners run, the other 18 requests are turned away rather than queued
+    for _ in 0..19 {
+        node.dispatch(Message::Mine).await;
+    }
+    assert_eq!(node.mining_slots.available_permits(), 0);
+
+    node.cancel_mining();
+    for _ in 0..2000 {
+        if node.mining_slots.available_permits() == 2 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+    }
+    assert_eq!(node.mining_slots.available_permits(), 2);
+    assert_eq!(node.blockchain.lock().await.chain.len(), 1);
+}
+
+#[test]
+fn validate_exits_0_on_a_good_file_1_on_a_corrupt_one_and_2_when_it_cant_tell() {
+    let dir = std::env::temp_dir();
+    let good = dir.join(format!("smblockchain-good-{}.json", std::process::id()));
+    let corrupt = dir.join(format!("smblockchain-corrupt-{}.json", std::process::id()));
+    let mut bchain = test_chain(GenesisConfig::default());
+    extend(&mut bchain, 0, 3);
+    bchain.save_to_file(&good).unwrap();
+    let mut blocks = serde_json::to_value(bchain.chain.blocks()).unwrap();
+    blocks[2]["nonce"] = serde_json::json!(7);
+    fs::write(&corrupt, blocks.to_string()).unwrap();
+
+    let validate = |path: &Path| validate_command(&[String::from("--file"), path.display().to_string(), String::from("--difficulty"), String::from("0")]);
+    let codes = [validate(&good), validate(&corrupt), validate(&dir.join("smblockchain-no-such-file.json"))];
+    let unknown_flag = validate_command(&[String::from("--file"), good.display().to_string(), String::from("--colour"), String::from("red")]);
+    let _ = fs::remove_file(&good);
+    let _ = fs::remove_file(&corrupt);
+
+    assert_eq!(codes, [0, 1, 2]);
+    assert_eq!(unknown_flag, 2);
+}
+
+#[test]
+fn one_seed_always_draws_the_same_rounds_and_topology() {
+    let draw = |seed: u64| {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let links = topology(6, Some(1), &mut rng);
+        let rounds: Vec<Round> = (0..50).map(|_| draw_round(&mut rng, 6)).collect();
+        (links, rounds)
+    };
+    let miners = |rounds: &[Round]| rounds.iter().map(|round| round.miner).collect::<Vec<_>>();
+
+    let (links, rounds) = draw(42);
+    assert!(links.iter().all(|peers| peers.len() >= 2));
+    assert_ne!(miners(&draw(43).1), miners(&rounds));
+    assert_eq!(draw(42), (links, rounds));
+}
+
+#[tokio::test]
+async fn a_full_peer_queue_drops_and_counts_and_a_closed_one_is_forgotten() {
+    let (mut node, _inbound) = Node::new(0, 0, GenesisConfig::default());
+    let (slow, mut slow_queue) = mpsc::channel(1);
+    let (gone, gone_queue) = mpsc::channel(1);
+    node.connect(1, slow);
+    node.connect(2, gone);
+    drop(gone_queue);
+
+    //none of these wait on the full queue, they'd never return otherwise
+    for _ in 0..5 {
+        node.broadcast(Message::Mine);
+    }
+    assert_eq!(node.metrics.messages_dropped.load(Ordering::Relaxed), 4);
+    assert_eq!(node.peer_ids, [1]);
+    assert!(slow_queue.try_recv().is_ok());
+    assert!(slow_queue.try_recv().is_err());
+
+    //a full queue isn't a reason to drop the peer, there's room again now
+    node.broadcast(Message::Mine);
+    assert!(slow_queue.try_recv().is_ok());
+    assert_eq!(node.peer_ids, [1]);
+}
+
+#[tokio::test]
+async fn rpc_queues_a_valid_transaction_and_says_why_an_invalid_one_is_refused() {
+    let blockchain = tokio::sync::Mutex::new(funded(100));
+    let (inbound, mut queued) = mpsc::channel(4);
+    let call = |method: &str, params: serde_json::Value| serde_json::json!({ "jsonrpc": "2.0", "method": method, "params": params, "id": "a" }).to_string();
+
+    let tx = transfer(0, &node_address(1), 30, 1, 0);
+    let response = rpc::handle(call("submitTransaction", serde_json::json!([tx])).as_bytes(), &blockchain, &inbound).await.unwrap();
+    assert_eq!(response, serde_json::json!({ "jsonrpc": "2.0", "result": tx.hash(), "id": "a" }));
+    match queued.try_recv() {
+        Ok(Message::Tx { tx: sent, hops_left }) => assert_eq!((sent, hops_left), (tx.clone(), TX_HOPS)),
+        _ => panic!("transaction wasn't handed to the node"),
+    }
+
+    let mut forged = transfer(0, &node_address(1), 30, 1, 0);
+    forged.outputs[0].1 = 90;
+    let response = rpc::handle(call("submitTransaction", serde_json::json!({ "tx": forged })).as_bytes(), &blockchain, &inbound).await.unwrap();
+    assert_eq!(response["error"]["code"], -32000);
+    assert_eq!(response["error"]["message"], MempoolError::InvalidSignature.to_string());
+    assert_eq!(response["id"], "a");
+    assert!(queued.try_recv().is_err());
+    let response = rpc::handle(call("submitTransaction", serde_json::json!([{ "from": 1 }])).as_bytes(), &blockchain, &inbound).await.unwrap();
+    assert_eq!(response["error"]["code"], -32602);
+
+    let response = rpc::handle(call("getBalance", serde_json::json!([node_address(0)])).as_bytes(), &blockchain, &inbound).await.unwrap();
+    assert_eq!(response["result"], 100);
+    let response = rpc::handle(call("getBalance", serde_json::json!(["abcd"])).as_bytes(), &blockchain, &inbound).await.unwrap();
+    assert_eq!(response["error"]["code"], -32602);
+
+    assert_eq!(rpc::handle(call("mine", serde_json::json!([])).as_bytes(), &blockchain, &inbound).await.unwrap()["error"]["code"], -32601);
+    assert_eq!(rpc::handle(b"{", &blockchain, &inbound).await.unwrap()["error"]["code"], -32700);
+    //a notification is carried out but never answered
+    let notification = serde_json::json!({ "jsonrpc": "2.0", "method": "getBalance", "params": [node_address(0)] }).to_string();
+    assert!(rpc::handle(notification.as_bytes(), &blockchain, &inbound).await.is_none());
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_mined_block_is_held_back_within_the_jitter_range() {
+    for _ in 0..5 {
+        let (mut node, _inbound) = Node::new(0, 0, GenesisConfig::default());
+        node.broadcast_jitter_ms = 200..=300;
+        let (peer, mut peer_queue) = mpsc::channel(4);
+        node.connect(1, peer);
+        let blockchain = node.blockchain.clone();
+
+        let started = tokio::time::Instant::now();
+        node.dispatch(Message::Mine).await;
+        let Some(Message::NewBlock { block, .. }) = peer_queue.recv().await else { panic!("no block sent") };
+        let held = started.elapsed();
+        //the clock only moves for the sleep, mining itself takes no time on it
+        assert!((200..=300).contains(&held.as_millis()), "held back {:?}", held);
+        assert_eq!(blockchain.lock().await.last_block(), &block);
+    }
+}
+
+#[test]
+fn a_chain_that_stops_growing_turns_unhealthy_once_the_window_passes() {
+    let mut bchain = test_chain(GenesisConfig::default());
+    let clock = Arc::new(clock::MockClock::new(1_000));
+    bchain.clock = clock.clone();
+    //never having moved is no sign of life
+    assert!(!bchain.health(500).healthy);
+
+    extend(&mut bchain, 0, 1);
+    clock.advance(500);
+    let health = bchain.health(500);
+    assert!(health.healthy);
+    assert_eq!((health.height, health.idle_ms), (1, Some(500)));
+
+    clock.advance(1);
+    assert!(!bchain.health(500).healthy);
+    extend(&mut bchain, 0, 1);
+    assert_eq!(bchain.health(500).idle_ms, Some(0));
+    assert!(bchain.health(500).healthy);
+}
+
+//until the node's miners have all finished, whether or not they found a block
+async fn wait_for_miners(node: &Node) {
+    for _ in 0..2000 {
+        if node.mining_slots.available_permits() == 1 {
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+    }
+    panic!("miner never finished");
+}
+
+#[tokio::test]
+async fn an_empty_mempool_is_mined_only_when_empty_blocks_are_allowed() {
+    let genesis = GenesisConfig { allocations: vec![(node_address(0), 100)], ..GenesisConfig::default() };
+    let (mut node, _inbound) = Node::new(0, 0, genesis);
+    node.mine_empty_blocks = false;
+    node.dispatch(Message::Mine).await;
+    wait_for_miners(&node).await;
+    assert_eq!(node.blockchain.lock().await.chain.len(), 1);
+
+    //with something to mine it goes ahead
+    assert!(node.blockchain.lock().await.add_to_mempool(transfer(0, &node_address(1), 10, 0, 0)));
+    node.dispatch(Message::Mine).await;
+    wait_for_miners(&node).await;
+    assert_eq!(node.blockchain.lock().await.last_block().body.transactions.len(), 2);
+
+    node.mine_empty_blocks = true;
+    node.dispatch(Message::Mine).await;
+    wait_for_miners(&node).await;
+    let bchain = node.blockchain.lock().await;
+    assert_eq!(bchain.chain.len(), 3);
+    assert_eq!(bchain.last_block().body.transactions.len(), 1);
+}
+
+#[test]
+fn a_generated_wallet_key_signs_transactions_that_verify() {
+    let key = std::env::temp_dir().join(format!("smblockchain-wallet-{}.key", std::process::id()));
+    let created = wallet_new(Some(&key));
+    let overwrite = wallet_new(Some(&key));
+    let secret = fs::read_to_string(&key);
+    #[cfg(unix)]
+    let mode = fs::metadata(&key).map(|meta| std::os::unix::fs::PermissionsExt::mode(&meta.permissions()) & 0o777);
+    let unsigned = serde_json::json!({ "outputs": [[node_address(1), 30]], "fee": 1, "nonce": 0 }).to_string();
+    let signed = signed_transaction(&key, &unsigned);
+    let _ = fs::remove_file(&key);
+
+    created.unwrap();
+    assert!(overwrite.is_err(), "an existing key file was overwritten");
+    #[cfg(unix)]
+    assert_eq!(mode.unwrap(), 0o600);
+    let mut secret_bytes = [0u8; 32];
+    hex::decode_to_slice(secret.unwrap().trim(), &mut secret_bytes).unwrap();
+    let keypair = ed25519::Keypair::from_secret(secret_bytes);
+
+    let tx = signed.unwrap();
+    assert!(tx.verify());
+    assert_eq!(tx.from, hex::encode(keypair.public));
+    assert_eq!((tx.outputs, tx.fee, tx.nonce), (vec![(node_address(1), 30)], 1, 0));
+    assert!(signed_transaction(Path::new("/nonexistent/smblockchain.key"), &unsigned).is_err());
+}
+
+#[tokio::test]
+async fn a_self_mining_node_advances_its_tip_without_being_told_to() {
+    let (mut node, _inbound) = Node::new(0, 0, GenesisConfig::default());
+    node.self_mine = true;
+    node.mine_empty_blocks = true;
+    let (blockchain, shutdown, running) = start(node);
+
+    wait_for_height(&blockchain, 3).await;
+    shutdown.send(()).unwrap();
+    running.await.unwrap();
+    let bchain = blockchain.lock().await;
+    assert_eq!(bchain.validate_chain(&bchain.chain.load_all(), &bchain.genesis), Ok(()));
+}
+
+#[test]
+fn a_genesis_allocation_is_spendable_at_once_and_part_of_the_genesis_hash() {
+    let genesis = |amount| GenesisConfig { allocations: vec![(node_address(0), amount)], ..GenesisConfig::default() };
+    let mut bchain = test_chain(genesis(100));
+    assert_eq!(bchain.balance_of(&node_address(0)), 100);
+
+    let tx = transfer(0, &node_address(1), 60, 1, 0);
+    assert!(bchain.add_to_mempool(tx.clone()));
+    let block = next_block(&bchain, &node_address(2), vec![tx]);
+    bchain.add_block(block).unwrap();
+    assert_eq!(bchain.balance_of(&node_address(0)), 39);
+    assert_eq!(bchain.balance_of(&node_address(1)), 60);
+
+    //nodes allocating differently don't share a genesis block
+    let hashes: Vec<String> = [genesis(100), genesis(101), GenesisConfig::default()].into_iter().map(|genesis| test_chain(genesis).chain.load_all()[0].header.hash.clone()).collect();
+    assert_ne!(hashes[0], hashes[1]);
+    assert_ne!(hashes[0], hashes[2]);
+    assert_eq!(hashes[0], test_chain(genesis(100)).chain.load_all()[0].header.hash);
+}
+
+#[tokio::test]
+async fn a_node_whose_chain_response_is_lost_catches_up_on_a_retry() {
+    let mut network = TestNetwork::unlinked(2);
+    for _ in 0..3 {
+        network.mine_on(0, Vec::new()).unwrap();
+    }
+    network.link(0, 1);
+    network.mine_on(0, Vec::new()).unwrap();
+
+    //node 1 can't place block 4 and asks node 0, whose answer never arrives
+    let new_block = network.node(1).receiver.try_recv().unwrap();
+    network.node(1).dispatch(new_block).await;
+    let request = network.node(0).receiver.try_recv().unwrap();
+    network.node(0).dispatch(request).await;
+    while network.node(1).receiver.try_recv().is_ok() {}
+    assert_eq!(network.chain_of(1).len(), 1);
+    assert_eq!(network.node(1).pending_sync.as_ref().map(|sync| (sync.target_height, sync.retries)), Some((4, 0)));
+
+    network.node(1).retry_sync().await;
+    assert_eq!(network.node(1).pending_sync.as_ref().map(|sync| sync.retries), Some(1));
+    network.deliver_all().await;
+    assert_eq!(network.chain_of(1), network.chain_of(0));
+    assert!(network.node(1).pending_sync.is_none());
+}
+
+#[test]
+fn block_numbers_hash_as_fixed_width_big_endian_bytes() {
+    let root = merkle_root(&[]);
+    let previous = "ab".repeat(32);
+    let target = Target::from_leading_zero_bits(3);
+    let mut preimage = Sha256::new();
+    let numbers = [7u64.to_be_bytes().to_vec(), 2u64.to_be_bytes().to_vec(), 1_500u128.to_be_bytes().to_vec()];
+    let tail = [root.as_bytes().to_vec(), previous.as_bytes().to_vec(), 9u64.to_be_bytes().to_vec(), b"v".to_vec(), target.to_hex().into_bytes()];
+    for part in numbers.iter().chain(&tail) {
+        preimage.update((part.len() as u64).to_be_bytes());
+        preimage.update(part);
+    }
+    assert_eq!(Block::compute_hash(&Sha256Hasher, 7, 2, 1_500, &root, &previous, 9, "v", Some(target)), hex::encode(preimage.finalize()));
+
+    //a block hashed the old way, its numbers written out as text, no longer validates
+    let mut bchain = test_chain(GenesisConfig::default());
+    extend(&mut bchain, 0, 2);
+    let mut chain = bchain.chain.load_all();
+    assert_eq!(bchain.validate_chain(&chain, &bchain.genesis), Ok(()));
+    let header = &chain[2].header;
+    let numbers = [bchain.genesis.chain_id.to_string(), header.index.to_string(), header.timestamp.to_string(), header.nonce.to_string()];
+    let target = header.target.map(Target::to_hex);
+    let mut parts: Vec<&[u8]> = vec![numbers[0].as_bytes(), numbers[1].as_bytes(), numbers[2].as_bytes(), header.merkle_root.as_bytes(), header.previous_hash.as_bytes(), numbers[3].as_bytes(), header.validator.as_bytes()];
+    if let Some(target) = &target {
+        parts.push(target.as_bytes());
+    }
+    chain[2].header.hash = Sha256Hasher.hash(&parts);
+    assert_eq!(bchain.validate_chain(&chain, &bchain.genesis), Err(ChainError::Block { index: 2, error: AddBlockError::HashMismatch }));
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_block_sent_over_a_200ms_link_arrives_no_sooner() {
+    let (peer, mut received) = mpsc::channel(16);
+    let link = delayed_link(peer, 200..=200);
+    let bchain = test_chain(GenesisConfig::default());
+    let block = next_block(&bchain, &node_address(0), Vec::new());
+    let sent_at = tokio::time::Instant::now();
+    link.send(Message::NewBlock { from: 0, block: block.clone() }).await.unwrap();
+    link.send(Message::Mine).await.unwrap();
+
+    tokio::time::sleep(std::time::Duration::from_millis(199)).await;
+    assert!(received.try_recv().is_err(), "the block arrived early");
+    match received.recv().await {
+        Some(Message::NewBlock { block: arrived, .. }) => assert_eq!(arrived, block),
+        _ => panic!("the block didn't come first"),
+    }
+    assert!(sent_at.elapsed() >= std::time::Duration::from_millis(200));
+    assert!(matches!(received.recv().await, Some(Message::Mine)));
+}
+
+#[tokio::test]
+async fn bad_frames_cost_the_peer_that_sent_them_its_score() {
+    let mut network = TestNetwork::new(3);
+    network.send(0, Message::BadFrame { peer: 1 });
+    network.deliver_all().await;
+    assert_eq!(network.node(0).peer_scores.get(&1), Some(&-PEER_PENALTY));
+    assert_eq!(network.node(0).peer_scores.get(&2), None);
+
+    for _ in 1..(-BAN_SCORE / PEER_PENALTY) {
+        network.send(0, Message::BadFrame { peer: 1 });
+    }
+    network.deliver_all().await;
+    assert!(network.node(0).banned.contains(&1));
+    assert_eq!(network.node(0).peer_ids, [2]);
+}
+
+#[tokio::test]
+async fn a_resync_with_no_heavier_chain_around_keeps_the_chain_it_had() {
+    let mut network = TestNetwork::unlinked(3);
+    for _ in 0..4 {
+        network.mine_with_work_on(0, 8).unwrap();
+    }
+    network.mine_on(1, Vec::new()).unwrap();
+    network.link(0, 1);
+    network.link(0, 2);
+    let ours = network.chain_of(0);
+    assert!(Blockchain::total_work(&ours) > Blockchain::total_work(&network.chain_of(1)));
+
+    network.send(0, Message::Resync);
+    network.deliver_all().await;
+    assert_eq!(network.chain_of(0), ours);
+    assert!(network.node(0).download.is_none());
+    let bchain = network.blockchain(0);
+    assert_eq!(bchain.validate_chain(&ours, &bchain.genesis), Ok(()));
+}
+
+#[tokio::test]
+async fn a_full_node_answers_a_tx_proof_that_checks_out_from_the_header_alone() {
+    let mut network = TestNetwork::new(2);
+    let tx = transfer(0, &node_address(1), 10, 1, 0);
+    network.mine_on(0, vec![tx.clone()]).unwrap();
+    network.mine_on(0, Vec::new()).unwrap();
+    network.deliver_all().await;
+    let (light_client, mut heard) = mpsc::channel(16);
+    network.node(0).connect(5, light_client);
+
+    network.send(0, Message::GetTxProof { requester: 5, tx_hash: tx.hash() });
+    network.deliver_all().await;
+    let Ok(Message::TxProof { peer: 0, requester: 5, tx_hash, block_header, merkle_proof, height: 1 }) = heard.try_recv() else { panic!("no TxProof reply"); };
+    assert_eq!(tx_hash, tx.hash());
+    assert_eq!(*block_header, network.chain_of(0)[1].header);
+    assert!(block_header.proves_inclusion(&Sha256Hasher, 0, &network.chain_of(1)[1].header.hash, &tx_hash, &merkle_proof));
+
+    //node 1 asking checks the proof itself, and one proving some other transaction costs the peer
+    network.send(0, Message::GetTxProof { requester: 1, tx_hash: tx.hash() });
+    network.deliver_all().await;
+    assert!(network.node(1).peer_scores.is_empty());
+    let forged = Message::TxProof { peer: 0, requester: 1, tx_hash: transfer(0, &node_address(1), 10, 1, 1).hash(), block_header, merkle_proof, height: 1 };
+    network.send(1, forged);
+    network.deliver_all().await;
+    assert_eq!(network.node(1).peer_scores.get(&0), Some(&-PEER_PENALTY));
+}
+
+#[tokio::test]
+async fn the_self_check_passes_a_good_chain_and_halts_on_a_corrupted_block() {
+    let mut network = TestNetwork::new(1);
+    for _ in 0..3 {
+        network.mine_on(0, Vec::new()).unwrap();
+    }
+    let node = network.node(0);
+    node.start_self_check();
+    node.self_check.take().unwrap().await.unwrap();
+    assert!(tokio::time::timeout(std::time::Duration::from_millis(50), node.corruption.notified()).await.is_err());
+
+    //a block slipped in past add_block, as a bug in reorg handling might
+    let mut bchain = node.blockchain.try_lock().unwrap();
+    let mut corrupted = bchain.chain.truncate(2).remove(0);
+    corrupted.body.transactions[0].outputs[0].1 += 1;
+    bchain.chain.append(corrupted);
+    drop(bchain);
+
+    let (mut node, _inbound) = Node::new(0, 0, GenesisConfig::default());
+    node.blockchain = network.node(0).blockchain.clone();
+    node.self_check_interval_ms = Some(10);
+    node.halt_on_corruption = true;
+    let (_, _shutdown, running) = start(node);
+    tokio::time::timeout(std::time::Duration::from_secs(10), running).await.expect("the node kept running on a corrupt chain").unwrap();
+}
+
+#[test]
+fn a_yielding_miner_on_every_core_leaves_room_for_other_blocking_work() {
+    let cancel = AtomicBool::new(false);
+    let cores = std::thread::available_parallelism().map_or(2, |cores| cores.get());
+    let done = std::thread::scope(|scope| {
+        let miner = scope.spawn(|| Block::mine_block_parallel(&Sha256Hasher, 0, 1, 1_000, Vec::new(), String::from("parent"), Target([0; 32]), &cancel, cores, 100, None));
+        //stands in for a file write waiting on a blocking thread, it only has to get done
+        let other = scope.spawn(|| (0..1_000u64).map(std::hint::black_box).sum::<u64>());
+        let done = other.join().unwrap();
+        cancel.store(true, Ordering::Relaxed);
+        assert!(matches!(miner.join().unwrap(), Err(MineError::Cancelled)));
+        done
+    });
+    assert_eq!(done, 499_500);
+}
+
+#[test]
+fn a_slow_search_reports_its_progress_along_the_way() {
+    let reports = std::sync::Mutex::new(Vec::new());
+    let report = |attempts: u64, best_bits: u32| reports.lock().unwrap().push((attempts, best_bits));
+    let progress = MiningProgress { every: 1_000, report: &report };
+    let target = Target::from_leading_zero_bits(16);
+    let block = Block::mine_block(&Sha256Hasher, 0, 1, 1_000, Vec::new(), String::from("parent"), target, &AtomicBool::new(false), 0, Some(&progress)).unwrap();
+    assert!(target.is_met_by(&block.header.hash));
+
+    let reports = reports.into_inner().unwrap();
+    assert!(!reports.is_empty(), "no progress in {} nonces", block.header.nonce);
+    assert_eq!(reports.len() as u64, (block.header.nonce + 1) / 1_000);
+    for (i, &(attempts, best_bits)) in reports.iter().enumerate() {
+        assert_eq!(attempts, 1_000 * (i as u64 + 1));
+        assert!(best_bits < 16);
+    }
+    assert!(reports.windows(2).all(|pair| pair[0].1 <= pair[1].1));
+}
+
+#[test]
+fn a_transactions_id_leaves_out_its_signature() {
+    let tx = transfer(0, &node_address(1), 10, 1, 0);
+    let mut tampered = tx.clone();
+    tampered.signature[63] ^= 0x20;
+    assert_eq!(tampered.hash(), tx.hash());
+    assert!(!tampered.verify());
+
+    //what is signed and who signed it still change it
+    assert_ne!(transfer(0, &node_address(1), 11, 1, 0).hash(), tx.hash());
+    assert_ne!(transfer(1, &node_address(1), 10, 1, 0).hash(), tx.hash());
+}
+
+#[tokio::test]
+async fn a_made_up_header_with_no_target_proves_nothing() {
+    let mut network = TestNetwork::new(2);
+    network.mine_on(0, Vec::new()).unwrap();
+    network.deliver_all().await;
+
+    //hashes to itself and holds the transaction, but records no target and isn't node 1's block 1
+    let tx = transfer(0, &node_address(1), 10, 1, 0);
+    let parent = network.chain_of(1)[0].header.hash.clone();
+    let forged = Block::new_block(&Sha256Hasher, 0, 1, 2_000, vec![tx.clone()], parent, 0);
+    assert_eq!(forged.header.target, None);
+    let merkle_proof = forged.merkle_proof(0).unwrap();
+    assert!(!forged.header.proves_inclusion(&Sha256Hasher, 0, &network.chain_of(1)[1].header.hash, &tx.hash(), &merkle_proof));
+
+    let proof = |height| Message::TxProof { peer: 0, requester: 1, tx_hash: tx.hash(), block_header: Box::new(forged.header.clone()), merkle_proof: merkle_proof.clone(), height };
+    network.send(1, proof(1));
+    network.deliver_all().await;
+    assert_eq!(network.node(1).peer_scores.get(&0), Some(&-PEER_PENALTY));
+
+    //past node 1's tip there's nothing to hold it against, it's ignored rather than held against the peer
+    network.send(1, proof(2));
+    network.deliver_all().await;
+    assert_eq!(network.node(1).peer_scores.get(&0), Some(&-PEER_PENALTY));
+}